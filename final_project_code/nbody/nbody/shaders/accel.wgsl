@@ -0,0 +1,120 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> accel: array<vec4<f32>>;
+@group(0) @binding(2)
+var<uniform> params: SimParams;
+// simulation::ewald::table, a single unused entry for open boundaries
+@group(0) @binding(3)
+var<storage, read> ewald_table: array<vec4<f32>>;
+
+const KERNEL_PLUMMER: u32 = 0u;
+const KERNEL_SPLINE: u32 = 1u;
+
+// The softened 1/r^3, a = G * m * r * softened_inv_r3(r^2). Mirrors SofteningKernel::inv_r3
+fn softened_inv_r3(r2: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return 1.0 / pow(r2, 1.5);
+        }
+
+        let u = sqrt(r2) / h;
+        let h3 = h * h * h;
+        if u < 0.5 {
+            return (10.666667 + u * u * (32.0 * u - 38.4)) / h3;
+        }
+        return (21.333333 - 48.0 * u + 38.4 * u * u - 10.666667 * u * u * u - 0.066666667 / (u * u * u)) / h3;
+    }
+
+    return 1.0 / pow(r2 + eps * eps, 1.5);
+}
+
+const EWALD_TABLE: u32 = 32u;
+
+// The Ewald correction at a nearest image displacement v, as the table has it for a unit box.
+// Mirrors simulation::ewald::lookup, which also scales it to the box
+fn ewald(v: vec3f) -> vec4f {
+    let side = EWALD_TABLE + 1u;
+    let u = min(abs(v) / params.box_size * f32(2u * EWALD_TABLE), vec3f(f32(EWALD_TABLE)));
+    let i = min(vec3u(u), vec3u(EWALD_TABLE - 1u));
+    let f = u - vec3f(i);
+
+    var c = vec4f(0.0);
+    for(var corner = 0u; corner < 8u; corner++) {
+        let o = vec3u(corner & 1u, (corner >> 1u) & 1u, corner >> 2u);
+        let w = select(1.0 - f, f, o == vec3u(1u));
+        c += (w.x * w.y * w.z) * ewald_table[i.x + o.x + side * (i.y + o.y + side * (i.z + o.z))];
+    }
+    return vec4f(c.xyz * sign(v), c.w);
+}
+
+const BLOCK_SIZE: u32 = 64u;
+
+var<workgroup> pos_shared: array<vec3f, BLOCK_SIZE>;
+var<workgroup> mass_shared: array<f32, BLOCK_SIZE>;
+
+// Same tiled pairwise sum as integrate.wgsl, but only writes out the acceleration so
+// the multi-stage integrators can evaluate it at trial positions
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    let n_stars = params.n_stars;
+    let size = params.box_size;
+    let periodic = size > 0.0;
+    var f = vec3f(0.0);
+    let pos = stars[min(id.x, n_stars - 1u)].position;
+    for(var i = 0u; i < n_stars; i += BLOCK_SIZE) {
+        // Padding for the last partial tile, skipped below since without softening a star
+        // sitting on it would get 0 * inf
+        let j = i + lid.x;
+        if j < n_stars {
+            pos_shared[lid.x] = stars[j].position;
+            mass_shared[lid.x] = stars[j].mass;
+        } else {
+            pos_shared[lid.x] = vec3f(0.0);
+            mass_shared[lid.x] = 0.0;
+        }
+        workgroupBarrier();
+
+        for(var k = 0u; k < BLOCK_SIZE; k++) {
+            if i + k != id.x && i + k < n_stars {
+                var v = pos_shared[k] - pos;
+                if periodic {
+                    v -= size * round(v / size);
+                    f += (params.G * mass_shared[k]) * (softened_inv_r3(dot(v, v)) * v + ewald(v).xyz / (size * size));
+                } else {
+                    f += (params.G * mass_shared[k] * softened_inv_r3(dot(v, v))) * v;
+                }
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if id.x < n_stars {
+        accel[id.x] = vec4f(f, 0.0);
+    }
+}
@@ -0,0 +1,120 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> accel: array<vec4<f32>>;
+@group(0) @binding(2)
+var<storage, read_write> jerk: array<vec4<f32>>;
+@group(0) @binding(3)
+var<uniform> params: SimParams;
+
+const KERNEL_PLUMMER: u32 = 0u;
+const KERNEL_SPLINE: u32 = 1u;
+
+// The softened 1/r^3, a = G * m * r * softened_inv_r3(r^2). Mirrors SofteningKernel::inv_r3
+fn softened_inv_r3(r2: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return 1.0 / pow(r2, 1.5);
+        }
+
+        let u = sqrt(r2) / h;
+        let h3 = h * h * h;
+        if u < 0.5 {
+            return (10.666667 + u * u * (32.0 * u - 38.4)) / h3;
+        }
+        return (21.333333 - 48.0 * u + 38.4 * u * u - 10.666667 * u * u * u - 0.066666667 / (u * u * u)) / h3;
+    }
+
+    return 1.0 / pow(r2 + eps * eps, 1.5);
+}
+
+// d(softened_inv_r3)/dr divided by r, for the jerk
+fn softened_dinv_r3(r2: f32, inv_r3: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return -3.0 * inv_r3 / r2;
+        }
+
+        let u = sqrt(r2) / h;
+        let h5 = h * h * h * h * h;
+        if u < 0.5 {
+            return (-76.8 + 96.0 * u) / h5;
+        }
+        return (-48.0 / u + 76.8 - 32.0 * u + 0.2 / (u * u * u * u * u)) / h5;
+    }
+
+    return -3.0 * inv_r3 / (r2 + eps * eps);
+}
+
+const BLOCK_SIZE: u32 = 64u;
+
+var<workgroup> pos_shared: array<vec3f, BLOCK_SIZE>;
+var<workgroup> vel_shared: array<vec3f, BLOCK_SIZE>;
+var<workgroup> mass_shared: array<f32, BLOCK_SIZE>;
+
+// accel.wgsl plus the time derivative of the acceleration, for the Hermite integrator
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    let n_stars = params.n_stars;
+    var a = vec3f(0.0);
+    var jk = vec3f(0.0);
+    let me = stars[min(id.x, n_stars - 1u)];
+    for(var i = 0u; i < n_stars; i += BLOCK_SIZE) {
+        let j = i + lid.x;
+        if j < n_stars {
+            pos_shared[lid.x] = stars[j].position;
+            vel_shared[lid.x] = stars[j].velocity;
+            mass_shared[lid.x] = stars[j].mass;
+        } else {
+            pos_shared[lid.x] = vec3f(0.0);
+            vel_shared[lid.x] = vec3f(0.0);
+            mass_shared[lid.x] = 0.0;
+        }
+        workgroupBarrier();
+
+        for(var k = 0u; k < BLOCK_SIZE; k++) {
+            if i + k != id.x && i + k < n_stars {
+                let r = pos_shared[k] - me.position;
+                let v = vel_shared[k] - me.velocity;
+                let r2 = dot(r, r);
+                let inv_r3 = softened_inv_r3(r2);
+                let gm = params.G * mass_shared[k];
+                a += (gm * inv_r3) * r;
+                jk += gm * (inv_r3 * v + (softened_dinv_r3(r2, inv_r3) * dot(r, v)) * r);
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if id.x < n_stars {
+        accel[id.x] = vec4f(a, 0.0);
+        jerk[id.x] = vec4f(jk, 0.0);
+    }
+}
@@ -0,0 +1,230 @@
+// Mirrors simulation::collisions::CollisionParams
+struct CollisionParams {
+    n: u32,
+    num_tiles: u32,
+    merge_radius: f32,
+    // 0 for open boundaries
+    box_size: f32
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+// Merged in place, then compacted into stars_out, which becomes the current buffer
+@group(0) @binding(0)
+var<storage, read_write> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> stars_out: array<Star>;
+// The integrator's forces carried to the next step, compacted the same way
+@group(0) @binding(2)
+var<storage, read_write> accel: array<vec4<f32>>;
+@group(0) @binding(3)
+var<storage, read_write> jerk: array<vec4<f32>>;
+@group(0) @binding(4)
+var<storage, read_write> accel_out: array<vec4<f32>>;
+@group(0) @binding(5)
+var<storage, read_write> jerk_out: array<vec4<f32>>;
+// Each star's nearest neighbour inside the merge radius, or NONE
+@group(0) @binding(6)
+var<storage, read_write> partner: array<u32>;
+@group(0) @binding(7)
+var<storage, read_write> alive: array<u32>;
+// Live stars per tile, scanned into offsets. The last entry ends up with the total
+@group(0) @binding(8)
+var<storage, read_write> counts: array<u32>;
+@group(0) @binding(9)
+var<uniform> params: CollisionParams;
+
+const NONE: u32 = 0xffffffffu;
+const BLOCK_SIZE: u32 = 64u;
+// Stars per invocation in count and scatter
+const TILE: u32 = 64u;
+const WG_SIZE: u32 = 256u;
+
+var<workgroup> pos_shared: array<vec3f, BLOCK_SIZE>;
+var<workgroup> mass_shared: array<f32, BLOCK_SIZE>;
+var<workgroup> sums: array<u32, WG_SIZE>;
+
+// The nearest image of v in a periodic box, as in accel.wgsl
+fn minimum_image(v: vec3f) -> vec3f {
+    let size = params.box_size;
+    if size > 0.0 {
+        return v - size * round(v / size);
+    }
+    return v;
+}
+
+// Same tiled pairwise loop as integrate.wgsl. Massless stars are the dead ones left at the end
+// of the buffer until the CPU shrinks it, they never merge
+@compute
+@workgroup_size(64, 1, 1)
+fn nearest(@builtin(global_invocation_id) id: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    let n = params.n;
+    let me = stars[min(id.x, n - 1u)];
+
+    var best = NONE;
+    var best_r2 = params.merge_radius * params.merge_radius;
+    for(var i = 0u; i < n; i += BLOCK_SIZE) {
+        let j = i + lid.x;
+        if j < n {
+            pos_shared[lid.x] = stars[j].position;
+            mass_shared[lid.x] = stars[j].mass;
+        } else {
+            pos_shared[lid.x] = vec3f(0.0);
+            mass_shared[lid.x] = 0.0;
+        }
+        workgroupBarrier();
+
+        for(var k = 0u; k < BLOCK_SIZE; k++) {
+            let v = minimum_image(pos_shared[k] - me.position);
+            let r2 = dot(v, v);
+            if i + k != id.x && mass_shared[k] > 0.0 && r2 < best_r2 {
+                best = i + k;
+                best_r2 = r2;
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if id.x < n {
+        partner[id.x] = select(NONE, best, me.mass > 0.0);
+    }
+}
+
+// Pairs that are each other's nearest merge into the lower index, conserving mass and momentum.
+// Each invocation only writes its own star, the absorbed one is only marked dead. Stars in
+// chains wait for a later step
+@compute
+@workgroup_size(64, 1, 1)
+fn merge(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= params.n {
+        return;
+    }
+
+    let a = stars[i];
+    var live = u32(a.mass > 0.0);
+    let j = partner[i];
+    if j != NONE && partner[j] == i {
+        if i < j {
+            let b = stars[j];
+            let m = a.mass + b.mass;
+            // Around a, to b's nearest image, and back into the box like integrators.wgsl wrap
+            var position = a.position + b.mass * minimum_image(b.position - a.position) / m;
+            if params.box_size > 0.0 {
+                position -= params.box_size * floor(position / params.box_size + 0.5);
+            }
+            stars[i] = Star(
+                position,
+                m,
+                (a.mass * a.velocity + b.mass * b.velocity) / m,
+                max(a.bright, b.bright)
+            );
+            // Not the force at the merged state, but close, and it keeps the total momentum
+            // the next kick adds unchanged
+            accel[i] = (a.mass * accel[i] + b.mass * accel[j]) / m;
+            jerk[i] = (a.mass * jerk[i] + b.mass * jerk[j]) / m;
+        } else {
+            live = 0u;
+        }
+    }
+    alive[i] = live;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn count(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tile = id.x;
+    if tile >= params.num_tiles {
+        return;
+    }
+
+    var total = 0u;
+    let end = min((tile + 1u) * TILE, params.n);
+    for(var i = tile * TILE; i < end; i++) {
+        total += alive[i];
+    }
+    counts[tile] = total;
+}
+
+// Exclusive scan of the tile counts with a single workgroup, as in radix_scan.wgsl
+@compute
+@workgroup_size(256, 1, 1)
+fn scan(@builtin(local_invocation_id) lid: vec3<u32>) {
+    let len = params.num_tiles;
+    let per = (len + WG_SIZE - 1u) / WG_SIZE;
+    let start = lid.x * per;
+    let end = min(start + per, len);
+
+    var total = 0u;
+    for(var i = start; i < end; i++) {
+        total += counts[i];
+    }
+    sums[lid.x] = total;
+    workgroupBarrier();
+
+    for(var offset = 1u; offset < WG_SIZE; offset <<= 1u) {
+        var v = 0u;
+        if lid.x >= offset {
+            v = sums[lid.x - offset];
+        }
+        workgroupBarrier();
+        sums[lid.x] += v;
+        workgroupBarrier();
+    }
+
+    var running = sums[lid.x] - total;
+    for(var i = start; i < end; i++) {
+        let c = counts[i];
+        counts[i] = running;
+        running += c;
+    }
+    if lid.x == WG_SIZE - 1u {
+        counts[len] = sums[lid.x];
+    }
+}
+
+// Walks its tile in order, so the stars keep their order. The dead go after the live ones,
+// massless but where they were, so they can't land on top of each other and divide by zero in
+// an unsoftened force loop before the CPU shrinks the buffer
+@compute
+@workgroup_size(64, 1, 1)
+fn scatter(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tile = id.x;
+    if tile >= params.num_tiles {
+        return;
+    }
+
+    var live = counts[tile];
+    var dead = counts[params.num_tiles] + tile * TILE - live;
+    let end = min((tile + 1u) * TILE, params.n);
+    for(var i = tile * TILE; i < end; i++) {
+        var out = live;
+        if alive[i] == 1u {
+            live += 1u;
+        } else {
+            out = dead;
+            dead += 1u;
+        }
+
+        var star = stars[i];
+        star.mass *= f32(alive[i]);
+        stars_out[out] = star;
+        accel_out[out] = accel[i];
+        jerk_out[out] = jerk[i];
+    }
+}
+
+// The compacted forces go back where the integrator reads them
+@compute
+@workgroup_size(64, 1, 1)
+fn copy_back(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x < params.n {
+        accel[id.x] = accel_out[id.x];
+        jerk[id.x] = jerk_out[id.x];
+    }
+}
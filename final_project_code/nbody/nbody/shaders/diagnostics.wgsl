@@ -0,0 +1,161 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+// Mirrors simulation::diagnostics::Partial, one per workgroup
+struct Partial {
+    // Kinetic energy, potential energy, mass
+    energy: vec4<f32>,
+    momentum: vec4<f32>,
+    angular_momentum: vec4<f32>,
+    // Mass weighted position, for the center of mass
+    moment: vec4<f32>
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> partials: array<Partial>;
+@group(0) @binding(2)
+var<uniform> params: SimParams;
+// simulation::ewald::table, a single unused entry for open boundaries
+@group(0) @binding(3)
+var<storage, read> ewald_table: array<vec4<f32>>;
+
+const KERNEL_PLUMMER: u32 = 0u;
+const KERNEL_SPLINE: u32 = 1u;
+
+// The softened 1/r, phi = -G * m * softened_inv_r(r^2). Mirrors SofteningKernel::inv_r
+fn softened_inv_r(r2: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return inverseSqrt(r2);
+        }
+
+        let u = sqrt(r2) / h;
+        if u < 0.5 {
+            return (2.8 - u * u * (5.333333 + u * u * (6.4 * u - 9.6))) / h;
+        }
+        return (3.2 - 0.066666667 / u - u * u * (10.666667 + u * (-16.0 + u * (9.6 - 2.133333 * u)))) / h;
+    }
+
+    return inverseSqrt(r2 + eps * eps);
+}
+
+const EWALD_TABLE: u32 = 32u;
+
+// Same as accel.wgsl
+fn ewald(v: vec3f) -> vec4f {
+    let side = EWALD_TABLE + 1u;
+    let u = min(abs(v) / params.box_size * f32(2u * EWALD_TABLE), vec3f(f32(EWALD_TABLE)));
+    let i = min(vec3u(u), vec3u(EWALD_TABLE - 1u));
+    let f = u - vec3f(i);
+
+    var c = vec4f(0.0);
+    for(var corner = 0u; corner < 8u; corner++) {
+        let o = vec3u(corner & 1u, (corner >> 1u) & 1u, corner >> 2u);
+        let w = select(1.0 - f, f, o == vec3u(1u));
+        c += (w.x * w.y * w.z) * ewald_table[i.x + o.x + side * (i.y + o.y + side * (i.z + o.z))];
+    }
+    return vec4f(c.xyz * sign(v), c.w);
+}
+
+const BLOCK_SIZE: u32 = 64u;
+
+var<workgroup> pos_shared: array<vec3f, BLOCK_SIZE>;
+var<workgroup> mass_shared: array<f32, BLOCK_SIZE>;
+var<workgroup> sums: array<Partial, BLOCK_SIZE>;
+
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+    @builtin(workgroup_id) wid: vec3<u32>
+) {
+    let n_stars = params.n_stars;
+    let s = stars[min(id.x, n_stars - 1u)];
+    let size = params.box_size;
+    let periodic = size > 0.0;
+
+    // Same tiled pairwise loop as integrate.wgsl, summing the potential instead of the force
+    var phi = 0.0;
+    for(var i = 0u; i < n_stars; i += BLOCK_SIZE) {
+        let j = i + lid.x;
+        if j < n_stars {
+            pos_shared[lid.x] = stars[j].position;
+            mass_shared[lid.x] = stars[j].mass;
+        } else {
+            pos_shared[lid.x] = vec3f(0.0);
+            mass_shared[lid.x] = 0.0;
+        }
+        workgroupBarrier();
+
+        // The padding is skipped rather than weighted by zero, 1/r of a star on it could be inf
+        for(var k = 0u; k < BLOCK_SIZE; k++) {
+            if i + k != id.x && i + k < n_stars {
+                var v = pos_shared[k] - s.position;
+                if periodic {
+                    v -= size * round(v / size);
+                    phi -= params.G * mass_shared[k] * (softened_inv_r(dot(v, v)) + ewald(v).w / size);
+                } else {
+                    phi -= params.G * mass_shared[k] * softened_inv_r(dot(v, v));
+                }
+            }
+        }
+        workgroupBarrier();
+    }
+
+    // Every star also sits in the potential of its own images
+    if periodic {
+        phi -= params.G * s.mass * ewald_table[0].w / size;
+    }
+
+    var p = Partial(vec4f(0.0), vec4f(0.0), vec4f(0.0), vec4f(0.0));
+    if id.x < n_stars {
+        let m = s.mass;
+        // Every pair is seen from both ends, hence half the potential
+        p.energy = vec4f(0.5 * m * dot(s.velocity, s.velocity), 0.5 * m * phi, m, 0.0);
+        p.momentum = vec4f(m * s.velocity, 0.0);
+        p.angular_momentum = vec4f(m * cross(s.position, s.velocity), 0.0);
+        p.moment = vec4f(m * s.position, 0.0);
+    }
+    sums[lid.x] = p;
+    workgroupBarrier();
+
+    // Tree reduction over the workgroup, the CPU adds up the workgroups in f64
+    for(var stride = BLOCK_SIZE / 2u; stride > 0u; stride /= 2u) {
+        if lid.x < stride {
+            let o = sums[lid.x + stride];
+            sums[lid.x].energy += o.energy;
+            sums[lid.x].momentum += o.momentum;
+            sums[lid.x].angular_momentum += o.angular_momentum;
+            sums[lid.x].moment += o.moment;
+        }
+        workgroupBarrier();
+    }
+
+    if lid.x == 0u {
+        partials[wid.x] = sums[0];
+    }
+}
@@ -0,0 +1,31 @@
+@group(0) @binding(0)
+var<uniform> vp_mat: mat4x4<f32>;
+// Same as draw_stars.wgsl
+@group(0) @binding(1)
+var<uniform> extent: vec4<f32>;
+// x is half the periodic box's side, in simulation units
+@group(0) @binding(2)
+var<uniform> box_size: vec4<f32>;
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4f(0.35, 0.35, 0.35, 1.0);
+}
+
+// The 12 edges as a line list, 4 along each axis, with no vertex buffer
+@vertex
+fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+    let edge = i / 2u;
+    let along = f32(i & 1u) * 2.0 - 1.0;
+    let a = f32(edge & 1u) * 2.0 - 1.0;
+    let b = f32((edge >> 1u) & 1u) * 2.0 - 1.0;
+
+    var corner: vec3f;
+    switch edge / 4u {
+        case 0u: { corner = vec3f(along, a, b); }
+        case 1u: { corner = vec3f(a, along, b); }
+        default: { corner = vec3f(a, b, along); }
+    }
+
+    return vp_mat * vec4f((corner * box_size.x - extent.xyz) / extent.w, 1.0);
+}
@@ -1,7 +1,8 @@
 @group(0) @binding(0)
 var<uniform> vp_mat: mat4x4<f32>;
-
-const UNIVERSE_SIZE: f32 = 9.0E8;
+// xyz is the center of the scene, w its half width, in simulation units
+@group(0) @binding(1)
+var<uniform> extent: vec4<f32>;
 
 struct Star {
     @location(0) position: vec3<f32>,
@@ -29,7 +30,7 @@ fn fs_main(vo: VertexOut) -> @location(0) vec4<f32> {
 fn vs_main(
     star: Star
 ) -> VertexOut {
-    let p = vp_mat * vec4f(star.position/vec3f(UNIVERSE_SIZE/2.0) - vec3f(1.0, 1.0, 1.0), 1.0);
+    let p = vp_mat * vec4f((star.position - extent.xyz) / extent.w, 1.0);
 
     return VertexOut(p, star.col);
 }
\ No newline at end of file
@@ -1,3 +1,18 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
 struct Star {
     position: vec3<f32>,
     mass: f32,
@@ -5,13 +20,36 @@ struct Star {
     bright: f32
 }
 
+// Reads state N and writes state N + 1 to the other buffer, see PingPongBuffer
 @group(0) @binding(0)
-var<storage, read_write> stars: array<Star>;
+var<storage, read> stars: array<Star>;
 @group(0) @binding(1)
-var<uniform> n_stars: u32;
+var<storage, read_write> stars_out: array<Star>;
+@group(0) @binding(2)
+var<uniform> params: SimParams;
 
-const G: f32 = 6.67430E-11;
-const S: f32 = 1.0E6;
+const KERNEL_PLUMMER: u32 = 0u;
+const KERNEL_SPLINE: u32 = 1u;
+
+// The softened 1/r^3, a = G * m * r * softened_inv_r3(r^2). Mirrors SofteningKernel::inv_r3
+fn softened_inv_r3(r2: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return 1.0 / pow(r2, 1.5);
+        }
+
+        let u = sqrt(r2) / h;
+        let h3 = h * h * h;
+        if u < 0.5 {
+            return (10.666667 + u * u * (32.0 * u - 38.4)) / h3;
+        }
+        return (21.333333 - 48.0 * u + 38.4 * u * u - 10.666667 * u * u * u - 0.066666667 / (u * u * u)) / h3;
+    }
+
+    return 1.0 / pow(r2 + eps * eps, 1.5); // pow is almost twice as fast as length + normalize
+}
 
 const BLOCK_SIZE: i32 = 64;
 
@@ -22,28 +60,37 @@ var<workgroup> mass_shared: array<f32, BLOCK_SIZE>;
 @workgroup_size(64, 1, 1)
 fn cs_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
     let block_idx = i32(lid.x);
-    let S2 = S*S;
+    let n_stars = i32(params.n_stars);
 
+    // Threads past the end still have to load their share of every tile
     var f = vec3f(0.0);
-    let pos = stars[id.x].position;
-    for(var i: i32 = 0; i < i32(n_stars); i+=BLOCK_SIZE) {
-        pos_shared[block_idx] = stars[i+block_idx].position;
-        mass_shared[block_idx] = stars[i+block_idx].mass;
+    let pos = stars[min(i32(id.x), n_stars - 1)].position;
+    for(var i: i32 = 0; i < n_stars; i+=BLOCK_SIZE) {
+        if i + block_idx < n_stars {
+            pos_shared[block_idx] = stars[i+block_idx].position;
+            mass_shared[block_idx] = stars[i+block_idx].mass;
+        } else {
+            pos_shared[block_idx] = vec3f(0.0);
+            mass_shared[block_idx] = 0.0;
+        }
         workgroupBarrier();
 
         for(var j: i32 = 0; j < BLOCK_SIZE; j++) {
             let idx = i + j;
-            if idx != i32(id.x) {
+            if idx != i32(id.x) && idx < n_stars {
                 let v = pos_shared[j] - pos;
-                // let r = length(v);
-                // let dir = normalize(v);
-                let r = pow(dot(v, v) + S2, 1.5); // almost doubles performance
-                //f += dir * (G * mass_shared[j]) / (r * r + S2);
-                f += ((G * mass_shared[j]) / r) * v;
+                f += (params.G * mass_shared[j] * softened_inv_r3(dot(v, v))) * v;
             }
         }
+        workgroupBarrier();
+    }
+
+    if i32(id.x) >= n_stars {
+        return;
     }
 
-    stars[id.x].velocity += f;
-    stars[id.x].position += stars[id.x].velocity;
+    var s = stars[id.x];
+    s.velocity += f * params.dt;
+    s.position += s.velocity * params.dt;
+    stars_out[id.x] = s;
 }
\ No newline at end of file
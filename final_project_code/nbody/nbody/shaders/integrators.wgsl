@@ -0,0 +1,204 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+struct RkStage {
+    weight: f32,
+    step: f32
+}
+
+// Each kernel below is one step of an integrator in simulation::Integrator, the
+// accelerations between them come from accel.wgsl/accel_jerk.wgsl or a CPU solver
+@group(0) @binding(0)
+var<storage, read_write> stars: array<Star>;
+// Trial state the forces get evaluated at (RK4 stages, Hermite prediction)
+@group(0) @binding(1)
+var<storage, read_write> trial: array<Star>;
+@group(0) @binding(2)
+var<storage, read_write> accel: array<vec4<f32>>;
+@group(0) @binding(3)
+var<storage, read_write> accel_old: array<vec4<f32>>;
+@group(0) @binding(4)
+var<storage, read_write> jerk: array<vec4<f32>>;
+@group(0) @binding(5)
+var<storage, read_write> accel_new: array<vec4<f32>>;
+@group(0) @binding(6)
+var<storage, read_write> jerk_new: array<vec4<f32>>;
+@group(0) @binding(7)
+var<storage, read_write> sum_x: array<vec4<f32>>;
+@group(0) @binding(8)
+var<storage, read_write> sum_v: array<vec4<f32>>;
+@group(0) @binding(9)
+var<uniform> params: SimParams;
+@group(1) @binding(0)
+var<uniform> rk: RkStage;
+
+@compute
+@workgroup_size(64, 1, 1)
+fn euler(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].velocity += accel[id.x].xyz * dt;
+    stars[id.x].position += stars[id.x].velocity * dt;
+}
+
+// Leapfrog's two half kicks and the drift between them go by the factors in params, dt / 2 and
+// dt, or for comoving runs the integrals of dt / a and dt / a^2 over their part of the step
+@compute
+@workgroup_size(64, 1, 1)
+fn kick_open(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].velocity += accel[id.x].xyz * params.kick.x;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn kick_close(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].velocity += accel[id.x].xyz * params.kick.y;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn drift(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].position += stars[id.x].velocity * params.drift;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn verlet_drift(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    // Grouped so the small acceleration term is rounded into the velocity, not the position
+    stars[id.x].position += (stars[id.x].velocity + accel[id.x].xyz * (dt / 2.0)) * dt;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn verlet_kick(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].velocity += (accel_old[id.x].xyz + accel[id.x].xyz) * (dt / 2.0);
+}
+
+// Accumulates k_n = (trial velocity, accel) with its weight, then sets up the next trial state
+@compute
+@workgroup_size(64, 1, 1)
+fn rk4_stage(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let kx = trial[id.x].velocity;
+    let kv = accel[id.x].xyz;
+    sum_x[id.x] += vec4f(rk.weight * kx, 0.0);
+    sum_v[id.x] += vec4f(rk.weight * kv, 0.0);
+
+    trial[id.x].position = stars[id.x].position + (rk.step * dt) * kx;
+    trial[id.x].velocity = stars[id.x].velocity + (rk.step * dt) * kv;
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn rk4_finish(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    stars[id.x].position += (dt / 6.0) * (sum_x[id.x].xyz + trial[id.x].velocity);
+    stars[id.x].velocity += (dt / 6.0) * (sum_v[id.x].xyz + accel[id.x].xyz);
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn hermite_predict(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let s = stars[id.x];
+    let a = accel[id.x].xyz;
+    let j = jerk[id.x].xyz;
+    trial[id.x] = Star(
+        s.position + s.velocity * dt + a * (dt * dt / 2.0) + j * (dt * dt * dt / 6.0),
+        s.mass,
+        s.velocity + a * dt + j * (dt * dt / 2.0),
+        s.bright
+    );
+}
+
+// Makino & Aarseth 1992, the new forces become the old ones for the next step
+@compute
+@workgroup_size(64, 1, 1)
+fn hermite_correct(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dt = params.dt;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let a0 = accel[id.x].xyz;
+    let j0 = jerk[id.x].xyz;
+    let a1 = accel_new[id.x].xyz;
+    let j1 = jerk_new[id.x].xyz;
+
+    let v0 = stars[id.x].velocity;
+    let v1 = v0 + (a0 + a1) * (dt / 2.0) + (j0 - j1) * (dt * dt / 12.0);
+    stars[id.x].position += (v0 + v1) * (dt / 2.0) + (a0 - a1) * (dt * dt / 12.0);
+    stars[id.x].velocity = v1;
+
+    accel[id.x] = vec4f(a1, 0.0);
+    jerk[id.x] = vec4f(j1, 0.0);
+}
+
+// Brings everything back into the periodic box after the step, [-L/2, L/2) on each axis
+@compute
+@workgroup_size(64, 1, 1)
+fn wrap(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = params.box_size;
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let p = stars[id.x].position;
+    stars[id.x].position = p - size * floor(p / size + 0.5);
+}
@@ -0,0 +1,274 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+// Mirrors simulation::pm::PmParams
+struct PmParams {
+    mesh: u32,
+    size: u32,
+    log2_size: u32,
+    _pad: u32
+}
+
+// Mirrors simulation::pm::FftParams
+struct FftParams {
+    axis: u32,
+    inverse: u32,
+    _pad0: u32,
+    _pad1: u32
+}
+
+// Where the mesh sits this evaluation, written by `bounds`
+struct Geometry {
+    origin: vec3f,
+    h: f32,
+    // Fixed point units per unit of mass in `density`
+    mass_scale: f32
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> accel: array<vec4<f32>>;
+// Mass per cell of the mesh in fixed point, WGSL has no float atomics and not every backend
+// has compare-exchange. The whole mass is 2^31 units, so one star in a million still gets
+// about two thousand
+@group(0) @binding(2)
+var<storage, read_write> density: array<atomic<u32>>;
+// The zero padded grid, complex and transformed in place
+@group(0) @binding(3)
+var<storage, read_write> grid: array<vec2<f32>>;
+// See simulation::pm::greens
+@group(0) @binding(4)
+var<storage, read> greens: array<f32>;
+// -grad phi at each cell of the mesh
+@group(0) @binding(5)
+var<storage, read_write> field: array<vec4<f32>>;
+@group(0) @binding(6)
+var<storage, read_write> geometry: Geometry;
+@group(0) @binding(7)
+var<uniform> params: SimParams;
+@group(0) @binding(8)
+var<uniform> pm: PmParams;
+@group(1) @binding(0)
+var<uniform> fft_params: FftParams;
+
+const WG_SIZE: u32 = 256u;
+// Must match simulation::pm::MARGIN and MAX_MESH
+const MARGIN: u32 = 3u;
+const MAX_SIZE: u32 = 512u;
+const PI: f32 = 3.14159265358979;
+
+var<workgroup> lo: array<vec3f, WG_SIZE>;
+var<workgroup> hi: array<vec3f, WG_SIZE>;
+var<workgroup> mass: array<f32, WG_SIZE>;
+var<workgroup> line: array<vec2f, MAX_SIZE>;
+
+// Same reduction as tree_bounds.wgsl plus the total mass, then the cube around the stars as in
+// simulation::pm::geometry
+@compute
+@workgroup_size(256, 1, 1)
+fn bounds(@builtin(local_invocation_id) lid: vec3<u32>) {
+    var mn = vec3f(3.4E38);
+    var mx = vec3f(-3.4E38);
+    var m = 0.0;
+    for(var i = lid.x; i < params.n_stars; i += WG_SIZE) {
+        mn = min(mn, stars[i].position);
+        mx = max(mx, stars[i].position);
+        m += stars[i].mass;
+    }
+    lo[lid.x] = mn;
+    hi[lid.x] = mx;
+    mass[lid.x] = m;
+    workgroupBarrier();
+
+    for(var s = WG_SIZE / 2u; s > 0u; s >>= 1u) {
+        if lid.x < s {
+            lo[lid.x] = min(lo[lid.x], lo[lid.x + s]);
+            hi[lid.x] = max(hi[lid.x], hi[lid.x + s]);
+            mass[lid.x] += mass[lid.x + s];
+        }
+        workgroupBarrier();
+    }
+
+    if lid.x == 0u {
+        let d = hi[0] - lo[0];
+        let h = max(max(d.x, d.y), max(d.z, 1e-30)) / f32(pm.mesh - 2u * MARGIN);
+        let origin = (lo[0] + hi[0]) / 2.0 - 0.5 * f32(pm.mesh) * h;
+        geometry = Geometry(origin, h, 2147483648.0 / max(mass[0], 1e-30));
+    }
+}
+
+struct Cic {
+    // Lower corner of the 8 cells a star spreads over
+    cell: vec3<u32>,
+    // How far towards the upper ones
+    f: vec3f
+}
+
+fn cic(position: vec3f) -> Cic {
+    let u = (position - geometry.origin) / geometry.h - 0.5;
+    let i = clamp(floor(u), vec3f(0.0), vec3f(f32(pm.mesh - 2u)));
+    return Cic(vec3<u32>(i), u - i);
+}
+
+fn weight(f: vec3f, d: vec3<u32>) -> f32 {
+    let w = select(1.0 - f, f, d == vec3<u32>(1u));
+    return w.x * w.y * w.z;
+}
+
+fn corner(c: u32) -> vec3<u32> {
+    return vec3<u32>(c & 1u, (c >> 1u) & 1u, c >> 2u);
+}
+
+fn mesh_index(c: vec3<u32>) -> u32 {
+    return c.x + pm.mesh * (c.y + pm.mesh * c.z);
+}
+
+fn grid_index(c: vec3<u32>) -> u32 {
+    return c.x + pm.size * (c.y + pm.size * c.z);
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn assign(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let star = stars[id.x];
+    let c = cic(star.position);
+    for(var k = 0u; k < 8u; k++) {
+        let d = corner(k);
+        atomicAdd(&density[mesh_index(c.cell + d)], u32(star.mass * weight(c.f, d) * geometry.mass_scale + 0.5));
+    }
+}
+
+// One invocation per cell of the padded grid, id.y is z
+@compute
+@workgroup_size(64, 1, 1)
+fn load(@builtin(global_invocation_id) id: vec3<u32>) {
+    let c = vec3<u32>(id.x % pm.size, id.x / pm.size, id.y);
+    var m = 0.0;
+    if all(c < vec3<u32>(pm.mesh)) {
+        m = f32(atomicLoad(&density[mesh_index(c)])) / geometry.mass_scale;
+    }
+    grid[grid_index(c)] = vec2f(m, 0.0);
+}
+
+fn line_index(a: u32, b: u32, k: u32) -> u32 {
+    switch fft_params.axis {
+        case 0u: { return grid_index(vec3<u32>(k, a, b)); }
+        case 1u: { return grid_index(vec3<u32>(a, k, b)); }
+        default: { return grid_index(vec3<u32>(a, b, k)); }
+    }
+}
+
+// Radix-2 along fft_params.axis in workgroup memory, unnormalized both ways like
+// simulation::pm::fft. A workgroup takes MAX_SIZE / size lines with one butterfly per invocation,
+// dispatched as (size / 8)^3 workgroups
+@compute
+@workgroup_size(256, 1, 1)
+fn fft(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    let n = pm.size;
+    let half_n = n / 2u;
+    let groups = n / 8u;
+    let first = (wid.x + groups * (wid.y + groups * wid.z)) * (MAX_SIZE / n);
+
+    // This invocation's line, where it sits in `line`, and its butterfly
+    let l = first + lid.x / half_n;
+    let a = l % n;
+    let b = l / n;
+    let base = (lid.x / half_n) * n;
+    let t = lid.x % half_n;
+
+    let shift = 32u - pm.log2_size;
+    line[base + (reverseBits(t) >> shift)] = grid[line_index(a, b, t)];
+    line[base + (reverseBits(t + half_n) >> shift)] = grid[line_index(a, b, t + half_n)];
+    workgroupBarrier();
+
+    let sign = select(-1.0, 1.0, fft_params.inverse == 1u);
+    for(var half = 1u; half < n; half <<= 1u) {
+        let j = t % half;
+        let i = base + (t / half) * 2u * half + j;
+        let angle = sign * PI * f32(j) / f32(half);
+        let w = vec2f(cos(angle), sin(angle));
+        let u = line[i];
+        let v = line[i + half];
+        let wv = vec2f(w.x * v.x - w.y * v.y, w.x * v.y + w.y * v.x);
+        line[i] = u + wv;
+        line[i + half] = u - wv;
+        workgroupBarrier();
+    }
+
+    grid[line_index(a, b, t)] = line[base + t];
+    grid[line_index(a, b, t + half_n)] = line[base + t + half_n];
+}
+
+// Multiplies by the Green's function, with G, the cell size and the inverse FFT's 1 / size^3
+@compute
+@workgroup_size(64, 1, 1)
+fn convolve(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x + id.y * pm.size * pm.size;
+    let size = f32(pm.size);
+    grid[i] *= greens[i] * (-params.G / (geometry.h * size * size * size));
+}
+
+fn phi(c: vec3<u32>) -> f32 {
+    return grid[grid_index(c)].x;
+}
+
+// phi(c + d) - phi(c - d) along each axis, clamped at the edge of the mesh where no star reaches
+fn difference(c: vec3<u32>, d: u32) -> vec3f {
+    let up = min(c + d, vec3<u32>(pm.mesh - 1u));
+    let down = max(c, vec3<u32>(d)) - d;
+    return vec3f(
+        phi(vec3<u32>(up.x, c.y, c.z)) - phi(vec3<u32>(down.x, c.y, c.z)),
+        phi(vec3<u32>(c.x, up.y, c.z)) - phi(vec3<u32>(c.x, down.y, c.z)),
+        phi(vec3<u32>(c.x, c.y, up.z)) - phi(vec3<u32>(c.x, c.y, down.z))
+    );
+}
+
+// Four point differences of the potential
+@compute
+@workgroup_size(64, 1, 1)
+fn gradient(@builtin(global_invocation_id) id: vec3<u32>) {
+    let c = vec3<u32>(id.x % pm.mesh, id.x / pm.mesh, id.y);
+    let e = (8.0 * difference(c, 1u) - difference(c, 2u)) / 12.0;
+    field[mesh_index(c)] = vec4f(-e / geometry.h, 0.0);
+}
+
+// Back to the stars with the weights they were assigned with
+@compute
+@workgroup_size(64, 1, 1)
+fn interpolate(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars {
+        return;
+    }
+
+    let c = cic(stars[id.x].position);
+    var a = vec3f(0.0);
+    for(var k = 0u; k < 8u; k++) {
+        let d = corner(k);
+        a += weight(c.f, d) * field[mesh_index(c.cell + d)].xyz;
+    }
+    accel[id.x] = vec4f(a, 0.0);
+}
@@ -0,0 +1,44 @@
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+@group(0) @binding(0)
+var<storage, read> keys: array<vec2<u32>>;
+@group(0) @binding(1)
+var<storage, read_write> hist: array<u32>;
+@group(0) @binding(2)
+var<uniform> params: TreeParams;
+
+const TILE: u32 = 64u;
+const RADIX: u32 = 16u;
+
+fn digit(key: vec2<u32>, shift: u32) -> u32 {
+    if shift < 32u {
+        return (key.y >> shift) & (RADIX - 1u);
+    }
+    return (key.x >> (shift - 32u)) & (RADIX - 1u);
+}
+
+// One invocation per tile of keys. The histogram is stored digit-major so an exclusive
+// scan over the whole thing gives each (digit, tile) its output offset.
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tile = id.x;
+    if tile >= params.num_tiles {
+        return;
+    }
+
+    var counts: array<u32, RADIX>;
+    let end = min((tile + 1u) * TILE, params.n);
+    for(var i = tile * TILE; i < end; i++) {
+        counts[digit(keys[i], params.shift)] += 1u;
+    }
+
+    for(var d = 0u; d < RADIX; d++) {
+        hist[d * params.num_tiles + tile] = counts[d];
+    }
+}
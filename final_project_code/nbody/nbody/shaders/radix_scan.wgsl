@@ -0,0 +1,50 @@
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+@group(0) @binding(0)
+var<storage, read_write> hist: array<u32>;
+@group(0) @binding(1)
+var<uniform> params: TreeParams;
+
+const WG_SIZE: u32 = 256u;
+const RADIX: u32 = 16u;
+
+var<workgroup> sums: array<u32, WG_SIZE>;
+
+// In-place exclusive scan of the whole histogram with a single workgroup
+@compute
+@workgroup_size(256, 1, 1)
+fn cs_main(@builtin(local_invocation_id) lid: vec3<u32>) {
+    let len = RADIX * params.num_tiles;
+    let per = (len + WG_SIZE - 1u) / WG_SIZE;
+    let start = lid.x * per;
+    let end = min(start + per, len);
+
+    var total = 0u;
+    for(var i = start; i < end; i++) {
+        total += hist[i];
+    }
+    sums[lid.x] = total;
+    workgroupBarrier();
+
+    for(var offset = 1u; offset < WG_SIZE; offset <<= 1u) {
+        var v = 0u;
+        if lid.x >= offset {
+            v = sums[lid.x - offset];
+        }
+        workgroupBarrier();
+        sums[lid.x] += v;
+        workgroupBarrier();
+    }
+
+    var running = sums[lid.x] - total;
+    for(var i = start; i < end; i++) {
+        let c = hist[i];
+        hist[i] = running;
+        running += c;
+    }
+}
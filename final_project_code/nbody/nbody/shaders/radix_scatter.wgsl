@@ -0,0 +1,53 @@
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+@group(0) @binding(0)
+var<storage, read> keys_in: array<vec2<u32>>;
+@group(0) @binding(1)
+var<storage, read> values_in: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> keys_out: array<vec2<u32>>;
+@group(0) @binding(3)
+var<storage, read_write> values_out: array<u32>;
+@group(0) @binding(4)
+var<storage, read> hist: array<u32>;
+@group(0) @binding(5)
+var<uniform> params: TreeParams;
+
+const TILE: u32 = 64u;
+const RADIX: u32 = 16u;
+
+fn digit(key: vec2<u32>, shift: u32) -> u32 {
+    if shift < 32u {
+        return (key.y >> shift) & (RADIX - 1u);
+    }
+    return (key.x >> (shift - 32u)) & (RADIX - 1u);
+}
+
+// Walks its tile in order, so the sort is stable
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tile = id.x;
+    if tile >= params.num_tiles {
+        return;
+    }
+
+    var offsets: array<u32, RADIX>;
+    for(var d = 0u; d < RADIX; d++) {
+        offsets[d] = hist[d * params.num_tiles + tile];
+    }
+
+    let end = min((tile + 1u) * TILE, params.n);
+    for(var i = tile * TILE; i < end; i++) {
+        let key = keys_in[i];
+        let d = digit(key, params.shift);
+        keys_out[offsets[d]] = key;
+        values_out[offsets[d]] = values_in[i];
+        offsets[d] += 1u;
+    }
+}
@@ -0,0 +1,53 @@
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read_write> bounds: array<vec4<f32>, 2>;
+@group(0) @binding(2)
+var<uniform> params: TreeParams;
+
+const WG_SIZE: u32 = 256u;
+
+var<workgroup> lo: array<vec3f, WG_SIZE>;
+var<workgroup> hi: array<vec3f, WG_SIZE>;
+
+// Single workgroup, each invocation strides over the stars then the results are tree-reduced
+@compute
+@workgroup_size(256, 1, 1)
+fn cs_main(@builtin(local_invocation_id) lid: vec3<u32>) {
+    var mn = vec3f(3.4E38);
+    var mx = vec3f(-3.4E38);
+    for(var i = lid.x; i < params.n; i += WG_SIZE) {
+        mn = min(mn, stars[i].position);
+        mx = max(mx, stars[i].position);
+    }
+    lo[lid.x] = mn;
+    hi[lid.x] = mx;
+    workgroupBarrier();
+
+    for(var s = WG_SIZE / 2u; s > 0u; s >>= 1u) {
+        if lid.x < s {
+            lo[lid.x] = min(lo[lid.x], lo[lid.x + s]);
+            hi[lid.x] = max(hi[lid.x], hi[lid.x + s]);
+        }
+        workgroupBarrier();
+    }
+
+    if lid.x == 0u {
+        bounds[0] = vec4f(lo[0], 0.0);
+        bounds[1] = vec4f(hi[0], 0.0);
+    }
+}
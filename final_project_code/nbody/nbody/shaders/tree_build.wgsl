@@ -0,0 +1,110 @@
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+struct Node {
+    left: u32,
+    right: u32,
+    parent: u32,
+    first: u32,
+    last: u32
+}
+
+@group(0) @binding(0)
+var<storage, read> keys: array<vec2<u32>>;
+@group(0) @binding(1)
+var<storage, read_write> nodes: array<Node>;
+@group(0) @binding(2)
+var<storage, read_write> leaf_parents: array<u32>;
+@group(0) @binding(3)
+var<uniform> params: TreeParams;
+
+const LEAF_BIT: u32 = 0x80000000u;
+const NONE: u32 = 0xFFFFFFFFu;
+
+// Length of the common key prefix, ties broken by index so duplicate codes still form a tree
+fn delta(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(params.n) {
+        return -1;
+    }
+
+    let a = keys[i];
+    let b = keys[j];
+    if a.x != b.x {
+        return i32(countLeadingZeros(a.x ^ b.x));
+    }
+    if a.y != b.y {
+        return 32 + i32(countLeadingZeros(a.y ^ b.y));
+    }
+    return 64 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+}
+
+// One invocation per internal node, Karras 2012
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x + 1u >= params.n {
+        return;
+    }
+    let i = i32(id.x);
+
+    let d = select(-1, 1, delta(i, i + 1) > delta(i, i - 1));
+    let d_min = delta(i, i - d);
+
+    var l_max = 2;
+    while delta(i, i + l_max * d) > d_min {
+        l_max *= 2;
+    }
+
+    var l = 0;
+    for(var t = l_max / 2; t >= 1; t /= 2) {
+        if delta(i, i + (l + t) * d) > d_min {
+            l += t;
+        }
+    }
+    let j = i + l * d;
+
+    let d_node = delta(i, j);
+    var s = 0;
+    var t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(i, i + (s + t) * d) > d_node {
+            s += t;
+        }
+        if t <= 1 {
+            break;
+        }
+    }
+    let gamma = i + s * d + min(d, 0);
+
+    let first = min(i, j);
+    let last = max(i, j);
+
+    var left = u32(gamma);
+    if first == gamma {
+        leaf_parents[gamma] = u32(i);
+        left |= LEAF_BIT;
+    } else {
+        nodes[gamma].parent = u32(i);
+    }
+
+    var right = u32(gamma + 1);
+    if last == gamma + 1 {
+        leaf_parents[gamma + 1] = u32(i);
+        right |= LEAF_BIT;
+    } else {
+        nodes[gamma + 1].parent = u32(i);
+    }
+
+    nodes[i].left = left;
+    nodes[i].right = right;
+    nodes[i].first = u32(first);
+    nodes[i].last = u32(last);
+    if i == 0 {
+        nodes[0].parent = NONE;
+    }
+}
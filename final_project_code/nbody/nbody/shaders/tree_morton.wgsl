@@ -0,0 +1,55 @@
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read> bounds: array<vec4<f32>, 2>;
+// (hi, lo) halves of each 64 bit key
+@group(0) @binding(2)
+var<storage, read_write> keys: array<vec2<u32>>;
+@group(0) @binding(3)
+var<storage, read_write> values: array<u32>;
+@group(0) @binding(4)
+var<uniform> params: TreeParams;
+
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n {
+        return;
+    }
+
+    let lo = bounds[0].xyz;
+    let extent = max(bounds[1].xyz - lo, vec3f(1.0E-30));
+    let scale = f32((1u << params.bits_per_axis) - 1u);
+    let q = vec3<u32>(clamp((stars[id.x].position - lo) / extent, vec3f(0.0), vec3f(1.0)) * scale);
+
+    // x takes the highest bit of each triple
+    var key = vec2<u32>(0u);
+    for(var i = 0u; i < params.bits_per_axis; i++) {
+        for(var a = 0u; a < 3u; a++) {
+            let bit = (q[a] >> i) & 1u;
+            let pos = 3u * i + 2u - a;
+            if pos < 32u {
+                key.y |= bit << pos;
+            } else {
+                key.x |= bit << (pos - 32u);
+            }
+        }
+    }
+
+    keys[id.x] = key;
+    values[id.x] = id.x;
+}
@@ -0,0 +1,113 @@
+struct Star {
+    position: vec3<f32>,
+    mass: f32,
+    velocity: vec3<f32>,
+    bright: f32
+}
+
+struct TreeParams {
+    n: u32,
+    bits_per_axis: u32,
+    num_tiles: u32,
+    shift: u32
+}
+
+struct Node {
+    left: u32,
+    right: u32,
+    parent: u32,
+    first: u32,
+    last: u32
+}
+
+struct ReduceLevel {
+    level: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32
+}
+
+struct NodeData {
+    min: vec3<f32>,
+    mass: f32,
+    max: vec3<f32>,
+    com: vec3<f32>
+}
+
+@group(0) @binding(0)
+var<storage, read> stars: array<Star>;
+@group(0) @binding(1)
+var<storage, read> values: array<u32>;
+@group(0) @binding(2)
+var<storage, read> nodes: array<Node>;
+@group(0) @binding(3)
+var<storage, read> leaf_parents: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> node_data: array<NodeData>;
+@group(0) @binding(5)
+var<storage, read_write> leaf_data: array<NodeData>;
+// The level each internal node got merged at, 0 until then
+@group(0) @binding(6)
+var<storage, read_write> merged: array<atomic<u32>>;
+@group(0) @binding(7)
+var<uniform> params: TreeParams;
+@group(1) @binding(0)
+var<uniform> reduce: ReduceLevel;
+
+const LEAF_BIT: u32 = 0x80000000u;
+const NONE: u32 = 0xFFFFFFFFu;
+
+fn child(c: u32) -> NodeData {
+    if (c & LEAF_BIT) != 0u {
+        return leaf_data[c & ~LEAF_BIT];
+    }
+    return node_data[c];
+}
+
+// Lerp between the two COMs, m * x overflows f32 with our masses
+fn merge(a: NodeData, b: NodeData) -> NodeData {
+    let mass = a.mass + b.mass;
+    var t = 0.0;
+    if mass > 0.0 {
+        t = b.mass / mass;
+    }
+    return NodeData(min(a.min, b.min), mass, max(a.max, b.max), a.com + (b.com - a.com) * t);
+}
+
+@compute
+@workgroup_size(64, 1, 1)
+fn leaves(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n {
+        return;
+    }
+
+    let s = stars[values[id.x]];
+    leaf_data[id.x] = NodeData(s.position, s.mass, s.position, s.position);
+}
+
+// Leaves are written before the first level, internal nodes count once an earlier dispatch
+// merged them
+fn ready(c: u32) -> bool {
+    if (c & LEAF_BIT) != 0u {
+        return true;
+    }
+    let level = atomicLoad(&merged[c]);
+    return level != 0u && level < reduce.level;
+}
+
+// One dispatch per level, an invocation per internal node, merging the nodes whose children are
+// both ready. Plain stores made by other workgroups in the same dispatch aren't guaranteed to be
+// visible, so a child merged this dispatch makes its parent wait for the next one
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x + 1u >= params.n || atomicLoad(&merged[id.x]) != 0u {
+        return;
+    }
+
+    let node = nodes[id.x];
+    if ready(node.left) && ready(node.right) {
+        node_data[id.x] = merge(child(node.left), child(node.right));
+        atomicStore(&merged[id.x], reduce.level);
+    }
+}
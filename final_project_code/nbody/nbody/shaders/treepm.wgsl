@@ -0,0 +1,176 @@
+// Mirrors simulation::params::SimParams
+struct SimParams {
+    dt: f32,
+    G: f32,
+    softening: f32,
+    kernel: u32,
+    n_stars: u32,
+    box_size: f32,
+    hubble: f32,
+    omega_m: f32,
+    omega_lambda: f32,
+    drift: f32,
+    kick: vec2<f32>
+}
+
+// Mirrors simulation::treepm::ShortRangeParams
+struct ShortRangeParams {
+    theta: f32,
+    // r_s in mesh cells, and the cutoff in r_s
+    split: f32,
+    cutoff: f32,
+    _pad: u32
+}
+
+struct Node {
+    left: u32,
+    right: u32,
+    parent: u32,
+    first: u32,
+    last: u32
+}
+
+struct NodeData {
+    min: vec3<f32>,
+    mass: f32,
+    max: vec3<f32>,
+    com: vec3<f32>
+}
+
+// Written by `bounds` in pm.wgsl
+struct Geometry {
+    origin: vec3f,
+    h: f32,
+    mass_scale: f32
+}
+
+// Added to, the long-range PM force is already in
+@group(0) @binding(0)
+var<storage, read_write> accel: array<vec4<f32>>;
+// Star index of each sorted leaf
+@group(0) @binding(1)
+var<storage, read> order: array<u32>;
+@group(0) @binding(2)
+var<storage, read> nodes: array<Node>;
+@group(0) @binding(3)
+var<storage, read> leaf_parents: array<u32>;
+@group(0) @binding(4)
+var<storage, read> node_data: array<NodeData>;
+@group(0) @binding(5)
+var<storage, read> leaf_data: array<NodeData>;
+@group(0) @binding(6)
+var<storage, read> geometry: Geometry;
+@group(0) @binding(7)
+var<uniform> params: SimParams;
+@group(0) @binding(8)
+var<uniform> short_range: ShortRangeParams;
+
+const LEAF_BIT: u32 = 0x80000000u;
+const NONE: u32 = 0xFFFFFFFFu;
+const KERNEL_SPLINE: u32 = 1u;
+
+// Same as accel.wgsl
+fn softened_inv_r3(r2: f32) -> f32 {
+    let eps = params.softening;
+    if params.kernel == KERNEL_SPLINE {
+        let h = 2.8 * eps;
+        if r2 >= h * h {
+            return 1.0 / pow(r2, 1.5);
+        }
+
+        let u = sqrt(r2) / h;
+        let h3 = h * h * h;
+        if u < 0.5 {
+            return (10.666667 + u * u * (32.0 * u - 38.4)) / h3;
+        }
+        return (21.333333 - 48.0 * u + 38.4 * u * u - 10.666667 * u * u * u - 0.066666667 / (u * u * u)) / h3;
+    }
+
+    return 1.0 / pow(r2 + eps * eps, 1.5);
+}
+
+// Abramowitz and Stegun 7.1.26, within 1.5e-7 for x >= 0
+fn erfc(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    return poly * exp(-x * x);
+}
+
+// Mirrors simulation::treepm::short_range
+fn split_factor(r: f32, rs: f32) -> f32 {
+    let u = r / (2.0 * rs);
+    return erfc(u) + 1.1283792 * u * exp(-u * u);
+}
+
+fn data(c: u32) -> NodeData {
+    if (c & LEAF_BIT) != 0u {
+        return leaf_data[c & ~LEAF_BIT];
+    }
+    return node_data[c];
+}
+
+fn parent(c: u32) -> u32 {
+    if (c & LEAF_BIT) != 0u {
+        return leaf_parents[c & ~LEAF_BIT];
+    }
+    return nodes[c].parent;
+}
+
+// One invocation per sorted leaf, so neighbouring invocations walk much the same nodes.
+// Mirrors simulation::treepm::short_range_accelerations
+@compute
+@workgroup_size(64, 1, 1)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.n_stars || params.n_stars < 2u {
+        return;
+    }
+
+    let p = leaf_data[id.x].com;
+    let rs = short_range.split * geometry.h;
+    let cutoff = short_range.cutoff * rs;
+    let theta = short_range.theta;
+    var f = vec3f(0.0);
+
+    // Stackless, arriving from the parent means the node is new, from the left child that the
+    // right one is next, and from the right one that it's done
+    var node = 0u;
+    var prev = NONE;
+    loop {
+        if node == NONE {
+            break;
+        }
+
+        var next: u32;
+        if prev == parent(node) {
+            let d = data(node);
+            let gap = max(max(d.min - p, p - d.max), vec3f(0.0));
+            let v = d.com - p;
+            let r2 = dot(v, v);
+            let extent = d.max - d.min;
+            let size = max(extent.x, max(extent.y, extent.z));
+            let leaf = (node & LEAF_BIT) != 0u;
+
+            if node == (id.x | LEAF_BIT) || dot(gap, gap) > cutoff * cutoff {
+                next = parent(node);
+            } else if leaf || (any(gap > vec3f(0.0)) && size * size < theta * theta * r2) {
+                let r = sqrt(r2);
+                if r < cutoff {
+                    f += (params.G * d.mass * softened_inv_r3(r2) * split_factor(r, rs)) * v;
+                }
+                next = parent(node);
+            } else {
+                next = nodes[node].left;
+            }
+        } else if prev == nodes[node].left {
+            next = nodes[node].right;
+        } else {
+            next = parent(node);
+        }
+
+        prev = node;
+        node = next;
+    }
+
+    let i = order[id.x];
+    accel[i] += vec4f(f, 0.0);
+}
@@ -0,0 +1,45 @@
+use std::time::Instant;
+
+use crate::{
+    app::GpuContext,
+    headless::Simulation,
+    simulation::{checkpoint::Checkpoint, direct::relative_errors, Integrator, Solver},
+};
+
+// `nbody accuracy`, the forces a solver gives on a scenario's initial stars against the direct
+// sum's on the same star buffer. For picking theta, mesh and order
+
+// Where in the sorted per star errors each reported one is
+const QUANTILES: [(&str, f64); 4] = [("median", 0.5), ("90%", 0.9), ("99%", 0.99), ("max", 1.0)];
+
+pub fn accuracy(start: Checkpoint) {
+    let params = start.header.params;
+    let (direct, direct_time) = forces(&start, Solver::Direct);
+    let (tested, tested_time) = forces(&start, start.solver);
+
+    let errors = relative_errors(&tested, &direct);
+    println!("{:?} on {} stars, softening {}", start.solver, params.n_stars, params.softening);
+    println!("  {:.3}s, against {:.3}s for the direct sum", tested_time, direct_time);
+    if errors.is_empty() {
+        println!("  no forces to compare");
+        return;
+    }
+
+    let rms = (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+    let report: Vec<String> = QUANTILES
+        .iter()
+        .map(|(name, q)| format!("{} {:.2e}", name, errors[((errors.len() - 1) as f64 * q).round() as usize]))
+        .collect();
+    println!("  relative error {}  rms {:.2e}", report.join("  "), rms);
+}
+
+// Each solver gets its own device, like the runs in verify.rs. The time is the evaluation alone,
+// with the pipelines already built
+fn forces(start: &Checkpoint, solver: Solver) -> (Vec<[f32; 4]>, f64) {
+    let ctx = pollster::block_on(GpuContext::headless());
+    let sim = Simulation::new(ctx, start.stars.clone(), solver, Integrator::Leapfrog, start.header.params);
+
+    let timer = Instant::now();
+    let accel = sim.forces();
+    (accel, timer.elapsed().as_secs_f64())
+}
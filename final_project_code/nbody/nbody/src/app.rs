@@ -1,20 +1,63 @@
-use std::{iter, rc::Rc};
+use std::{iter, ops::Deref, path::Path, rc::Rc};
 
 use egui_winit_platform::{Platform, PlatformDescriptor};
 use glm::{Matrix4, ext::{perspective, translate, look_at_rh}, Vector3};
 use wgpu::{
-    util::{BufferInitDescriptor, DeviceExt},
-    Buffer, ComputePipeline, Texture, TextureDescriptor, TextureDimension, TextureView, TextureFormat,
+    ComputePipeline, Texture, TextureDescriptor, TextureDimension, TextureView, TextureFormat,
 };
 use winit::window::Window;
 
 use crate::{
-    pass::{ColorPass::ColorPass, PPFXPass::PPFXPass, RenderPass::{RenderPass, ComputePass}, BlitPass::BlitPass, IntegratePass::IntegratePass},
-    simulation::star::Star,
+    config::Config,
+    pass::{ColorPass::{ColorPass, Extent}, PPFXPass::PPFXPass, RenderPass::{RenderPass, ComputePass}, BlitPass::BlitPass, CollisionPass::CollisionPass, DiagnosticsPass::DiagnosticsPass, IntegratePass::IntegratePass, OctreePass::OctreePass, SnapshotPass::SnapshotPass},
+    pipelines::{read_buffer, PingPongBuffer},
+    simulation::{checkpoint::Checkpoint, diagnostics::DiagnosticsLog, lbvh::MortonBits, snapshot::{self, SnapshotFormat, SnapshotHeader}, params::SimParams, star::Star, units::{Units, PARSEC, SOLAR_MASS}, Integrator, Solver},
 };
 
-pub const N_PARTS: u32 = 96304;
-// pub const N_PARTS: u32 = 64;
+// Defaults for whatever a scenario (see config.rs) leaves out
+
+// Starting particle count, App::set_particle_count changes it at runtime
+pub const DEFAULT_PARTS: u32 = 96304;
+// pub const DEFAULT_PARTS: u32 = 64;
+
+// One of simulation::scenario::Scenario::NAMES
+pub const MODEL: &str = "plummer";
+// Same seed, same stars, on every platform
+pub const SEED: u64 = 0;
+
+// Models are generated in N-body units (G = M = a = 1), this is what those are physically
+pub const TOTAL_MASS: f64 = 1.0E5 * SOLAR_MASS;
+pub const SCALE_RADIUS: f64 = PARSEC;
+
+// In N-body units, a Plummer sphere's crossing time is about 3
+pub const DT: f32 = 5.0E-3;
+pub const SOFTENING: f32 = 0.02;
+
+// Rebuild the GPU tree over the stars every frame
+pub const OCTREE: Option<MortonBits> = None;
+
+// Merge stars that come closer than this, in N-body lengths
+pub const MERGE_RADIUS: Option<f32> = None;
+
+// Side of a periodic box around the origin, in N-body lengths, see simulation::ewald
+pub const BOX_SIZE: Option<f32> = None;
+
+// Write the star state to SNAPSHOT_DIR every this many steps
+pub const SNAPSHOT_EVERY: Option<u32> = None;
+pub const SNAPSHOT_DIR: &str = "snapshots";
+pub const SNAPSHOT_FORMAT: SnapshotFormat = SnapshotFormat::Native;
+
+// Measure energy, momentum and angular momentum every this many steps, logged to DIAGNOSTICS_PATH
+pub const DIAGNOSTICS_EVERY: Option<u32> = None;
+pub const DIAGNOSTICS_PATH: &str = "diagnostics.csv";
+
+// Where the state is saved every CHECKPOINT_EVERY steps and when the window closes,
+// `nbody --resume PATH` continues from it. Native only
+pub const CHECKPOINT_EVERY: Option<u32> = Some(10000);
+pub const CHECKPOINT_PATH: &str = "checkpoint.bin";
+
+// Where the E key writes the current state, the extension picks the format (see snapshot::export)
+pub const EXPORT_PATH: &str = "stars.csv";
 
 pub struct EguiRendCtx {
     pub platform: Platform,
@@ -24,16 +67,79 @@ pub struct EguiRendCtx {
 
 struct RenderPasses {
     color_pass: ColorPass,
-    ppfx_pass: PPFXPass,
+    ppfx_pass: Option<PPFXPass>,
     blit_pass: BlitPass,
-    integrate: IntegratePass
+    integrate: IntegratePass,
+    octree: Option<OctreePass>,
+    collisions: Option<CollisionPass>,
+    snapshot: Option<SnapshotPass>,
+    diagnostics: Option<DiagnosticsPass>
 }
 
-pub struct RenderContext {
+// Everything the simulation itself needs, without a window or surface
+pub struct GpuContext {
     pub device: wgpu::Device,
+    pub command_queue: wgpu::Queue,
+}
+
+impl GpuContext {
+    // Takes the first adapter with no surface to be compatible with,
+    // wgpu's software fallback if there's no GPU at all
+    pub async fn headless() -> Self {
+        let adapter = match Self::adapter(false).await {
+            Some(adapter) => adapter,
+            None => Self::adapter(true).await.expect("no wgpu adapter available, not even the fallback one"),
+        };
+
+        Self::from_adapter(&adapter).await
+    }
+
+    // Always wgpu's software fallback, even with a GPU there, for tests that have to run the same
+    // everywhere
+    #[cfg(test)]
+    pub async fn fallback() -> Self {
+        let adapter = Self::adapter(true).await.expect("no fallback wgpu adapter available");
+        Self::from_adapter(&adapter).await
+    }
+
+    async fn adapter(force_fallback_adapter: bool) -> Option<wgpu::Adapter> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            dx12_shader_compiler: Default::default(),
+        });
+
+        instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter,
+            })
+            .await
+    }
+
+    pub async fn from_adapter(adapter: &wgpu::Adapter) -> Self {
+        let (device, command_queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    features: wgpu::Features::empty(),
+                    // The integrator stages bind more storage buffers than the default limits
+                    // allow, so ask for whatever the adapter has
+                    limits: adapter.limits(),
+                    label: None,
+                },
+                None, // Trace path
+            )
+            .await
+            .unwrap();
+
+        Self { device, command_queue }
+    }
+}
+
+pub struct RenderContext {
+    pub gpu: GpuContext,
     pub surface: wgpu::Surface,
     pub surface_configuration: wgpu::SurfaceConfiguration,
-    pub command_queue: wgpu::Queue,
     pub window: Window,
     pub current_surface_texture: Option<TextureView>,
     pub color_target: Texture,
@@ -45,15 +151,31 @@ pub struct RenderContext {
     pub zoom: f32
 }
 
+// So render passes can keep using ctx.device and ctx.command_queue
+impl Deref for RenderContext {
+    type Target = GpuContext;
+
+    fn deref(&self) -> &GpuContext {
+        &self.gpu
+    }
+}
+
 pub struct Buffers {
-    pub star_buffer: Rc<Buffer>,
+    pub stars: Rc<PingPongBuffer>,
 }
 
 pub struct App {
     pub render_ctx: RenderContext,
     pub bufs: Buffers,
+    pub units: Units,
+    pub seed: u64,
+    pub n_stars: u32,
+    // Steps taken and simulated time (in `units`) since the stars were generated
+    pub step: u64,
+    pub time: f64,
     pub size: winit::dpi::PhysicalSize<u32>,
     pub egui_rp: EguiRendCtx,
+    pub config: Config,
     render_passes: RenderPasses,
 }
 
@@ -61,8 +183,67 @@ fn update_camera_matrix(aspect: f32) -> Matrix4<f32> {
     perspective(2.0*3.1415 / 5.0, aspect, 0.1, 100.0)
 }
 
+pub fn star_buffer(ctx: &GpuContext, stars: &[Star]) -> Rc<PingPongBuffer> {
+    Rc::new(PingPongBuffer::new(
+        &ctx.device,
+        "Star Buffer",
+        bytemuck::cast_slice(stars),
+        wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+    ))
+}
+
+fn load_stars(
+    ctx: &RenderContext,
+    config: &Config,
+    stars: &[Star],
+    units: &Units,
+    seed: u64,
+    step: u64,
+    solver: Solver,
+    integrator: Integrator,
+    params: SimParams,
+) -> (Buffers, RenderPasses) {
+    let bufs = Buffers { stars: star_buffer(ctx, stars) };
+    let integrate = IntegratePass::new(ctx, bufs.stars.clone(), solver, integrator, params);
+
+    let render_passes = RenderPasses {
+        color_pass: if params.is_periodic() {
+            ColorPass::new(ctx, bufs.stars.clone(), Extent::periodic(params.box_size)).outline(ctx, params.box_size)
+        } else {
+            ColorPass::new(ctx, bufs.stars.clone(), Extent::fit(stars))
+        },
+        ppfx_pass: config.post.bloom.then(|| PPFXPass::new(ctx)),
+        blit_pass: BlitPass::new(ctx),
+        octree: config.params.octree.map(|bits| OctreePass::new(ctx, bufs.stars.clone(), bits)),
+        collisions: config.params.merge_radius.map(|r| CollisionPass::new(ctx, bufs.stars.clone(), &integrate, r)),
+        snapshot: config.output.snapshot_every().map(|every| {
+            SnapshotPass::new(ctx, bufs.stars.clone(), every, config.output.snapshot_dir(), config.output.snapshot_format, *units, seed)
+        }),
+        diagnostics: config.output.diagnostics_every().map(|every| {
+            let pass = DiagnosticsPass::new(ctx, bufs.stars.clone(), integrate.ewald(), every);
+            // The web has nowhere to write the CSV to, the console still gets every measurement
+            if cfg!(target_arch = "wasm32") {
+                return pass;
+            }
+
+            let path = config.output.diagnostics_path();
+            match DiagnosticsLog::open(&path, step) {
+                Ok(log) => pass.log(log),
+                Err(e) => {
+                    eprintln!("failed to open {:?}: {}", path, e);
+                    pass
+                }
+            }
+        }),
+        integrate,
+    };
+
+    (bufs, render_passes)
+}
+
 impl App {
-    pub async fn new(window: Window) -> Self {
+    // Starts the configured scenario, or continues a checkpointed run
+    pub async fn new(window: Window, resume: Option<Checkpoint>, config: Config) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -87,24 +268,7 @@ impl App {
             .await
             .unwrap();
 
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    // WebGL doesn't support all of wgpu's features, so if
-                    // we're building for the web we'll have to disable some.
-                    // limits: if cfg!(target_arch = "wasm32") {
-                    //     wgpu::Limits::downlevel_webgl2_defaults()
-                    // } else {
-                    //     wgpu::Limits::default()
-                    // },
-                    limits: wgpu::Limits::default(),
-                    label: None,
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
+        let GpuContext { device, command_queue: queue } = GpuContext::from_adapter(&adapter).await;
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
@@ -116,16 +280,16 @@ impl App {
             .copied()
             .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
-        let config = wgpu::SurfaceConfiguration {
+        let surface_config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
             format: surface_format,
             width: size.width,
             height: size.height,
-            present_mode: surface_caps.present_modes[0],
+            present_mode: if config.window.vsync { wgpu::PresentMode::AutoVsync } else { wgpu::PresentMode::AutoNoVsync },
             alpha_mode: surface_caps.alpha_modes[0],
             view_formats: vec![],
         };
-        surface.configure(&device, &config);
+        surface.configure(&device, &surface_config);
 
         // Set up uniforms (resolution, framecount, etc)
 
@@ -195,11 +359,12 @@ impl App {
             view_formats: &[],
         });
 
+        let start = resume.unwrap_or_else(|| config.initial_run());
+
         let render_context = RenderContext {
-            device,
+            gpu: GpuContext { device, command_queue: queue },
             surface,
-            surface_configuration: config,
-            command_queue: queue,
+            surface_configuration: surface_config,
             window,
             current_surface_texture: None,
             color_target,
@@ -207,77 +372,122 @@ impl App {
             internal_target_size,
             camera_proj: update_camera_matrix(size.width as f32/size.height as f32),
             camera_view: look_at_rh(Vector3{ x: 10.0, y: 0.0, z: 0.0}, Vector3{ x: 0.0, y: 0.0, z: 0.0},  Vector3{ x: 0.0, y: 0.0, z: 1.0}),
-            frame_cnt: 0.0,
-            zoom: 5.0
+            frame_cnt: start.frame_cnt,
+            zoom: start.zoom
         };
 
-        let mut stars_temp: Vec<Star> = vec![];
-
-        for _ in 0..N_PARTS/2 {
-            let x = rand::random::<f32>();
-            let y = rand::random::<f32>();
-            let z = rand::random::<f32>();
-
-            stars_temp.push(
-                Star { 
-                    x: x * 9E8 - 10E8,
-                    z: z * 9E8, 
-                    y: y * 9E8, 
-                    x_vel: 0.0,
-                    y_vel: 0.0, 
-                    z_vel: 0.0, 
-                    mass: rand::random::<f32>() * 5E29, // 2E26 = 100 * mass of sun in millions of kg
-                    bright: 1.0
-                });
-        }
-
-        for _ in 0..N_PARTS/2 {
-            let x = rand::random::<f32>();
-            let y = rand::random::<f32>();
-            let z = rand::random::<f32>();
-
-            stars_temp.push(
-                Star { 
-                    x: x * 9E8 + 10E8,
-                    z: z * 9E8, 
-                    y: y * 9E8, 
-                    x_vel: 0.0,
-                    y_vel: 0.0, 
-                    z_vel: 0.0, 
-                    mass: rand::random::<f32>() * 5E29, // 2E26 = 100 * mass of sun in millions of kg
-                    bright: 0.5
-                });
-        }
-
-        let bufs =
-            Buffers {
-                star_buffer: Rc::new(render_context.device.create_buffer_init(
-                    &BufferInitDescriptor {
-                        label: Some("Star Buffer"),
-                        contents: bytemuck::cast_slice(stars_temp.as_slice()),
-                        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE
-                    },
-                )),
-            };
-
-        let render_passes = RenderPasses {
-            color_pass: ColorPass::new(&render_context, bufs.star_buffer.clone()),
-            ppfx_pass: PPFXPass::new(&render_context),
-            blit_pass: BlitPass::new(&render_context),
-            integrate: IntegratePass::new(&render_context, bufs.star_buffer.clone())
-        };
+        let (units, seed) = (start.header.units(), start.header.seed);
+        let (bufs, render_passes) =
+            load_stars(&render_context, &config, &start.stars, &units, seed, start.header.step, start.solver, start.integrator, start.header.params);
+        render_passes.integrate.restore(&render_context.gpu, &start.state);
 
         let compute_pipelines: Vec<ComputePipeline> = vec![];
 
         Self {
             render_ctx: render_context,
             bufs,
+            units,
+            seed,
+            n_stars: start.stars.len() as u32,
+            step: start.header.step,
+            time: start.header.time,
             size,
             egui_rp,
+            config,
             render_passes,
         }
     }
 
+    // Regenerates the scenario with `n` stars, every pass that holds the star buffer gets rebuilt
+    pub fn set_particle_count(&mut self, n: u32) {
+        let n = n.max(1);
+        let integrate = &self.render_passes.integrate;
+        let (solver, integrator, params) = (integrate.solver(), integrate.integrator(), integrate.params());
+        let stars = self.config.stars(&self.units, n, self.seed);
+        let (bufs, render_passes) =
+            load_stars(&self.render_ctx, &self.config, &stars, &self.units, self.seed, 0, solver, integrator, params);
+
+        self.bufs = bufs;
+        self.render_passes = render_passes;
+        // Comoving runs round n down to a cube
+        self.n_stars = stars.len() as u32;
+        self.step = 0;
+        self.time = self.config.start_time();
+    }
+
+    // Rebuilds the passes around a buffer of the first `live` stars, where CollisionPass
+    // compacted the survivors, like Simulation::shrink does
+    fn shrink(&mut self, live: u32) {
+        self.n_stars = live;
+        // The rebuild blocks on a readback, which the web can't do. There the dead stay in the
+        // buffer, massless and past the ones drawn
+        if cfg!(target_arch = "wasm32") {
+            return;
+        }
+
+        let mut start = self.checkpoint();
+        start.stars.truncate(live as usize);
+        start.state.truncate(live as usize);
+
+        let ctx = &self.render_ctx.gpu;
+        let stars = star_buffer(ctx, &start.stars);
+        let passes = &mut self.render_passes;
+        let integrate = IntegratePass::new(ctx, stars.clone(), start.solver, start.integrator, start.header.params);
+        integrate.restore(ctx, &start.state);
+
+        passes.color_pass.rebind(stars.clone());
+        if let Some(octree) = &mut passes.octree {
+            *octree = OctreePass::new(ctx, stars.clone(), octree.bits());
+        }
+        if let Some(collisions) = &mut passes.collisions {
+            *collisions = CollisionPass::new(ctx, stars.clone(), &integrate, collisions.merge_radius());
+        }
+        if let Some(snapshot) = &mut passes.snapshot {
+            snapshot.rebind(ctx, stars.clone());
+        }
+        if let Some(diagnostics) = &mut passes.diagnostics {
+            diagnostics.rebind(ctx, stars.clone());
+        }
+
+        passes.integrate = integrate;
+        self.bufs = Buffers { stars };
+    }
+
+    // Blocks until the GPU is idle and everything is read back
+    pub fn checkpoint(&self) -> Checkpoint {
+        let ctx = &self.render_ctx;
+        let integrate = &self.render_passes.integrate;
+
+        Checkpoint {
+            header: SnapshotHeader::new(self.step, self.time, &self.units, integrate.params(), self.seed),
+            stars: read_buffer(&ctx.device, &ctx.command_queue, self.bufs.stars.current()),
+            solver: integrate.solver(),
+            integrator: integrate.integrator(),
+            state: integrate.state(ctx),
+            frame_cnt: ctx.frame_cnt,
+            zoom: ctx.zoom,
+        }
+    }
+
+    // Writes the current state in the format the extension of `path` names, see snapshot::export
+    pub fn export(&self, path: &Path) {
+        let ctx = &self.render_ctx;
+        let header = SnapshotHeader::new(self.step, self.time, &self.units, self.render_passes.integrate.params(), self.seed);
+        let stars: Vec<Star> = read_buffer(&ctx.device, &ctx.command_queue, self.bufs.stars.current());
+        match snapshot::export(path, &header, &stars) {
+            Ok(()) => println!("{} stars at step {} written to {:?}", stars.len(), self.step, path),
+            Err(e) => eprintln!("failed to write {:?}: {}", path, e),
+        }
+    }
+
+    pub fn save_checkpoint(&self) {
+        let path = self.config.output.checkpoint_path();
+        match self.checkpoint().write(&path) {
+            Ok(()) => println!("checkpoint at step {} written to {:?}", self.step, path),
+            Err(e) => eprintln!("failed to write checkpoint {:?}: {}", path, e),
+        }
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
@@ -310,17 +520,34 @@ impl App {
                     label: Some("Render Encoder"),
                 });
 
+        let params = self.render_passes.integrate.params();
+        if let Some(snapshot) = &mut self.render_passes.snapshot {
+            snapshot.capture(&mut encoder, self.step, self.time, params);
+        }
+        if let Some(diagnostics) = &mut self.render_passes.diagnostics {
+            diagnostics.capture(&self.render_ctx.gpu, &mut encoder, self.step, self.time, params);
+        }
+
+        if let Some(octree) = &self.render_passes.octree {
+            octree.exec(&self.render_ctx.gpu, &mut encoder);
+        }
+
+        self.render_passes.integrate.set_time(self.time);
         self.render_passes
             .integrate
-            .exec(&self.render_ctx, &mut encoder);
+            .exec(&self.render_ctx.gpu, &mut encoder);
+
+        if let Some(collisions) = &self.render_passes.collisions {
+            collisions.exec(&self.render_ctx.gpu, &mut encoder);
+        }
 
         self.render_passes
             .color_pass
-            .draw(&self.render_ctx, &mut encoder, 0..N_PARTS, 0..1);
+            .draw(&self.render_ctx, &mut encoder, 0..self.n_stars, 0..1);
 
-        self.render_passes
-            .ppfx_pass
-            .exec(&self.render_ctx, &mut encoder);
+        if let Some(ppfx) = &self.render_passes.ppfx_pass {
+            ppfx.exec(&self.render_ctx, &mut encoder);
+        }
 
         self.render_passes
             .blit_pass
@@ -330,6 +557,32 @@ impl App {
             .command_queue
             .submit(iter::once(encoder.finish()));
 
+        self.step += 1;
+        self.time += params.dt as f64;
+        if let Some(snapshot) = &mut self.render_passes.snapshot {
+            snapshot.poll(&self.render_ctx.gpu);
+        }
+        if let Some(diagnostics) = &mut self.render_passes.diagnostics {
+            if diagnostics.poll(&self.render_ctx.gpu) > 0 {
+                let (first, last) = (diagnostics.history()[0], *diagnostics.history().last().unwrap());
+                console_log!(
+                    "step {}: E = {:.6e}, dE/E = {:.2e}, 2K/|W| = {:.3}",
+                    last.step,
+                    last.energy(),
+                    last.energy_drift(&first),
+                    last.virial_ratio()
+                );
+            }
+        }
+        if let Some(live) = self.render_passes.collisions.as_mut().and_then(|c| c.poll(&self.render_ctx.gpu)) {
+            console_log!("step {}: {} stars left after merging", self.step, live);
+            self.shrink(live);
+        }
+        let checkpoint_every = self.config.output.checkpoint_every();
+        if !cfg!(target_arch = "wasm32") && checkpoint_every.is_some_and(|every| self.step % every as u64 == 0) {
+            self.save_checkpoint();
+        }
+
         output.present();
 
         // self.egui_rp
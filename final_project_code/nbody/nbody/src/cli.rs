@@ -0,0 +1,253 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    config::Config,
+    simulation::{
+        checkpoint::Checkpoint,
+        gadget::Gadget,
+        reference::Reference,
+        text::{Column, TextFormat},
+        fmm,
+        pm,
+        units::{fit_nbody, Units},
+        Integrator, Solver,
+    },
+};
+
+// Flags override the scenario file, which overrides the defaults in config.rs
+#[derive(Parser, Debug)]
+#[command(name = "nbody", about = "GPU N-body simulation", args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub scenario: ScenarioArgs,
+    #[arg(long, value_parser = integrator, help = "euler, leapfrog, verlet, rk4 or hermite")]
+    pub integrator: Option<Integrator>,
+
+    #[arg(long, value_name = "STEPS", help = "Run STEPS steps without a window, then exit")]
+    pub headless: Option<u32>,
+    #[arg(short, long, value_name = "DIR", help = "Where snapshots and checkpoints go")]
+    pub output: Option<PathBuf>,
+    #[arg(long, value_name = "EVERY", help = "Log energy and momenta every EVERY steps")]
+    pub diagnostics: Option<u32>,
+    #[arg(long, value_name = "FILE", requires = "headless", help = "Write the final state of a headless run, the extension picks the format")]
+    pub export: Option<PathBuf>,
+
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = size, help = "Window size, maximized if left out")]
+    pub size: Option<(u32, u32)>,
+    #[arg(long, help = "Fill the screen")]
+    pub fullscreen: bool,
+    #[arg(long, value_name = "BOOL", help = "Wait for vertical sync, on by default")]
+    pub vsync: Option<bool>,
+
+    #[arg(long, value_name = "CHECKPOINT", group = "start", help = "Continue a checkpointed run")]
+    pub resume: Option<PathBuf>,
+    #[arg(long, value_name = "SNAPSHOT", group = "start", help = "Start from a GADGET snapshot instead of the model")]
+    pub gadget: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", group = "start", help = "Start from a CSV or whitespace separated text file")]
+    pub text: Option<PathBuf>,
+    #[arg(long, value_name = "x,y,z,...", requires = "text", value_delimiter = ',', value_parser = column, help = "Columns of the text file, from its header if left out")]
+    pub columns: Option<Vec<Column>>,
+    #[arg(long, requires = "text", value_parser = ["si", "astro"], help = "Units of the text file, N-body units if left out")]
+    pub units: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Check the integrators against problems with known answers, exits with 1 if any fail")]
+    Verify {
+        #[arg(long, value_parser = reference, help = "circular, eccentric, figure-eight or plummer, all of them if left out")]
+        problem: Option<Reference>,
+        #[arg(long, value_parser = integrator, help = "euler, leapfrog, verlet, rk4 or hermite, all of them if left out")]
+        integrator: Option<Integrator>,
+    },
+    #[command(about = "Compare a solver's forces with the direct sum's on a scenario's initial stars")]
+    Accuracy(AccuracyArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AccuracyArgs {
+    #[command(flatten)]
+    pub scenario: ScenarioArgs,
+}
+
+// The scenario and the model and solver flags, which the run and `nbody accuracy` share
+#[derive(clap::Args, Debug)]
+pub struct ScenarioArgs {
+    #[arg(help = "TOML scenario file, see scenarios/example.toml")]
+    pub scenario: Option<PathBuf>,
+    #[arg(short = 'n', long = "stars", value_name = "N", help = "Number of stars")]
+    pub n: Option<u32>,
+    #[arg(long, help = "Seed for the initial conditions")]
+    pub seed: Option<u64>,
+    #[arg(long, value_parser = solver, help = "direct, barnes-hut, pm, treepm or fmm")]
+    pub solver: Option<Solver>,
+    #[arg(long, help = "Opening angle for barnes-hut, treepm and fmm")]
+    pub theta: Option<f32>,
+    #[arg(long, value_parser = mesh, help = "PM cells per side for pm and treepm")]
+    pub mesh: Option<u32>,
+    #[arg(long, value_parser = order, help = "Expansion order for fmm")]
+    pub order: Option<u32>,
+}
+
+impl Args {
+    // The scenario file with the flags applied
+    pub fn config(&self) -> Config {
+        let mut config = self.scenario.config();
+        if let Some(integrator) = self.integrator {
+            config.params.integrator = integrator;
+        }
+        if let Some(dir) = &self.output {
+            config.output.dir = dir.clone();
+        }
+        if let Some(every) = self.diagnostics {
+            config.output.diagnostics_every = every;
+        }
+        if let Some((width, height)) = self.size {
+            config.window.width = Some(width);
+            config.window.height = Some(height);
+        }
+        config.window.fullscreen |= self.fullscreen;
+        if let Some(vsync) = self.vsync {
+            config.window.vsync = vsync;
+        }
+        config.validate().unwrap_or_else(|e| panic!("invalid scenario: {}", e))
+    }
+
+    // What --resume, --gadget or --text start from, None for the model
+    pub fn start(&self, config: &Config) -> Option<Checkpoint> {
+        if let Some(path) = &self.resume {
+            let checkpoint = Checkpoint::read(path).unwrap_or_else(|e| panic!("failed to read checkpoint {:?}: {}", path, e));
+            println!("Resuming from step {}.", checkpoint.header.step);
+            return Some(checkpoint);
+        }
+
+        if let Some(path) = &self.gadget {
+            let gadget = Gadget::read(path).unwrap_or_else(|e| panic!("failed to read GADGET snapshot {:?}: {}", path, e));
+            println!("Loaded {} particles from {:?}.", gadget.stars.len(), path);
+
+            return Some(config.start_from_gadget(&gadget).unwrap_or_else(|e| panic!("can't start from {:?}: {}", path, e)));
+        }
+
+        let path = self.text.as_ref()?;
+        let mut format = TextFormat::default();
+        if let Some(columns) = &self.columns {
+            format = format.columns(columns);
+        }
+
+        // Text files in physical units get N-body units fitted to them, like GADGET snapshots
+        let loaded = match self.units.as_deref() {
+            None => {
+                let units = config.units();
+                format.read_file(path, &units).map(|stars| (stars, units))
+            }
+            Some(name) => {
+                let file_units = if name == "si" { Units::SI } else { Units::ASTRO };
+                format.read_file(path, &file_units).map(|mut stars| {
+                    let units = fit_nbody(&stars, &file_units);
+                    file_units.convert_stars(&mut stars, &units);
+                    (stars, units)
+                })
+            }
+        };
+
+        let (stars, units) = loaded.unwrap_or_else(|e| panic!("failed to read {:?}: {}", path, e));
+        println!("Loaded {} stars from {:?}.", stars.len(), path);
+        Some(config.start_from(stars, units))
+    }
+}
+
+impl AccuracyArgs {
+    pub fn config(&self) -> Config {
+        self.scenario.config().validate().unwrap_or_else(|e| panic!("invalid scenario: {}", e))
+    }
+}
+
+impl ScenarioArgs {
+    // The scenario file with these flags applied, not validated yet as the caller may change more
+    pub fn config(&self) -> Config {
+        let mut config = match &self.scenario {
+            Some(path) => Config::read(path).unwrap_or_else(|e| panic!("failed to read scenario {:?}: {}", path, e)),
+            None => Config::default(),
+        };
+
+        if let Some(n) = self.n {
+            config.ics.n = n;
+        }
+        if let Some(seed) = self.seed {
+            config.ics.seed = seed;
+        }
+        if let Some(solver) = self.solver {
+            config.params.solver = solver;
+        }
+        config.params.theta = self.theta.or(config.params.theta);
+        config.params.mesh = self.mesh.or(config.params.mesh);
+        config.params.order = self.order.or(config.params.order);
+        config
+    }
+}
+
+fn integrator(name: &str) -> Result<Integrator, String> {
+    Integrator::from_name(name).ok_or_else(|| format!("expected one of {:?}", Integrator::NAMES))
+}
+
+fn reference(name: &str) -> Result<Reference, String> {
+    Reference::from_name(name).ok_or_else(|| format!("expected one of {:?}", Reference::NAMES))
+}
+
+fn solver(name: &str) -> Result<Solver, String> {
+    Solver::from_name(name).ok_or_else(|| format!("expected one of {:?}", Solver::NAMES))
+}
+
+fn mesh(s: &str) -> Result<u32, String> {
+    let mesh: u32 = s.parse().map_err(|_| "expected a number")?;
+    if mesh.is_power_of_two() && (pm::MIN_MESH..=pm::MAX_MESH).contains(&mesh) {
+        return Ok(mesh);
+    }
+    Err(format!("expected a power of two from {} to {}", pm::MIN_MESH, pm::MAX_MESH))
+}
+
+fn order(s: &str) -> Result<u32, String> {
+    let order: u32 = s.parse().map_err(|_| "expected a number")?;
+    if (fmm::MIN_ORDER..=fmm::MAX_ORDER).contains(&order) {
+        return Ok(order);
+    }
+    Err(format!("expected {} to {}", fmm::MIN_ORDER, fmm::MAX_ORDER))
+}
+
+fn size(s: &str) -> Result<(u32, u32), String> {
+    let (w, h) = s.split_once('x').ok_or("expected WIDTHxHEIGHT")?;
+    Ok((w.parse().map_err(|_| "bad width")?, h.parse().map_err(|_| "bad height")?))
+}
+
+fn column(name: &str) -> Result<Column, String> {
+    Column::from_name(name).ok_or_else(|| format!("unknown column {:?}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn run_and_accuracy_read_the_same_flags() {
+        Args::command().debug_assert();
+
+        let flags = ["-n", "1000", "--seed", "3", "--solver", "fmm", "--theta", "0.4", "--order", "5"];
+        let run = Args::try_parse_from(["nbody"].iter().chain(&flags)).unwrap().config();
+        let Some(Command::Accuracy(accuracy)) = Args::try_parse_from(["nbody", "accuracy"].iter().chain(&flags)).unwrap().command else {
+            panic!("not the accuracy command");
+        };
+        let accuracy = accuracy.config();
+
+        for config in [run, accuracy] {
+            assert_eq!((config.ics.n, config.ics.seed), (1000, 3));
+            assert_eq!(config.params.solver(), Solver::Fmm { order: 5, theta: 0.4 });
+        }
+    }
+}
@@ -0,0 +1,509 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::{
+    app::{
+        BOX_SIZE, CHECKPOINT_EVERY, CHECKPOINT_PATH, DEFAULT_PARTS, DIAGNOSTICS_EVERY, DIAGNOSTICS_PATH, DT, EXPORT_PATH, MERGE_RADIUS, MODEL, OCTREE, SCALE_RADIUS, SEED,
+        SNAPSHOT_DIR, SNAPSHOT_EVERY, SNAPSHOT_FORMAT, SOFTENING, TOTAL_MASS,
+    },
+    simulation::{
+        checkpoint::Checkpoint,
+        cosmology::{self, Cosmology},
+        fmm,
+        gadget::Gadget,
+        lbvh::MortonBits,
+        params::{SimParams, SofteningKernel},
+        pm,
+        scenario::Scenario,
+        snapshot::SnapshotFormat,
+        star::Star,
+        units::{Units, PARSEC, SOLAR_MASS},
+        zeldovich::{self, PowerSpectrum},
+        Integrator, Solver,
+    },
+};
+
+// A scenario, read from a TOML file natively and from the URL query on the web
+// (`?ics.n=20000&params.integrator=leapfrog`). Every table and key is optional, whatever is
+// left out keeps its default, from the Default impls below and the consts in app.rs. See
+// scenarios/example.toml.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ics: Ics,
+    pub params: Params,
+    pub camera: Camera,
+    pub post: Post,
+    pub output: Output,
+    pub window: WindowConfig,
+    // Makes the run comoving, see CosmologyConfig. A static background if left out
+    pub cosmology: Option<CosmologyConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ics {
+    // One of Scenario::NAMES
+    #[serde(deserialize_with = "model")]
+    pub model: String,
+    pub n: u32,
+    pub seed: u64,
+    // What N-body units are, in solar masses and parsecs
+    pub total_mass: f64,
+    pub scale_radius: f64,
+}
+
+impl Default for Ics {
+    fn default() -> Self {
+        Self {
+            model: MODEL.to_string(),
+            n: DEFAULT_PARTS,
+            seed: SEED,
+            total_mass: TOTAL_MASS / SOLAR_MASS,
+            scale_radius: SCALE_RADIUS / PARSEC,
+        }
+    }
+}
+
+// In N-body units
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    pub dt: f32,
+    pub softening: f32,
+    #[serde(deserialize_with = "kernel")]
+    pub kernel: SofteningKernel,
+    #[serde(deserialize_with = "solver")]
+    pub solver: Solver,
+    // Barnes-Hut, TreePM and FMM opening angle, 0.5 if left out
+    pub theta: Option<f32>,
+    // PM and TreePM cells per side, a power of two from 8 to 256. 64 if left out
+    #[serde(deserialize_with = "mesh")]
+    pub mesh: Option<u32>,
+    // FMM expansion order, 1 to 8. 3 (octupole) if left out
+    #[serde(deserialize_with = "order")]
+    pub order: Option<u32>,
+    #[serde(deserialize_with = "integrator")]
+    pub integrator: Integrator,
+    // Morton code bits for the GPU tree, 30 or 63. No tree if left out
+    #[serde(deserialize_with = "octree")]
+    pub octree: Option<MortonBits>,
+    // Stars closer than this merge, see CollisionPass. No merging if left out
+    pub merge_radius: Option<f32>,
+    // Side of a periodic box centered on the origin, direct solver only. Open if left out
+    #[serde(deserialize_with = "box_size")]
+    pub box_size: Option<f32>,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            dt: DT,
+            softening: SOFTENING,
+            kernel: SofteningKernel::Plummer,
+            solver: Solver::Direct,
+            theta: None,
+            mesh: None,
+            order: None,
+            integrator: Integrator::Euler,
+            octree: OCTREE,
+            merge_radius: MERGE_RADIUS,
+            box_size: BOX_SIZE,
+        }
+    }
+}
+
+impl Params {
+    pub fn solver(&self) -> Solver {
+        match self.solver {
+            Solver::BarnesHut { theta } => Solver::BarnesHut { theta: self.theta.unwrap_or(theta) },
+            Solver::ParticleMesh { mesh } => Solver::ParticleMesh { mesh: self.mesh.unwrap_or(mesh) },
+            Solver::TreePm { mesh, theta } => Solver::TreePm { mesh: self.mesh.unwrap_or(mesh), theta: self.theta.unwrap_or(theta) },
+            Solver::Fmm { order, theta } => Solver::Fmm { order: self.order.unwrap_or(order), theta: self.theta.unwrap_or(theta) },
+            solver => solver,
+        }
+    }
+}
+
+// A periodic box of matter in an expanding background, from Zel'dovich initial conditions at
+// z_start to today. Replaces the model and the N-body units: params.box_size is in Mpc/h and
+// params.dt in ln a, with the integrator the leapfrog. ics.n is rounded down to a cube
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CosmologyConfig {
+    #[serde(deserialize_with = "positive")]
+    pub omega_m: f64,
+    pub omega_lambda: f64,
+    // H0 in units of 100 km/s/Mpc
+    #[serde(deserialize_with = "positive")]
+    pub h: f64,
+    #[serde(deserialize_with = "positive")]
+    pub z_start: f64,
+    #[serde(deserialize_with = "positive")]
+    pub sigma8: f64,
+    pub spectral_index: f64,
+}
+
+impl Default for CosmologyConfig {
+    fn default() -> Self {
+        Self { omega_m: 0.3, omega_lambda: 0.7, h: 0.7, z_start: 50.0, sigma8: 0.8, spectral_index: 0.96 }
+    }
+}
+
+impl CosmologyConfig {
+    // In Units::cosmological, whose time unit is 1/H0
+    pub fn cosmology(&self) -> Cosmology {
+        Cosmology { omega_m: self.omega_m, omega_lambda: self.omega_lambda, hubble: 1.0 }
+    }
+
+    pub fn spectrum(&self) -> PowerSpectrum {
+        PowerSpectrum { spectral_index: self.spectral_index, sigma8: self.sigma8, shape: self.omega_m * self.h }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Camera {
+    // Distance from the origin
+    pub zoom: f32,
+    // The camera circles the origin once every 200 pi frames, this is where it starts
+    pub frame: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self { zoom: 5.0, frame: 0.0 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Post {
+    pub bloom: bool,
+}
+
+impl Default for Post {
+    fn default() -> Self {
+        Self { bloom: true }
+    }
+}
+
+// Paths are relative to `dir`. 0 turns snapshots, checkpoints or diagnostics off
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    pub dir: PathBuf,
+    pub snapshot_every: u32,
+    #[serde(deserialize_with = "snapshot_format")]
+    pub snapshot_format: SnapshotFormat,
+    pub snapshots: PathBuf,
+    pub checkpoint_every: u32,
+    pub checkpoint: PathBuf,
+    // Energy and momenta as a CSV time series, see DiagnosticsPass
+    pub diagnostics_every: u32,
+    pub diagnostics: PathBuf,
+    // Where the E key writes, the extension picks the format
+    pub export: PathBuf,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            snapshot_every: SNAPSHOT_EVERY.unwrap_or(0),
+            snapshot_format: SNAPSHOT_FORMAT,
+            snapshots: SNAPSHOT_DIR.into(),
+            checkpoint_every: CHECKPOINT_EVERY.unwrap_or(0),
+            checkpoint: CHECKPOINT_PATH.into(),
+            diagnostics_every: DIAGNOSTICS_EVERY.unwrap_or(0),
+            diagnostics: DIAGNOSTICS_PATH.into(),
+            export: EXPORT_PATH.into(),
+        }
+    }
+}
+
+impl Output {
+    pub fn snapshot_every(&self) -> Option<u32> {
+        (self.snapshot_every > 0).then_some(self.snapshot_every)
+    }
+
+    pub fn checkpoint_every(&self) -> Option<u32> {
+        (self.checkpoint_every > 0).then_some(self.checkpoint_every)
+    }
+
+    pub fn diagnostics_every(&self) -> Option<u32> {
+        (self.diagnostics_every > 0).then_some(self.diagnostics_every)
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.dir.join(&self.snapshots)
+    }
+
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.dir.join(&self.checkpoint)
+    }
+
+    pub fn diagnostics_path(&self) -> PathBuf {
+        self.dir.join(&self.diagnostics)
+    }
+
+    pub fn export_path(&self) -> PathBuf {
+        self.dir.join(&self.export)
+    }
+}
+
+// Native only, the web canvas fills the page
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    // Maximized if either is left out
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fullscreen: bool,
+    pub vsync: bool,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self { width: None, height: None, fullscreen: false, vsync: true }
+    }
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str::<Self>(text).map_err(|e| e.to_string())?.validate()
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, String> {
+        Self::from_toml(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+    }
+
+    // `a.b=value` pairs, already percent-decoded. Values are read as TOML where they parse
+    // (numbers, booleans) and taken as strings otherwise, so quotes are never needed
+    pub fn from_query<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self, String> {
+        let mut root = toml::Table::new();
+        for (key, raw) in pairs {
+            let value = match toml::from_str::<toml::Table>(&format!("v = {}", raw)) {
+                Ok(mut t) if t.len() == 1 => t.remove("v").unwrap(),
+                _ => toml::Value::String(raw.to_string()),
+            };
+
+            let mut path: Vec<&str> = key.split('.').collect();
+            let last = path.pop().unwrap();
+            let mut table = &mut root;
+            for part in path {
+                table = table
+                    .entry(part)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .ok_or_else(|| format!("{} is a value, not a table", part))?;
+            }
+            table.insert(last.to_string(), value);
+        }
+
+        toml::Value::Table(root).try_into::<Self>().map_err(|e| e.to_string())?.validate()
+    }
+
+    // What the fields can't check one at a time, the combinations IntegratePass would refuse.
+    // Run again after anything that changes the config, like the command line flags
+    pub fn validate(self) -> Result<Self, String> {
+        let params = &self.params;
+        if params.box_size.is_some() && (params.solver() != Solver::Direct || params.integrator == Integrator::Hermite) {
+            return Err("periodic boxes need the direct solver, and an integrator other than Hermite, whose jerk has no Ewald term".to_string());
+        }
+        if self.cosmology.is_some() {
+            if params.box_size.is_none() {
+                return Err("a cosmology needs params.box_size, the side of the periodic box in Mpc/h".to_string());
+            }
+            if params.integrator != Integrator::Leapfrog {
+                return Err("comoving runs need the leapfrog, the one integrator with comoving kicks and drifts".to_string());
+            }
+            if self.ics.n < 8 {
+                return Err(format!("a cosmology needs at least 8 stars for a 2^3 lattice, got {}", self.ics.n));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn units(&self) -> Units {
+        match &self.cosmology {
+            Some(c) => Units::cosmological(c.h),
+            None => Units::nbody(self.ics.total_mass * SOLAR_MASS, self.ics.scale_radius * PARSEC),
+        }
+    }
+
+    // The model with `n` stars, in `units`. Comoving runs get the largest power-of-two cube of
+    // at most `n`, validate makes sure there's at least 2^3 and a box
+    pub fn stars(&self, units: &Units, n: u32, seed: u64) -> Vec<Star> {
+        if let Some(c) = &self.cosmology {
+            let box_size = self.params.box_size.unwrap_or_default();
+            let mut side = 2;
+            while (side * 2usize).pow(3) <= n as usize {
+                side *= 2;
+            }
+            let a = cosmology::scale_factor(self.start_time());
+            return zeldovich::generate(&c.cosmology(), &c.spectrum(), side, box_size as f64, a, seed, units.g());
+        }
+
+        Scenario::from_name(&self.ics.model, n, seed, units.g())
+            .unwrap_or_else(|| panic!("unknown model {:?}, expected one of {:?}", self.ics.model, Scenario::NAMES))
+            .build()
+    }
+
+    pub fn sim_params(&self, units: &Units, n: u32) -> SimParams {
+        let params = units
+            .params(n)
+            .dt(self.params.dt)
+            .softening(self.params.softening, self.params.kernel)
+            .periodic(self.params.box_size.unwrap_or(0.0));
+        match &self.cosmology {
+            Some(c) => params.comoving(&c.cosmology()),
+            None => params,
+        }
+    }
+
+    // ln a at z_start for comoving runs, whose clock is the scale factor. 0 otherwise
+    pub fn start_time(&self) -> f64 {
+        self.cosmology.as_ref().map_or(0.0, |c| cosmology::time_at(c.z_start))
+    }
+
+    // A fresh run of the model, what amounts to a checkpoint at step 0
+    pub fn initial_run(&self) -> Checkpoint {
+        let units = self.units();
+        self.start_from(self.stars(&units, self.ics.n, self.ics.seed), units)
+    }
+
+    // A fresh run of `stars`, which come from somewhere other than the model
+    pub fn start_from(&self, stars: Vec<Star>, units: Units) -> Checkpoint {
+        let params = self.sim_params(&units, stars.len() as u32);
+        Checkpoint {
+            frame_cnt: self.camera.frame,
+            zoom: self.camera.zoom,
+            ..Checkpoint::initial(stars, &units, self.start_time(), self.ics.seed, self.params.solver(), self.params.integrator, params)
+        }
+    }
+
+    // A fresh run of a GADGET snapshot. A comoving one brings its cosmology, box and scale factor,
+    // which replace the scenario's, and keeps its units. Others get N-body units fitted to them
+    pub fn start_from_gadget(&self, gadget: &Gadget) -> Result<Checkpoint, String> {
+        if !gadget.is_comoving() {
+            let units = gadget.nbody_units();
+            return Ok(self.start_from(gadget.stars_by_id(&units), units));
+        }
+
+        let header = &gadget.header;
+        let mut config = self.clone();
+        config.cosmology = Some(CosmologyConfig {
+            omega_m: header.omega0,
+            omega_lambda: header.omega_lambda,
+            h: if header.hubble_param > 0.0 { header.hubble_param } else { 1.0 },
+            z_start: 1.0 / header.time - 1.0,
+            ..self.cosmology.clone().unwrap_or_default()
+        });
+        let units = config.units();
+        config.params.box_size = Some((header.box_size * header.units().length_to(&units)) as f32);
+        config.ics.n = gadget.stars.len() as u32;
+
+        let config = config.validate()?;
+        Ok(config.start_from(gadget.stars_by_id(&units), units))
+    }
+}
+
+// Enums are written by name, mistakes list the names there are
+fn by_name<'de, D: Deserializer<'de>, T>(d: D, from_name: impl Fn(&str) -> Option<T>, names: &[&str]) -> Result<T, D::Error> {
+    let name = String::deserialize(d)?;
+    from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown name {:?}, expected one of {:?}", name, names)))
+}
+
+fn model<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    by_name(d, |n| Scenario::NAMES.contains(&n).then(|| n.to_string()), &Scenario::NAMES)
+}
+
+fn kernel<'de, D: Deserializer<'de>>(d: D) -> Result<SofteningKernel, D::Error> {
+    by_name(d, SofteningKernel::from_name, &SofteningKernel::NAMES)
+}
+
+fn solver<'de, D: Deserializer<'de>>(d: D) -> Result<Solver, D::Error> {
+    by_name(d, Solver::from_name, &Solver::NAMES)
+}
+
+fn mesh<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    let mesh = u32::deserialize(d)?;
+    if mesh.is_power_of_two() && (pm::MIN_MESH..=pm::MAX_MESH).contains(&mesh) {
+        return Ok(Some(mesh));
+    }
+    Err(D::Error::custom(format!("mesh takes a power of two from {} to {}, not {}", pm::MIN_MESH, pm::MAX_MESH, mesh)))
+}
+
+fn order<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    let order = u32::deserialize(d)?;
+    if (fmm::MIN_ORDER..=fmm::MAX_ORDER).contains(&order) {
+        return Ok(Some(order));
+    }
+    Err(D::Error::custom(format!("order takes {} to {}, not {}", fmm::MIN_ORDER, fmm::MAX_ORDER, order)))
+}
+
+fn box_size<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f32>, D::Error> {
+    let size = f32::deserialize(d)?;
+    if size > 0.0 {
+        return Ok(Some(size));
+    }
+    Err(D::Error::custom(format!("box_size has to be positive, not {}", size)))
+}
+
+fn positive<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    let value = f64::deserialize(d)?;
+    if value > 0.0 {
+        return Ok(value);
+    }
+    Err(D::Error::custom(format!("has to be positive, not {}", value)))
+}
+
+fn integrator<'de, D: Deserializer<'de>>(d: D) -> Result<Integrator, D::Error> {
+    by_name(d, Integrator::from_name, &Integrator::NAMES)
+}
+
+fn snapshot_format<'de, D: Deserializer<'de>>(d: D) -> Result<SnapshotFormat, D::Error> {
+    by_name(d, SnapshotFormat::from_name, &SnapshotFormat::NAMES)
+}
+
+fn octree<'de, D: Deserializer<'de>>(d: D) -> Result<Option<MortonBits>, D::Error> {
+    let bits = u32::deserialize(d)?;
+    MortonBits::from_bits(bits)
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("octree takes 30 or 63 bits, not {}", bits)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        Config::from_toml(text).expect_err(text)
+    }
+
+    #[test]
+    fn bad_scenarios_are_errors() {
+        assert!(error("[params]\nmesh = 100").contains("power of two"));
+        assert!(error("[params]\noctree = 31").contains("30 or 63"));
+        assert!(error("[params]\nbox_size = -1.0").contains("positive"));
+        assert!(error("[ics]\nmodel = \"nope\"").contains("unknown name"));
+        assert!(error("[ics]\nstars = 10").contains("unknown field"));
+
+        assert!(error("[params]\nbox_size = 10.0\nsolver = \"pm\"").contains("periodic boxes need the direct solver"));
+        assert!(error("[params]\nbox_size = 10.0\nintegrator = \"hermite\"").contains("periodic boxes need the direct solver"));
+        assert!(error("[params]\nintegrator = \"leapfrog\"\n[cosmology]").contains("needs params.box_size"));
+        assert!(error("[params]\nbox_size = 10.0\nintegrator = \"rk4\"\n[cosmology]").contains("need the leapfrog"));
+        assert!(error("[ics]\nn = 7\n[params]\nbox_size = 10.0\nintegrator = \"leapfrog\"\n[cosmology]").contains("at least 8 stars"));
+
+        let query = Config::from_query([("params.integrator", "euler"), ("params.box_size", "10"), ("cosmology.h", "0.7")]);
+        assert!(query.expect_err("query").contains("need the leapfrog"));
+
+        let ok = Config::from_toml("[ics]\nn = 8\n[params]\nbox_size = 10.0\nintegrator = \"leapfrog\"\n[cosmology]").unwrap();
+        assert_eq!(ok.initial_run().stars.len(), 8);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{io, iter, path::{Path, PathBuf}, rc::Rc};
+
+use crate::{
+    app::{star_buffer, GpuContext},
+    pass::{
+        CollisionPass::CollisionPass, DiagnosticsPass::DiagnosticsPass, IntegratePass::IntegratePass, OctreePass::OctreePass, RenderPass::ComputePass,
+        SnapshotPass::SnapshotPass,
+    },
+    pipelines::{read_buffer, PingPongBuffer},
+    simulation::{
+        checkpoint::Checkpoint, diagnostics::{Diagnostics, DiagnosticsLog}, lbvh::MortonBits, params::SimParams, snapshot::{self, SnapshotFormat, SnapshotHeader}, star::Star, units::Units,
+        Integrator, Solver,
+    },
+};
+
+// The compute half of App, with no window, surface or render passes.
+// Owns its device, so it runs anywhere wgpu finds an adapter, software ones included.
+pub struct Simulation {
+    pub ctx: GpuContext,
+    stars: Rc<PingPongBuffer>,
+    integrate: IntegratePass,
+    octree: Option<OctreePass>,
+    collisions: Option<CollisionPass>,
+    snapshot: Option<SnapshotPass>,
+    diagnostics: Option<DiagnosticsPass>,
+    // Every so many steps, and where to
+    checkpoints: Option<(u64, PathBuf)>,
+    units: Units,
+    seed: u64,
+    // The window's camera, only kept so checkpoints can be resumed in one
+    camera: (f32, f32),
+    n_stars: u32,
+    steps: u64,
+    time: f64,
+}
+
+impl Simulation {
+    pub fn new(ctx: GpuContext, stars: Vec<Star>, solver: Solver, integrator: Integrator, params: SimParams) -> Self {
+        Self::resume(ctx, Checkpoint::initial(stars, &Units::SI, 0.0, 0, solver, integrator, params))
+    }
+
+    // Continues a run exactly where `start` left it
+    pub fn resume(ctx: GpuContext, start: Checkpoint) -> Self {
+        let bufs = star_buffer(&ctx, &start.stars);
+        let integrate = IntegratePass::new(&ctx, bufs.clone(), start.solver, start.integrator, start.header.params);
+        integrate.restore(&ctx, &start.state);
+
+        Self {
+            ctx,
+            stars: bufs,
+            integrate,
+            octree: None,
+            collisions: None,
+            snapshot: None,
+            diagnostics: None,
+            checkpoints: None,
+            units: start.header.units(),
+            seed: start.header.seed,
+            camera: (start.frame_cnt, start.zoom),
+            n_stars: start.stars.len() as u32,
+            steps: start.header.step,
+            time: start.header.time,
+        }
+    }
+
+    // What the stars and params are in, and which seed generated them, for snapshot headers.
+    // Set before snapshots()
+    pub fn provenance(mut self, units: Units, seed: u64) -> Self {
+        self.units = units;
+        self.seed = seed;
+        self
+    }
+
+    // Also rebuild the GPU tree before every step, as App does with OCTREE set
+    pub fn octree(mut self, bits: MortonBits) -> Self {
+        self.octree = Some(OctreePass::new(&self.ctx, self.stars.clone(), bits));
+        self
+    }
+
+    // Merge stars that come closer than `merge_radius`, see CollisionPass
+    pub fn collisions(mut self, merge_radius: f32) -> Self {
+        self.collisions = Some(CollisionPass::new(&self.ctx, self.stars.clone(), &self.integrate, merge_radius));
+        self
+    }
+
+    // Write the state to `dir` every `every` steps, see SnapshotPass
+    pub fn snapshots(mut self, every: u32, dir: impl Into<PathBuf>, format: SnapshotFormat) -> Self {
+        self.snapshot = Some(SnapshotPass::new(&self.ctx, self.stars.clone(), every, dir, format, self.units, self.seed));
+        self
+    }
+
+    // Measure the energy and momenta every `every` steps, see DiagnosticsPass. With a `log`
+    // path they also go to a CSV file, continuing one a resumed run left there
+    pub fn diagnostics(mut self, every: u32, log: Option<PathBuf>) -> Self {
+        let mut pass = DiagnosticsPass::new(&self.ctx, self.stars.clone(), self.integrate.ewald(), every);
+        if let Some(path) = log {
+            match DiagnosticsLog::open(&path, self.steps) {
+                Ok(log) => pass = pass.log(log),
+                Err(e) => eprintln!("failed to open {:?}: {}", path, e),
+            }
+        }
+        self.diagnostics = Some(pass);
+        self
+    }
+
+    // Overwrite a checkpoint at `path` every `every` steps
+    pub fn checkpoints(mut self, every: u32, path: impl Into<PathBuf>) -> Self {
+        self.checkpoints = Some((every.max(1) as u64, path.into()));
+        self
+    }
+
+    pub fn units(&self) -> Units {
+        self.units
+    }
+
+    pub fn params(&self) -> SimParams {
+        self.integrate.params()
+    }
+
+    pub fn set_params(&self, params: SimParams) {
+        self.integrate.set_params(params);
+    }
+
+    pub fn n_stars(&self) -> u32 {
+        self.n_stars
+    }
+
+    // Steps taken since the start
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // In the units of the params, ln a for comoving runs
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    // Every measurement so far, empty without diagnostics()
+    pub fn history(&self) -> &[Diagnostics] {
+        self.diagnostics.as_ref().map_or(&[], |d| d.history())
+    }
+
+    // Measures the current state, whether or not diagnostics() is on. Blocks until the GPU is idle
+    pub fn measure(&self) -> Diagnostics {
+        let measure = |pass: &DiagnosticsPass| pass.measure(&self.ctx, self.steps, self.time, self.params());
+        match &self.diagnostics {
+            Some(pass) => measure(pass),
+            None => measure(&DiagnosticsPass::new(&self.ctx, self.stars.clone(), self.integrate.ewald(), 1)),
+        }
+    }
+
+    // Submits one command buffer per step and waits for the last one, and any snapshots,
+    // diagnostics and merges, to finish
+    pub fn step(&mut self, n: u32) {
+        for _ in 0..n {
+            let mut encoder = self.ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Simulation Encoder"),
+            });
+
+            let params = self.params();
+            if let Some(snapshot) = &mut self.snapshot {
+                snapshot.reserve(&self.ctx);
+                snapshot.capture(&mut encoder, self.steps, self.time, params);
+            }
+            if let Some(diagnostics) = &mut self.diagnostics {
+                diagnostics.reserve(&self.ctx);
+                diagnostics.capture(&self.ctx, &mut encoder, self.steps, self.time, params);
+            }
+
+            if let Some(octree) = &self.octree {
+                octree.exec(&self.ctx, &mut encoder);
+            }
+            self.integrate.set_time(self.time);
+            self.integrate.exec(&self.ctx, &mut encoder);
+            if let Some(collisions) = &self.collisions {
+                collisions.exec(&self.ctx, &mut encoder);
+            }
+
+            self.ctx.command_queue.submit(iter::once(encoder.finish()));
+            self.steps += 1;
+            self.time += params.dt as f64;
+
+            if let Some(snapshot) = &mut self.snapshot {
+                snapshot.poll(&self.ctx);
+            }
+            if let Some(diagnostics) = &mut self.diagnostics {
+                diagnostics.poll(&self.ctx);
+            }
+            if let Some(live) = self.collisions.as_mut().and_then(|c| c.poll(&self.ctx)) {
+                self.shrink(live);
+            }
+            if self.checkpoints.as_ref().is_some_and(|(every, _)| self.steps % every == 0) {
+                self.save_checkpoint();
+            }
+        }
+
+        self.ctx.device.poll(wgpu::Maintain::Wait);
+        if let Some(snapshot) = &mut self.snapshot {
+            snapshot.flush(&self.ctx);
+        }
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.flush(&self.ctx);
+        }
+        if let Some(live) = self.collisions.as_mut().and_then(|c| c.flush(&self.ctx)) {
+            self.shrink(live);
+        }
+    }
+
+    // Rebuilds the passes around a buffer of the first `live` stars, where CollisionPass
+    // compacted the survivors. Goes through a checkpoint so the integrator keeps its forces
+    fn shrink(&mut self, live: u32) {
+        let mut start = self.checkpoint();
+        start.stars.truncate(live as usize);
+        start.state.truncate(live as usize);
+
+        let bufs = star_buffer(&self.ctx, &start.stars);
+        let integrate = IntegratePass::new(&self.ctx, bufs.clone(), start.solver, start.integrator, start.header.params);
+        integrate.restore(&self.ctx, &start.state);
+
+        if let Some(octree) = &mut self.octree {
+            *octree = OctreePass::new(&self.ctx, bufs.clone(), octree.bits());
+        }
+        if let Some(collisions) = &mut self.collisions {
+            *collisions = CollisionPass::new(&self.ctx, bufs.clone(), &integrate, collisions.merge_radius());
+        }
+        if let Some(snapshot) = &mut self.snapshot {
+            snapshot.rebind(&self.ctx, bufs.clone());
+        }
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.rebind(&self.ctx, bufs.clone());
+        }
+
+        self.stars = bufs;
+        self.integrate = integrate;
+        self.n_stars = live;
+    }
+
+    // Blocks until the GPU is idle and everything is read back
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            header: SnapshotHeader::new(self.steps, self.time, &self.units, self.params(), self.seed),
+            stars: self.stars(),
+            solver: self.integrate.solver(),
+            integrator: self.integrate.integrator(),
+            state: self.integrate.state(&self.ctx),
+            frame_cnt: self.camera.0,
+            zoom: self.camera.1,
+        }
+    }
+
+    // Writes to the path given to checkpoints(), if any
+    pub fn save_checkpoint(&self) {
+        let Some((_, path)) = &self.checkpoints else { return };
+        if let Err(e) = self.checkpoint().write(path) {
+            eprintln!("failed to write checkpoint {:?}: {}", path, e);
+        }
+    }
+
+    // In the format the extension of `path` names, see snapshot::export
+    pub fn export(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let header = SnapshotHeader::new(self.steps, self.time, &self.units, self.params(), self.seed);
+        snapshot::export(path, &header, &self.stars())
+    }
+
+    // Copies the current state back to the CPU
+    pub fn stars(&self) -> Vec<Star> {
+        read_buffer(&self.ctx.device, &self.ctx.command_queue, self.stars.current())
+    }
+
+    // The solver's accelerations on the current state, in the same order as stars()
+    pub fn forces(&self) -> Vec<[f32; 4]> {
+        self.integrate.forces(&self.ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{ics::{IcParams, Model}, params::SofteningKernel};
+
+    #[test]
+    fn steps_on_the_fallback_adapter() {
+        let ctx = pollster::block_on(GpuContext::fallback());
+        let stars = Model::Plummer.generate(&IcParams { n: 256, mass: 1.0, scale_radius: 1.0, seed: 1 }, 1.0);
+        let params = SimParams::new(256).gravity(1.0).dt(1.0E-2).softening(0.05, SofteningKernel::Plummer);
+
+        let mut sim = Simulation::new(ctx, stars, Solver::Direct, Integrator::Leapfrog, params).diagnostics(5, None);
+        sim.step(20);
+
+        assert_eq!(sim.steps(), 20);
+        assert_eq!(sim.n_stars(), 256);
+        assert_eq!(sim.history().len(), 4);
+        let stars = sim.stars();
+        assert_eq!(stars.len(), 256);
+        assert!(stars.iter().all(|s| [s.x, s.y, s.z, s.x_vel, s.y_vel, s.z_vel].iter().all(|v| v.is_finite())));
+    }
+}
@@ -1,11 +1,30 @@
+// Declared before the modules so they can log to the browser console too
+#[cfg(target_arch = "wasm32")]
+macro_rules! console_log {
+    ($($t:tt)*) => (web_sys::console::log_1(&format_args!($($t)*).to_string().into()))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+macro_rules! console_log {
+    ($($t:tt)*) => (println!($($t)*))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod accuracy;
 mod app;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+mod config;
+mod headless;
 mod pass;
 mod pipelines;
 mod simulation;
+#[cfg(not(target_arch = "wasm32"))]
+mod verify;
 #[cfg(target_arch = "wasm32")]
 mod wasm;
 
-use std::panic;
+use std::{panic, path::Path};
 
 use winit::{
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -13,35 +32,129 @@ use winit::{
     window::WindowBuilder,
 };
 
-use crate::app::App;
-
-#[cfg(target_arch = "wasm32")]
-macro_rules! console_log {
-    ($($t:tt)*) => (web_sys::console::log_1(&format_args!($($t)*).to_string().into()))
-}
-
-#[cfg(not(target_arch = "wasm32"))]
-macro_rules! console_log {
-    ($($t:tt)*) => (println!($($t)*))
-}
+use crate::{app::App, config::Config, simulation::checkpoint::Checkpoint};
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
+    use clap::Parser;
+
     panic::set_hook(Box::new(console_error_panic_hook::hook));
 
+    // See cli.rs for the flags and config.rs for the scenario files
+    let args = cli::Args::parse();
+    if let Some(cli::Command::Verify { problem, integrator }) = args.command {
+        use crate::simulation::{reference::Reference, Integrator};
+
+        let problems = problem.map_or(Reference::ALL.to_vec(), |p| vec![p]);
+        let integrators = integrator.map_or_else(
+            || Integrator::NAMES.iter().filter_map(|n| Integrator::from_name(n)).collect(),
+            |i| vec![i],
+        );
+        std::process::exit(if verify::verify(&problems, &integrators) { 0 } else { 1 });
+    }
+    if let Some(cli::Command::Accuracy(accuracy)) = &args.command {
+        accuracy::accuracy(accuracy.config().initial_run());
+        return;
+    }
+    let config = args.config();
+    if let Err(e) = std::fs::create_dir_all(&config.output.dir) {
+        panic!("failed to create output directory {:?}: {}", config.output.dir, e);
+    }
+    let from = args.start(&config);
+
     console_log!("Starting simulation.");
 
+    if let Some(steps) = args.headless {
+        run_headless(&config, steps, from, args.export.as_deref());
+        return;
+    }
+
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new()
-        .with_maximized(true)
-        .build(&event_loop)
-        .expect("failed to create window!");
+    let mut window = WindowBuilder::new().with_maximized(true);
+    if let (Some(width), Some(height)) = (config.window.width, config.window.height) {
+        window = window.with_maximized(false).with_inner_size(winit::dpi::PhysicalSize::new(width, height));
+    }
+    if config.window.fullscreen {
+        window = window.with_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
+    }
+    let window = window.build(&event_loop).expect("failed to create window!");
 
-    let app = pollster::block_on(App::new(window));
+    let app = pollster::block_on(App::new(window, from, config));
 
     start(event_loop, app);
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+fn run_headless(config: &Config, steps: u32, start: Option<Checkpoint>, export: Option<&Path>) {
+    use crate::headless::Simulation;
+
+    let ctx = pollster::block_on(app::GpuContext::headless());
+    let start = start.unwrap_or_else(|| config.initial_run());
+
+    let mut sim = Simulation::resume(ctx, start);
+    if let Some(bits) = config.params.octree {
+        sim = sim.octree(bits);
+    }
+    if let Some(merge_radius) = config.params.merge_radius {
+        sim = sim.collisions(merge_radius);
+    }
+    if let Some(every) = config.output.snapshot_every() {
+        sim = sim.snapshots(every, config.output.snapshot_dir(), config.output.snapshot_format);
+    }
+    if let Some(every) = config.output.checkpoint_every() {
+        sim = sim.checkpoints(every, config.output.checkpoint_path());
+    }
+    if let Some(every) = config.output.diagnostics_every() {
+        sim = sim.diagnostics(every, Some(config.output.diagnostics_path()));
+    }
+
+    let timer = std::time::Instant::now();
+    sim.step(steps);
+    let elapsed = timer.elapsed().as_secs_f64();
+    sim.save_checkpoint();
+
+    if let Some(path) = export {
+        if let Err(e) = sim.export(path) {
+            eprintln!("failed to write {:?}: {}", path, e);
+        }
+    }
+
+    let stars = sim.stars();
+    let (mut com, mass) = ([0.0f64; 3], stars.iter().map(|s| s.mass as f64).sum::<f64>());
+    for s in &stars {
+        let m = s.mass as f64 / mass;
+        com[0] += m * s.x as f64;
+        com[1] += m * s.y as f64;
+        com[2] += m * s.z as f64;
+    }
+
+    console_log!(
+        "{} steps of {} stars in {:.2}s ({:.1} steps/s), t = {}, center of mass {:?}",
+        sim.steps(),
+        sim.n_stars(),
+        elapsed,
+        steps as f64 / elapsed,
+        sim.time(),
+        com
+    );
+    if sim.params().is_comoving() {
+        use crate::simulation::cosmology;
+        console_log!("a = {:.4}, z = {:.3}", cosmology::scale_factor(sim.time()), cosmology::redshift(sim.time()));
+    }
+
+    if let Some(first) = sim.history().first() {
+        let last = sim.measure();
+        console_log!(
+            "E = {:.6e} at step {}, {:.6e} now (dE/E = {:.2e}), 2K/|W| = {:.3}",
+            first.energy(),
+            first.step,
+            last.energy(),
+            last.energy_drift(first),
+            last.virial_ratio()
+        );
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 fn main() {
     use wasm_bindgen::prelude::*;
@@ -58,7 +171,7 @@ fn main() {
 
         wasm::insert_canvas(&window);
 
-        let app = App::new(window).await;
+        let app = App::new(window, None, wasm::query_config()).await;
         let start_closure = Closure::once_into_js(move || start(event_loop, app));
 
         // make sure to handle JS exceptions thrown inside start.
@@ -114,6 +227,47 @@ fn start(event_loop: EventLoop<()>, mut app: App) {
                 } => {
                     app.render_ctx.zoom -= 0.1;
                 }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::LBracket),
+                            ..
+                        },
+                    ..
+                } => {
+                    app.set_particle_count(app.n_stars / 2);
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::RBracket),
+                            ..
+                        },
+                    ..
+                } => {
+                    app.set_particle_count(app.n_stars.saturating_mul(2));
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::E),
+                            ..
+                        },
+                    ..
+                } => {
+                    if cfg!(not(target_arch = "wasm32")) {
+                        app.export(&app.config.output.export_path());
+                    }
+                }
+                WindowEvent::CloseRequested => {
+                    if cfg!(not(target_arch = "wasm32")) && app.config.output.checkpoint_every().is_some() {
+                        app.save_checkpoint();
+                    }
+                    *control_flow = ControlFlow::Exit;
+                }
                 WindowEvent::Resized(size) => app.resize(*size),
                 // WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                 //     //resize
@@ -0,0 +1,311 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use wgpu::{include_wgsl, util::DeviceExt, BindGroup, Buffer, CommandEncoder, ShaderStages};
+
+use crate::{
+    app::GpuContext,
+    pipelines::{BindgroupBuilder, Binding, BindingResource, ComputePipeline, ComputePipelineBuilder, PingPongBuffer},
+    simulation::{collisions::CollisionParams, star::Star},
+};
+
+use super::{IntegratePass::IntegratePass, RenderPass::ComputePass};
+
+// Stars per tile in count and scatter, must match TILE in collisions.wgsl
+const TILE: u32 = 64;
+
+// Merges stars closer than the merge radius and compacts the dead ones out of the star buffer,
+// run after IntegratePass. See simulation::collisions for the CPU reference.
+//
+// The buffers keep their size, the dead end up massless after the live stars. How many are
+// left is read back asynchronously, and once poll reports fewer the owner rebuilds its passes
+// around a smaller buffer. Finding the pairs is another O(N^2) loop, like a direct step.
+pub struct CollisionPass {
+    n: u32,
+    // The live count poll last reported
+    live: u32,
+    merge_radius: f32,
+    num_tiles: u32,
+    nearest: ComputePipeline,
+    merge: ComputePipeline,
+    count: ComputePipeline,
+    scan: ComputePipeline,
+    scatter: ComputePipeline,
+    copy_back: ComputePipeline,
+    // Indexed by star buffer, merged in place and compacted into the other one
+    groups: Vec<BindGroup>,
+    stars: Rc<PingPongBuffer>,
+    counts: Buffer,
+    // The live count, one readback in flight at a time
+    staging: Buffer,
+    copied: Cell<bool>,
+    map_requested: bool,
+    mapped: Arc<AtomicBool>,
+}
+
+impl CollisionPass {
+    pub fn new(ctx: &GpuContext, stars: Rc<PingPongBuffer>, integrate: &IntegratePass, merge_radius: f32) -> Self {
+        let n = (stars.size() / std::mem::size_of::<Star>() as u64) as u32;
+        let num_tiles = n.div_ceil(TILE);
+        let (accel, jerk) = integrate.carried();
+        let box_size = integrate.params().box_size;
+
+        let storage = |label: &str, size: u64| {
+            ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })
+        };
+
+        let accel_out = storage("Compacted Acceleration Buffer", accel.size());
+        let jerk_out = storage("Compacted Jerk Buffer", jerk.size());
+        let partner = storage("Merge Partner Buffer", n as u64 * 4);
+        let alive = storage("Alive Flag Buffer", n as u64 * 4);
+        // One per tile, then the total. Everyone is alive until the first exec, for flush
+        let mut initial = vec![0u32; num_tiles as usize + 1];
+        initial[num_tiles as usize] = n;
+        let counts = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Tile Count Buffer"),
+                contents: bytemuck::cast_slice(&initial),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            });
+
+        let params = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Collision Params Uniform"),
+                contents: bytemuck::cast_slice(&[CollisionParams { n, num_tiles, merge_radius, box_size }]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let group = |src: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(stars.get(src), false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(stars.get(1 - src), false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(accel, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(jerk, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&accel_out, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&jerk_out, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&partner, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&alive, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&counts, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params)})
+        };
+
+        let stage = |entry: &str, name: &str| {
+            ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/collisions.wgsl"))
+                .entry(entry)
+                .bind_group(&ctx.device, group(0))
+                .name(name)
+                .build(&ctx.device)
+        };
+
+        Self {
+            n,
+            live: n,
+            merge_radius,
+            num_tiles,
+            nearest: stage("nearest", "Nearest Neighbour Pipeline"),
+            merge: stage("merge", "Merge Pipeline"),
+            count: stage("count", "Live Count Pipeline"),
+            scan: stage("scan", "Live Scan Pipeline"),
+            scatter: stage("scatter", "Compaction Scatter Pipeline"),
+            copy_back: stage("copy_back", "Compacted Force Copy Pipeline"),
+            groups: (0..2).map(|s| group(s).build(&ctx.device).0).collect(),
+            stars,
+            staging: ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Live Count Staging Buffer"),
+                size: 4,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            counts,
+            copied: Cell::new(false),
+            map_requested: false,
+            mapped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn merge_radius(&self) -> f32 {
+        self.merge_radius
+    }
+
+    // Call after submitting the encoder passed to exec. The number of live stars, whenever it
+    // has dropped since the last call that returned one
+    pub fn poll(&mut self, ctx: &GpuContext) -> Option<u32> {
+        self.read(ctx).and_then(|live| self.dropped(live))
+    }
+
+    // Blocks until the count the last exec left is read back, so a batch run ends compacted
+    pub fn flush(&mut self, ctx: &GpuContext) -> Option<u32> {
+        let dropped = self.wait(ctx);
+
+        let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Live Count Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.counts, self.num_tiles as u64 * 4, &self.staging, 0, 4);
+        ctx.command_queue.submit(std::iter::once(encoder.finish()));
+        self.copied.set(true);
+
+        self.wait(ctx).or(dropped)
+    }
+
+    fn wait(&mut self, ctx: &GpuContext) -> Option<u32> {
+        let mut dropped = None;
+        while self.copied.get() {
+            dropped = self.poll(ctx).or(dropped);
+            ctx.device.poll(wgpu::Maintain::Wait);
+        }
+        dropped
+    }
+
+    fn dropped(&mut self, live: u32) -> Option<u32> {
+        if live >= self.live {
+            return None;
+        }
+        self.live = live;
+        Some(live)
+    }
+
+    // The count copied by exec, once it's mapped
+    fn read(&mut self, ctx: &GpuContext) -> Option<u32> {
+        if self.copied.get() && !self.map_requested {
+            let mapped = self.mapped.clone();
+            self.staging.slice(..).map_async(wgpu::MapMode::Read, move |res| {
+                res.expect("failed to map live count staging buffer");
+                mapped.store(true, Ordering::Release);
+            });
+            self.map_requested = true;
+        }
+
+        ctx.device.poll(wgpu::Maintain::Poll);
+        if !self.mapped.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let live = bytemuck::cast_slice::<u8, u32>(&self.staging.slice(..).get_mapped_range())[0];
+        self.staging.unmap();
+        self.copied.set(false);
+        self.map_requested = false;
+        self.mapped.store(false, Ordering::Release);
+        Some(live)
+    }
+}
+
+impl ComputePass<GpuContext> for CollisionPass {
+    fn exec(
+        &self,
+        _ctx: &GpuContext,
+        encoder: &mut CommandEncoder
+    ) {
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Collision Compute Pass")
+            });
+
+            let groups = |count: u32| count.div_ceil(64);
+            let group = &self.groups[self.stars.index()];
+
+            self.nearest.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(groups(self.n), 1, 1);
+
+            self.merge.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(groups(self.n), 1, 1);
+
+            self.count.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(groups(self.num_tiles), 1, 1);
+
+            self.scan.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+
+            self.scatter.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(groups(self.num_tiles), 1, 1);
+
+            self.copy_back.bind_with(&mut compute_pass, 0, group);
+            compute_pass.dispatch_workgroups(groups(self.n), 1, 1);
+        }
+
+        if !self.copied.replace(true) {
+            encoder.copy_buffer_to_buffer(&self.counts, self.num_tiles as u64 * 4, &self.staging, 0, 4);
+        }
+        self.stars.flip();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::star_buffer,
+        pipelines::read_buffer,
+        simulation::{collisions::collide, params::SimParams, Integrator, Solver},
+    };
+
+    // A lattice one apart with a companion 0.15 from every other star, heavier and moving
+    // differently. Along x the companion sits across the edge of a box of 4, so in the periodic
+    // run the merged star lands across it too
+    fn pairs() -> Vec<Star> {
+        let mut stars = vec![];
+        for i in 0..64 {
+            let p = [i % 4, (i / 4) % 4, i / 16].map(|c| c as f32 - 1.9);
+            let v = [0.1 * (i % 3) as f32, -0.2, 0.05 * i as f32];
+            stars.push(Star { x: p[0], y: p[1], z: p[2], mass: 1.0, x_vel: v[0], y_vel: v[1], z_vel: v[2], bright: 0.5 });
+            if i % 2 == 0 {
+                let x = if i % 4 == 0 { 1.95 } else { p[0] + 0.15 };
+                stars.push(Star { x, y: p[1] + 0.01, z: p[2], mass: 3.0, x_vel: -v[0], y_vel: 0.3, z_vel: 0.0, bright: 1.0 });
+            }
+        }
+        stars
+    }
+
+    fn totals(stars: &[Star]) -> [f64; 4] {
+        stars.iter().fold([0.0; 4], |t, s| {
+            let m = s.mass as f64;
+            [t[0] + m, t[1] + m * s.x_vel as f64, t[2] + m * s.y_vel as f64, t[3] + m * s.z_vel as f64]
+        })
+    }
+
+    #[test]
+    fn matches_cpu_and_conserves_mass_and_momentum() {
+        let ctx = pollster::block_on(GpuContext::headless());
+        let stars = pairs();
+        for box_size in [0.0, 4.0] {
+            let params = SimParams::new(stars.len() as u32).periodic(box_size);
+            let bufs = star_buffer(&ctx, &stars);
+            let integrate = IntegratePass::new(&ctx, bufs.clone(), Solver::Direct, Integrator::Euler, params);
+            let mut pass = CollisionPass::new(&ctx, bufs.clone(), &integrate, 0.3);
+
+            let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            pass.exec(&ctx, &mut encoder);
+            ctx.command_queue.submit(std::iter::once(encoder.finish()));
+            let live = pass.flush(&ctx).expect("no stars merged") as usize;
+
+            let gpu: Vec<Star> = read_buffer(&ctx.device, &ctx.command_queue, bufs.current());
+            let cpu = collide(&stars, 0.3, box_size);
+            assert_eq!(live, cpu.len(), "box {}", box_size);
+            // Open, only the interior companions are close enough
+            assert_eq!(live, if box_size > 0.0 { 64 } else { 80 }, "box {}", box_size);
+
+            for (g, c) in gpu[..live].iter().zip(&cpu) {
+                let (g, c) = ([g.x, g.y, g.z, g.mass, g.x_vel, g.y_vel, g.z_vel], [c.x, c.y, c.z, c.mass, c.x_vel, c.y_vel, c.z_vel]);
+                assert!(g.iter().zip(c).all(|(g, c)| (g - c).abs() <= 1.0E-5), "box {}: {:?} vs {:?}", box_size, g, c);
+            }
+            assert!(gpu[..live].iter().all(|s| [s.x, s.y, s.z].iter().all(|&x| box_size == 0.0 || (-2.0..2.0).contains(&x))));
+
+            let (before, after) = (totals(&stars), totals(&gpu));
+            for k in 0..4 {
+                assert!((before[k] - after[k]).abs() <= 1.0E-5 * before[0], "box {}: {:?} vs {:?}", box_size, before, after);
+            }
+        }
+    }
+}
@@ -7,21 +7,70 @@ use wgpu::{
 
 use crate::{
     app::RenderContext,
-    pipelines::{RenderPipeline, RenderPipelineBuilder, BindgroupBuilder, Binding, BindingResource},
+    pipelines::{RenderPipeline, RenderPipelineBuilder, BindgroupBuilder, Binding, BindingResource, PingPongBuffer},
     simulation::star::Star,
 };
 
 use super::RenderPass::RenderPass;
 
+// Maps simulation coordinates onto the [-1, 1] cube the camera looks at
+#[derive(Clone, Copy, Debug)]
+pub struct Extent {
+    pub center: [f32; 3],
+    pub half_width: f32,
+}
+
+impl Extent {
+    // Centered on the mean position and sized to hold most of the stars, so a few
+    // escapers don't shrink the rest of the scene to a dot
+    pub fn fit(stars: &[Star]) -> Self {
+        const FRACTION: f32 = 0.9;
+        let n = stars.len().max(1) as f64;
+
+        let mut center = [0.0f64; 3];
+        for s in stars {
+            center[0] += s.x as f64 / n;
+            center[1] += s.y as f64 / n;
+            center[2] += s.z as f64 / n;
+        }
+        let center = center.map(|c| c as f32);
+
+        let mut dist: Vec<f32> = stars
+            .iter()
+            .map(|s| (s.x - center[0]).abs().max((s.y - center[1]).abs()).max((s.z - center[2]).abs()))
+            .collect();
+        dist.sort_by(f32::total_cmp);
+        let half_width = dist.get((dist.len() as f32 * FRACTION) as usize).copied().unwrap_or(0.0);
+
+        Self {
+            center,
+            half_width: if half_width > 0.0 { half_width } else { 1.0 },
+        }
+    }
+
+    // Just the periodic box around the origin, where every star stays
+    pub fn periodic(box_size: f32) -> Self {
+        Self { center: [0.0; 3], half_width: box_size / 2.0 }
+    }
+
+    fn as_vec4(&self) -> [f32; 4] {
+        [self.center[0], self.center[1], self.center[2], self.half_width]
+    }
+}
+
 pub struct ColorPass {
     pl_drawstars: RenderPipeline,
     pl_drawgas: RenderPipeline,
+    // Only with outline()
+    pl_drawbox: Option<RenderPipeline>,
     output_view: TextureView,
-    vp_buf: Buffer
+    stars: Rc<PingPongBuffer>,
+    vp_buf: Buffer,
+    extent_buf: Buffer
 }
 
 impl ColorPass {
-    pub fn new(ctx: &RenderContext, stars: Rc<Buffer>) -> Self {
+    pub fn new(ctx: &RenderContext, stars: Rc<PingPongBuffer>, extent: Extent) -> Self {
         let target = ctx
             .color_target
             .create_view(&TextureViewDescriptor::default());
@@ -43,15 +92,24 @@ impl ColorPass {
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             });
 
+        let extent_unif = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("View Extent"),
+                contents: bytemuck::cast_slice(&extent.as_vec4()),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
         let bg = BindgroupBuilder::new()
-            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&vp_unif)});
+            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&vp_unif)})
+            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&extent_unif)});
 
         Self {
             pl_drawstars: {
                 RenderPipelineBuilder::new()
                     .vert(&ctx.device, include_wgsl!("../../shaders/draw_stars.wgsl"))
                     .frag(&ctx.device, include_wgsl!("../../shaders/draw_stars.wgsl"))
-                    .vertex_buffer(vertex_buffer_layout, stars)
+                    .vertex_buffer(vertex_buffer_layout, stars.get(0).clone())
                     .bind_group(&ctx.device, bg)
                     .topo(wgpu::PrimitiveTopology::PointList)
                     .name("Draw Stars")
@@ -63,10 +121,49 @@ impl ColorPass {
                     .frag(&ctx.device, include_wgsl!("../../shaders/draw_gas.wgsl"))
                     .build(&ctx.device, &TextureFormat::Rgba8Unorm)
             },
+            pl_drawbox: None,
             output_view: target,
-            vp_buf: vp_unif
+            stars,
+            vp_buf: vp_unif,
+            extent_buf: extent_unif
         }
     }
+
+    // Also draw the edges of a periodic box of side `box_size` around the origin
+    pub fn outline(mut self, ctx: &RenderContext, box_size: f32) -> Self {
+        let box_unif = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Box Size"),
+                contents: bytemuck::cast_slice(&[box_size / 2.0, 0.0, 0.0, 0.0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let bg = BindgroupBuilder::new()
+            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&self.vp_buf)})
+            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&self.extent_buf)})
+            .resource( Binding { vis: ShaderStages::VERTEX, res: BindingResource::Uniform(&box_unif)});
+
+        self.pl_drawbox = Some({
+            RenderPipelineBuilder::new()
+                .vert(&ctx.device, include_wgsl!("../../shaders/draw_box.wgsl"))
+                .frag(&ctx.device, include_wgsl!("../../shaders/draw_box.wgsl"))
+                .bind_group(&ctx.device, bg)
+                .topo(wgpu::PrimitiveTopology::LineList)
+                .name("Draw Box")
+                .build(&ctx.device, &TextureFormat::Rgba8Unorm)
+        });
+        self
+    }
+
+    // Draws from a new star buffer, after CollisionPass shrinks it. Keeps the extent
+    pub fn rebind(&mut self, stars: Rc<PingPongBuffer>) {
+        self.stars = stars;
+    }
+
+    pub fn set_extent(&self, ctx: &RenderContext, extent: Extent) {
+        ctx.command_queue.write_buffer(&self.extent_buf, 0, bytemuck::cast_slice(&extent.as_vec4()));
+    }
 }
 
 impl<'surf> RenderPass for ColorPass {
@@ -105,7 +202,12 @@ impl<'surf> RenderPass for ColorPass {
         });
 
         // self.pl_drawgas.draw(&mut render_pass);
-        self.pl_drawstars.bind(&mut render_pass);
+        self.pl_drawstars.bind_vertex_with(&mut render_pass, 0, self.stars.current());
         render_pass.draw(verts, instances);
+
+        if let Some(pl) = &self.pl_drawbox {
+            pl.bind(&mut render_pass);
+            render_pass.draw(0..24, 0..1);
+        }
     }
 }
@@ -0,0 +1,263 @@
+use std::{
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use wgpu::{include_wgsl, BindGroup, Buffer, CommandEncoder, ShaderStages};
+
+use crate::{
+    app::GpuContext,
+    pipelines::{read_buffer, BindgroupBuilder, Binding, BindingResource, ComputePipeline, ComputePipelineBuilder, PingPongBuffer},
+    simulation::{
+        diagnostics::{Diagnostics, DiagnosticsLog, Partial},
+        params::SimParams,
+        star::Star,
+    },
+};
+
+// How many measurements can be in flight before new ones get dropped
+const STAGING_BUFFERS: usize = 4;
+
+struct Staging {
+    buffer: Buffer,
+    // Step and time of the copy recorded into this buffer
+    at: Option<(u64, f64)>,
+    map_requested: bool,
+    mapped: Arc<AtomicBool>,
+}
+
+// Measures energy, momentum and angular momentum every `every` steps. The stars get reduced
+// to one Partial per workgroup on the GPU, those are copied out and mapped asynchronously
+// like SnapshotPass does, so nothing waits on the readback. The potential is the same O(N^2)
+// sum as a direct step, whatever solver is integrating.
+pub struct DiagnosticsPass {
+    stars: Rc<PingPongBuffer>,
+    every: u64,
+    n_stars: u32,
+    pipeline: ComputePipeline,
+    // Indexed by star buffer
+    groups: Vec<BindGroup>,
+    partials: Buffer,
+    params_unif: Buffer,
+    // IntegratePass::ewald, kept for rebind
+    ewald: Rc<Buffer>,
+    staging: Vec<Staging>,
+    log: Option<DiagnosticsLog>,
+    history: Vec<Diagnostics>,
+}
+
+impl DiagnosticsPass {
+    pub fn new(ctx: &GpuContext, stars: Rc<PingPongBuffer>, ewald: Rc<Buffer>, every: u32) -> Self {
+        let n_stars = (stars.size() / std::mem::size_of::<Star>() as u64) as u32;
+        let partials_size = (n_stars.div_ceil(64).max(1) as usize * std::mem::size_of::<Partial>()) as u64;
+
+        let partials = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Diagnostics Partials Buffer"),
+            size: partials_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let params_unif = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Diagnostics Params Uniform"),
+            size: std::mem::size_of::<SimParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let group = |src: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(stars.get(src), true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&partials, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params_unif)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&ewald, true)})
+        };
+
+        let staging = (0..STAGING_BUFFERS)
+            .map(|_| Staging {
+                buffer: ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Diagnostics Staging Buffer"),
+                    size: partials_size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                at: None,
+                map_requested: false,
+                mapped: Arc::new(AtomicBool::new(false)),
+            })
+            .collect();
+
+        Self {
+            every: every.max(1) as u64,
+            n_stars,
+            pipeline: ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/diagnostics.wgsl"))
+                .bind_group(&ctx.device, group(0))
+                .name("Diagnostics Pipeline")
+                .build(&ctx.device),
+            groups: (0..2).map(|s| group(s).build(&ctx.device).0).collect(),
+            stars,
+            partials,
+            params_unif,
+            ewald,
+            staging,
+            log: None,
+            history: vec![],
+        }
+    }
+
+    // Also append every measurement to a CSV time series
+    pub fn log(mut self, log: DiagnosticsLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    // Everything read back so far, in step order
+    pub fn history(&self) -> &[Diagnostics] {
+        &self.history
+    }
+
+    // Moves over to a new star buffer, after CollisionPass shrinks it. Keeps the log and history
+    pub fn rebind(&mut self, ctx: &GpuContext, stars: Rc<PingPongBuffer>) {
+        self.flush(ctx);
+        *self = Self {
+            log: self.log.take(),
+            history: std::mem::take(&mut self.history),
+            ..Self::new(ctx, stars, self.ewald.clone(), self.every as u32)
+        };
+    }
+
+    // Reduces the current star buffer into `partials`
+    fn record(&self, ctx: &GpuContext, encoder: &mut CommandEncoder, params: SimParams) {
+        let params = SimParams { n_stars: self.n_stars, ..params };
+        ctx.command_queue.write_buffer(&self.params_unif, 0, bytemuck::cast_slice(&[params]));
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Diagnostics Compute Pass")
+        });
+        self.pipeline.bind_with(&mut compute_pass, 0, &self.groups[self.stars.index()]);
+        compute_pass.dispatch_workgroups(self.n_stars.div_ceil(64), 1, 1);
+    }
+
+    // Records a measurement if `step` is due for one. Goes in before the integrate pass, like
+    // SnapshotPass::capture, so it measures `step` and `time`
+    pub fn capture(&mut self, ctx: &GpuContext, encoder: &mut CommandEncoder, step: u64, time: f64, params: SimParams) {
+        if step % self.every != 0 {
+            return;
+        }
+
+        let Some(i) = self.staging.iter().position(|s| s.at.is_none()) else {
+            eprintln!("dropping the diagnostics at step {}, the last {} haven't been read back yet", step, STAGING_BUFFERS);
+            return;
+        };
+
+        self.record(ctx, encoder, params);
+        let slot = &mut self.staging[i];
+        encoder.copy_buffer_to_buffer(&self.partials, 0, &slot.buffer, 0, self.partials.size());
+        slot.at = Some((step, time));
+    }
+
+    // Call after submitting the encoder passed to capture. Returns how many measurements came in
+    pub fn poll(&mut self, ctx: &GpuContext) -> usize {
+        for slot in self.staging.iter_mut().filter(|s| s.at.is_some() && !s.map_requested) {
+            let mapped = slot.mapped.clone();
+            slot.buffer.slice(..).map_async(wgpu::MapMode::Read, move |res| {
+                res.expect("failed to map diagnostics staging buffer");
+                mapped.store(true, Ordering::Release);
+            });
+            slot.map_requested = true;
+        }
+
+        ctx.device.poll(wgpu::Maintain::Poll);
+
+        let mut ready: Vec<Diagnostics> = vec![];
+        for slot in self.staging.iter_mut().filter(|s| s.mapped.load(Ordering::Acquire)) {
+            let (step, time) = slot.at.take().unwrap();
+            ready.push(Diagnostics::from_partials(step, time, bytemuck::cast_slice(&slot.buffer.slice(..).get_mapped_range())));
+            slot.buffer.unmap();
+            slot.map_requested = false;
+            slot.mapped.store(false, Ordering::Release);
+        }
+
+        // Slots can finish out of order
+        ready.sort_by_key(|d| d.step);
+        for d in &ready {
+            if let Some(log) = &mut self.log {
+                if let Err(e) = log.push(d) {
+                    eprintln!("failed to log diagnostics: {}", e);
+                }
+            }
+        }
+        self.history.extend_from_slice(&ready);
+        ready.len()
+    }
+
+    // Blocks until there's room for another capture, for batch runs that mustn't drop any
+    pub fn reserve(&mut self, ctx: &GpuContext) {
+        while self.staging.iter().all(|s| s.at.is_some()) {
+            self.poll(ctx);
+            ctx.device.poll(wgpu::Maintain::Wait);
+        }
+    }
+
+    // Blocks until every capture is read back
+    pub fn flush(&mut self, ctx: &GpuContext) {
+        while self.staging.iter().any(|s| s.at.is_some()) {
+            self.poll(ctx);
+            ctx.device.poll(wgpu::Maintain::Wait);
+        }
+    }
+
+    // Measures the current state right away, blocking until the GPU catches up.
+    // Not logged or kept in the history
+    pub fn measure(&self, ctx: &GpuContext, step: u64, time: f64, params: SimParams) -> Diagnostics {
+        let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Diagnostics Encoder"),
+        });
+        self.record(ctx, &mut encoder, params);
+        ctx.command_queue.submit(std::iter::once(encoder.finish()));
+
+        let partials: Vec<Partial> = read_buffer(&ctx.device, &ctx.command_queue, &self.partials);
+        Diagnostics::from_partials(step, time, &partials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::GpuContext,
+        headless::Simulation,
+        simulation::{diagnostics::Diagnostics, ics::{IcParams, Model}, params::{SimParams, SofteningKernel}, Integrator, Solver},
+    };
+
+    #[test]
+    fn matches_cpu_and_conserves_energy() {
+        let ctx = pollster::block_on(GpuContext::headless());
+        let stars = Model::Plummer.generate(&IcParams { n: 512, mass: 1.0, scale_radius: 1.0, seed: 2 }, 1.0);
+        let params = SimParams::new(512).gravity(1.0).dt(1.0E-2).softening(0.05, SofteningKernel::Plummer);
+        let mut sim = Simulation::new(ctx, stars, Solver::Direct, Integrator::Leapfrog, params).diagnostics(50, None);
+        sim.step(200);
+
+        let gpu = sim.measure();
+        let cpu = Diagnostics::measure(sim.steps(), sim.time(), &sim.stars(), &sim.params());
+        let close = |a: f64, b: f64, tolerance: f64| (a - b).abs() <= tolerance * b.abs().max(1.0);
+        assert!(close(gpu.kinetic, cpu.kinetic, 1.0E-5), "kinetic {} vs {}", gpu.kinetic, cpu.kinetic);
+        assert!(close(gpu.potential, cpu.potential, 1.0E-5), "potential {} vs {}", gpu.potential, cpu.potential);
+        assert!(close(gpu.mass, cpu.mass, 1.0E-6), "mass {} vs {}", gpu.mass, cpu.mass);
+        for k in 0..3 {
+            assert!(close(gpu.momentum[k], cpu.momentum[k], 1.0E-5), "momentum {:?} vs {:?}", gpu.momentum, cpu.momentum);
+            assert!(close(gpu.angular_momentum[k], cpu.angular_momentum[k], 1.0E-5), "angular momentum {:?} vs {:?}", gpu.angular_momentum, cpu.angular_momentum);
+            assert!(close(gpu.center_of_mass[k], cpu.center_of_mass[k], 1.0E-5), "center of mass {:?} vs {:?}", gpu.center_of_mass, cpu.center_of_mass);
+        }
+
+        // Four measurements on the way, the leapfrog keeps the energy to a few 1e-6 here
+        let history = sim.history();
+        assert_eq!(history.len(), 4);
+        for d in history {
+            assert!(d.energy_drift(&history[0]) < 1.0E-4, "dE/E = {} at step {}", d.energy_drift(&history[0]), d.step);
+        }
+        assert!(gpu.energy_drift(&history[0]) < 1.0E-4, "dE/E = {}", gpu.energy_drift(&history[0]));
+    }
+}
@@ -1,59 +1,537 @@
-use std::rc::Rc;
+use std::{cell::Cell, iter, rc::Rc};
 
+use bytemuck::{Pod, Zeroable};
 use wgpu::{
     include_wgsl, CommandEncoder, ShaderStages,
-    TextureViewDescriptor, Buffer, include_spirv_raw, util::DeviceExt,
+    Buffer, BindGroup, util::DeviceExt,
 };
 
 use crate::{
-    app::{RenderContext, N_PARTS},
-    pipelines::{BindgroupBuilder, ComputePipeline, ComputePipelineBuilder, Binding, BindingResource}, simulation::star::Star,
+    app::GpuContext,
+    pipelines::{read_buffer, BindgroupBuilder, ComputePipeline, ComputePipelineBuilder, Binding, BindingResource, PingPongBuffer},
+    simulation::{barnes_hut, checkpoint::IntegratorState, ewald, fmm, params::SimParams, star::Star, Integrator, Solver},
 };
 
-use super::RenderPass::ComputePass;
+use super::{ParticleMeshPass::ParticleMeshPass, RenderPass::ComputePass, TreePmPass::TreePmPass};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct RkStage {
+    weight: f32,
+    step: f32,
+}
+
+// Weight of k1..k3 in the final sum, and how far along the step the next trial state is
+const RK4_STAGES: [RkStage; 3] = [
+    RkStage { weight: 1.0, step: 0.5 },
+    RkStage { weight: 2.0, step: 0.5 },
+    RkStage { weight: 2.0, step: 1.0 },
+];
+
+// Which state the forces get evaluated at
+#[derive(Clone, Copy)]
+enum Source {
+    // The star buffer being written this step
+    Stars,
+    Trial,
+}
+
+// Slot of the trial buffer in the bind group vectors, after the two star buffers
+const TRIAL: usize = 2;
+
+struct Stages {
+    euler: ComputePipeline,
+    kick_open: ComputePipeline,
+    kick_close: ComputePipeline,
+    drift: ComputePipeline,
+    verlet_drift: ComputePipeline,
+    verlet_kick: ComputePipeline,
+    rk4_stage: ComputePipeline,
+    rk4_finish: ComputePipeline,
+    hermite_predict: ComputePipeline,
+    hermite_correct: ComputePipeline,
+    wrap: ComputePipeline,
+}
 
 pub struct IntegratePass {
+    solver: Solver,
+    integrator: Integrator,
     update_positions: ComputePipeline,
-    stars: Rc<Buffer>
+    accel_pl: ComputePipeline,
+    accel_jerk_pl: ComputePipeline,
+    // Only for Solver::ParticleMesh
+    mesh: Option<ParticleMeshPass>,
+    // Only for Solver::TreePm
+    treepm: Option<TreePmPass>,
+    // Fused Euler reads star buffer i and writes the other one
+    fused_groups: Vec<BindGroup>,
+    // Indexed by star buffer, or TRIAL
+    accel_groups: Vec<BindGroup>,
+    jerk_groups: Vec<BindGroup>,
+    // Indexed by the star buffer the stages work on
+    stage_groups: Vec<BindGroup>,
+    rk_groups: Vec<BindGroup>,
+    stages: Stages,
+    stars: Rc<PingPongBuffer>,
+    trial: Buffer,
+    accel: Buffer,
+    accel_old: Buffer,
+    jerk: Buffer,
+    sum_x: Buffer,
+    sum_v: Buffer,
+    readback: Buffer,
+    params_unif: Buffer,
+    // simulation::ewald::table for periodic boxes, shared with DiagnosticsPass
+    ewald: Rc<Buffer>,
+    params: Cell<SimParams>,
+    // Where the next step starts, see set_time
+    time: Cell<f64>,
+    // Leapfrog, Verlet and Hermite reuse the forces from the end of the previous step
+    primed: Cell<bool>
 }
 
 impl IntegratePass {
-    pub fn new(ctx: &RenderContext, bufs: Rc<Buffer>) -> Self {     
-        let np_unif = ctx
+    // Every step reads the current star buffer and leaves the result in the other one before
+    // flipping them. The star count comes from the buffer size, it overrides params.n_stars
+    pub fn new(ctx: &GpuContext, bufs: Rc<PingPongBuffer>, solver: Solver, integrator: Integrator, params: SimParams) -> Self {
+        assert!(
+            integrator != Integrator::Hermite || solver == Solver::Direct,
+            "the Hermite integrator needs the jerk, which only the direct solver computes"
+        );
+        assert!(
+            !params.is_periodic() || (solver == Solver::Direct && integrator != Integrator::Hermite),
+            "periodic boxes need the direct solver, and an integrator other than Hermite, whose jerk has no Ewald term"
+        );
+        assert!(
+            !params.is_comoving() || (params.is_periodic() && integrator == Integrator::Leapfrog),
+            "comoving runs need a periodic box, which takes out the mean density, and the leapfrog, the one integrator with comoving kicks and drifts"
+        );
+
+        let n = (bufs.size() / std::mem::size_of::<Star>() as u64) as u32;
+        let params = SimParams { n_stars: n, ..params };
+
+        let params_unif = ctx
             .device
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Part Count Uniform"),
-                contents: bytemuck::cast_slice(&[N_PARTS]),
+                label: Some("Sim Params Uniform"),
+                contents: bytemuck::cast_slice(&[params]),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             });
 
-        let bg = BindgroupBuilder::new()
-            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(bufs.as_ref(), false)})
-            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&np_unif)});
+        // One vec4 per star, CPU solvers upload into `accel` too
+        let per_star = |label: &str| {
+            ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (n.max(1) as usize * std::mem::size_of::<[f32; 4]>()) as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+
+        let accel = per_star("Acceleration Buffer");
+        let accel_old = per_star("Previous Acceleration Buffer");
+        let jerk = per_star("Jerk Buffer");
+        let accel_new = per_star("New Acceleration Buffer");
+        let jerk_new = per_star("New Jerk Buffer");
+        let sum_x = per_star("RK4 Position Sum Buffer");
+        let sum_v = per_star("RK4 Velocity Sum Buffer");
+
+        let trial = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Trial Star Buffer"),
+            size: bufs.size(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let readback = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Star Readback Buffer"),
+            size: bufs.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // The table is the same for every box, open boundaries bind one entry that's never read
+        let ewald_table = if params.is_periodic() { ewald::table() } else { vec![[0.0; 4]] };
+        let ewald = Rc::new(ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Ewald Table Buffer"),
+            contents: bytemuck::cast_slice(&ewald_table),
+            usage: wgpu::BufferUsages::STORAGE,
+        }));
+
+        let rk_unifs: Vec<Buffer> = RK4_STAGES
+            .iter()
+            .map(|s| {
+                ctx.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("RK4 Stage Uniform"),
+                        contents: bytemuck::cast_slice(&[*s]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    })
+            })
+            .collect();
+
+        let fused_group = |src: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(bufs.get(src), true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(bufs.get(1 - src), false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params_unif)})
+        };
+
+        let sources = [bufs.get(0).as_ref(), bufs.get(1).as_ref(), &trial];
+
+        let accel_group = |src: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[src], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&accel, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params_unif)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&ewald, true)})
+        };
+
+        // Hermite keeps the forces at the start of the step, the ones at the prediction go to *_new
+        let jerk_group = |src: usize| {
+            let (a, j) = if src == TRIAL { (&accel_new, &jerk_new) } else { (&accel, &jerk) };
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[src], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(a, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(j, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params_unif)})
+        };
+
+        let rk_group = |stage: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&rk_unifs[stage])})
+        };
+
+        let stage_group = |target: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(bufs.get(target), false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&trial, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&accel, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&accel_old, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&jerk, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&accel_new, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&jerk_new, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&sum_x, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&sum_v, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params_unif)})
+        };
+
+        let stage = |entry: &str, name: &str| {
+            ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/integrators.wgsl"))
+                .entry(entry)
+                .bind_group(&ctx.device, stage_group(0))
+                .bind_group(&ctx.device, rk_group(0))
+                .name(name)
+                .build(&ctx.device)
+        };
 
         Self {
+            solver,
+            integrator,
             update_positions: {
                 ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/integrate.wgsl"))
-                    .bind_group(&ctx.device, bg)
+                    .bind_group(&ctx.device, fused_group(0))
                     .name("Update Positions Pipeline")
                     .build(&ctx.device)
             },
-            stars: bufs
+            accel_pl: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/accel.wgsl"))
+                    .bind_group(&ctx.device, accel_group(0))
+                    .name("Acceleration Pipeline")
+                    .build(&ctx.device)
+            },
+            accel_jerk_pl: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/accel_jerk.wgsl"))
+                    .bind_group(&ctx.device, jerk_group(0))
+                    .name("Acceleration and Jerk Pipeline")
+                    .build(&ctx.device)
+            },
+            mesh: match solver {
+                Solver::ParticleMesh { mesh } => Some(ParticleMeshPass::new(ctx, &sources, &accel, &params_unif, mesh, None)),
+                _ => None,
+            },
+            treepm: match solver {
+                Solver::TreePm { mesh, theta } => Some(TreePmPass::new(ctx, &sources, &accel, &params_unif, mesh, theta)),
+                _ => None,
+            },
+            fused_groups: (0..2).map(|s| fused_group(s).build(&ctx.device).0).collect(),
+            accel_groups: (0..sources.len()).map(|s| accel_group(s).build(&ctx.device).0).collect(),
+            jerk_groups: (0..sources.len()).map(|s| jerk_group(s).build(&ctx.device).0).collect(),
+            stage_groups: (0..2).map(|s| stage_group(s).build(&ctx.device).0).collect(),
+            rk_groups: (0..RK4_STAGES.len()).map(|s| rk_group(s).build(&ctx.device).0).collect(),
+            stages: Stages {
+                euler: stage("euler", "Euler Pipeline"),
+                kick_open: stage("kick_open", "Opening Kick Pipeline"),
+                kick_close: stage("kick_close", "Closing Kick Pipeline"),
+                drift: stage("drift", "Drift Pipeline"),
+                verlet_drift: stage("verlet_drift", "Verlet Drift Pipeline"),
+                verlet_kick: stage("verlet_kick", "Verlet Kick Pipeline"),
+                rk4_stage: stage("rk4_stage", "RK4 Stage Pipeline"),
+                rk4_finish: stage("rk4_finish", "RK4 Finish Pipeline"),
+                hermite_predict: stage("hermite_predict", "Hermite Predict Pipeline"),
+                hermite_correct: stage("hermite_correct", "Hermite Correct Pipeline"),
+                wrap: stage("wrap", "Wrap Pipeline"),
+            },
+            stars: bufs,
+            trial,
+            accel,
+            accel_old,
+            jerk,
+            sum_x,
+            sum_v,
+            readback,
+            params_unif,
+            ewald,
+            params: Cell::new(params),
+            time: Cell::new(0.0),
+            primed: Cell::new(false)
+        }
+    }
+
+    pub fn params(&self) -> SimParams {
+        self.params.get()
+    }
+
+    // Takes effect on the next exec, no pipelines need rebuilding. The box stays as it was, the
+    // Ewald table is only there if it started out periodic
+    pub fn set_params(&self, params: SimParams) {
+        let old = self.params.get();
+        self.params.set(SimParams { n_stars: old.n_stars, box_size: old.box_size, ..params });
+    }
+
+    // The time the next exec steps from, which the drift and kicks of comoving runs depend on.
+    // The owner keeps the clock, and sets it before every step
+    pub fn set_time(&self, time: f64) {
+        self.time.set(time);
+    }
+
+    pub fn solver(&self) -> Solver {
+        self.solver
+    }
+
+    pub fn integrator(&self) -> Integrator {
+        self.integrator
+    }
+
+    pub fn ewald(&self) -> Rc<Buffer> {
+        self.ewald.clone()
+    }
+
+    // The per star forces carried from one step to the next, which CollisionPass compacts
+    pub fn carried(&self) -> (&Buffer, &Buffer) {
+        (&self.accel, &self.jerk)
+    }
+
+    // Reads back the forces the next step will reuse, blocking until the GPU catches up
+    pub fn state(&self, ctx: &GpuContext) -> IntegratorState {
+        let read = |b: &Buffer| read_buffer::<[f32; 4]>(&ctx.device, &ctx.command_queue, b);
+        let (accel, jerk) = match self.integrator {
+            Integrator::Leapfrog | Integrator::VelocityVerlet => (read(&self.accel), vec![]),
+            Integrator::Hermite => (read(&self.accel), read(&self.jerk)),
+            Integrator::Euler | Integrator::Rk4 => (vec![], vec![]),
+        };
+
+        IntegratorState { primed: self.primed.get(), accel, jerk }
+    }
+
+    // The inverse of state, before the first exec
+    pub fn restore(&self, ctx: &GpuContext, state: &IntegratorState) {
+        if !state.accel.is_empty() {
+            ctx.command_queue.write_buffer(&self.accel, 0, bytemuck::cast_slice(&state.accel));
+        }
+        if !state.jerk.is_empty() {
+            ctx.command_queue.write_buffer(&self.jerk, 0, bytemuck::cast_slice(&state.jerk));
+        }
+        self.primed.set(state.primed && !state.accel.is_empty());
+    }
+
+    // The forces on the stars as they are, blocking until the GPU catches up. They are what
+    // the next Leapfrog, Verlet or Hermite step would start from, so that step reuses them
+    pub fn forces(&self, ctx: &GpuContext) -> Vec<[f32; 4]> {
+        ctx.command_queue.write_buffer(&self.params_unif, 0, bytemuck::cast_slice(&[self.params.get()]));
+        let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Forces Encoder"),
+        });
+
+        // eval reads the buffer a step would write
+        let (cur, next) = (self.stars.current(), self.stars.next());
+        encoder.copy_buffer_to_buffer(cur, 0, next, 0, cur.size());
+        self.eval(ctx, &mut encoder, Source::Stars);
+        ctx.command_queue.submit(iter::once(encoder.finish()));
+        self.primed.set(true);
+
+        read_buffer(&ctx.device, &ctx.command_queue, &self.accel)
+    }
+
+    fn workgroups(&self) -> u32 {
+        (self.params.get().n_stars + 63) / 64
+    }
+
+    // Runs one of the integrators.wgsl stages on the star buffer being written
+    fn dispatch(&self, encoder: &mut CommandEncoder, pl: &ComputePipeline, rk_stage: Option<&BindGroup>) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Integrate Compute Pass")
+        });
+
+        pl.bind_with(&mut compute_pass, 0, &self.stage_groups[1 - self.stars.index()]);
+        if let Some(bg) = rk_stage {
+            compute_pass.set_bind_group(1, bg, &[]);
+        }
+        compute_pass.dispatch_workgroups(self.workgroups(), 1, 1);
+    }
+
+    fn slot(&self, src: Source) -> usize {
+        match src {
+            Source::Stars => 1 - self.stars.index(),
+            Source::Trial => TRIAL,
         }
     }
+
+    // Fills `accel` (and `jerk` for Hermite) with the forces at the given state
+    fn eval(&self, ctx: &GpuContext, encoder: &mut CommandEncoder, src: Source) {
+        match self.solver {
+            Solver::Direct => {
+                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Acceleration Compute Pass")
+                });
+
+                if self.integrator == Integrator::Hermite {
+                    self.accel_jerk_pl.bind_with(&mut compute_pass, 0, &self.jerk_groups[self.slot(src)]);
+                } else {
+                    self.accel_pl.bind_with(&mut compute_pass, 0, &self.accel_groups[self.slot(src)]);
+                }
+                compute_pass.dispatch_workgroups(self.workgroups(), 1, 1);
+            }
+            Solver::ParticleMesh { .. } => {
+                let mesh = self.mesh.as_ref().unwrap();
+                mesh.solve(encoder, self.slot(src), self.params.get().n_stars);
+            }
+            Solver::TreePm { .. } => {
+                let treepm = self.treepm.as_ref().unwrap();
+                treepm.solve(encoder, self.slot(src), self.params.get().n_stars);
+            }
+            Solver::BarnesHut { .. } | Solver::Fmm { .. } => {
+                // The readback has to see everything recorded so far
+                let pending = std::mem::replace(
+                    encoder,
+                    ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                        label: Some("Render Encoder"),
+                    }),
+                );
+                ctx.command_queue.submit(iter::once(pending.finish()));
+
+                let src = match src {
+                    Source::Stars => self.stars.next().as_ref(),
+                    Source::Trial => &self.trial,
+                };
+                self.solve_on_cpu(ctx, src);
+            }
+        }
+    }
+
+    fn prime(&self, ctx: &GpuContext, encoder: &mut CommandEncoder) {
+        if !self.primed.replace(true) {
+            self.eval(ctx, encoder, Source::Stars);
+        }
+    }
+
+    // Blocks until `src` is on the CPU, so this is only usable natively
+    fn solve_on_cpu(&self, ctx: &GpuContext, src: &Buffer) {
+        let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Star Readback Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(src, 0, &self.readback, 0, src.size());
+        ctx.command_queue.submit(iter::once(encoder.finish()));
+
+        let slice = self.readback.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |res| res.expect("failed to map star readback"));
+        ctx.device.poll(wgpu::Maintain::Wait);
+
+        let accel = {
+            let data = slice.get_mapped_range();
+            let stars = bytemuck::cast_slice(&data);
+            match self.solver {
+                Solver::BarnesHut { theta } => barnes_hut::accelerations(stars, theta, &self.params.get()),
+                Solver::Fmm { order, theta } => fmm::accelerations(stars, order, theta, &self.params.get()),
+                _ => unreachable!("{:?} runs on the GPU", self.solver),
+            }
+        };
+        self.readback.unmap();
+
+        ctx.command_queue.write_buffer(&self.accel, 0, bytemuck::cast_slice(&accel));
+    }
 }
 
-impl<'surf> ComputePass for IntegratePass {
+impl ComputePass<GpuContext> for IntegratePass {
     fn exec(
         &self,
-        ctx: &RenderContext,
+        ctx: &GpuContext,
         encoder: &mut CommandEncoder
     ) {
-        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("Integrate Compute Pass")
-        });
+        let stages = &self.stages;
+        let params = self.params.get().at(self.time.get());
+        ctx.command_queue.write_buffer(&self.params_unif, 0, bytemuck::cast_slice(&[params]));
 
-        self.update_positions.bind(&mut compute_pass);
-        compute_pass.dispatch_workgroups((self.stars.size() / std::mem::size_of::<Star>() as u64 / 64) as u32, 1, 1);
+        let periodic = self.params.get().is_periodic();
+        let fused = self.integrator == Integrator::Euler && self.solver == Solver::Direct && !periodic;
+        if fused {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Integrate Compute Pass")
+            });
+
+            self.update_positions.bind_with(&mut compute_pass, 0, &self.fused_groups[self.stars.index()]);
+            compute_pass.dispatch_workgroups(self.workgroups(), 1, 1);
+        } else {
+            // The stages below update in place, each star only touching its own entry
+            let (cur, next) = (self.stars.current(), self.stars.next());
+            encoder.copy_buffer_to_buffer(cur, 0, next, 0, cur.size());
+        }
+
+        match self.integrator {
+            _ if fused => {}
+            Integrator::Euler => {
+                self.eval(ctx, encoder, Source::Stars);
+                self.dispatch(encoder, &stages.euler, None);
+            }
+            Integrator::Leapfrog => {
+                self.prime(ctx, encoder);
+                self.dispatch(encoder, &stages.kick_open, None);
+                self.dispatch(encoder, &stages.drift, None);
+                self.eval(ctx, encoder, Source::Stars);
+                self.dispatch(encoder, &stages.kick_close, None);
+            }
+            Integrator::VelocityVerlet => {
+                self.prime(ctx, encoder);
+                encoder.copy_buffer_to_buffer(&self.accel, 0, &self.accel_old, 0, self.accel.size());
+                self.dispatch(encoder, &stages.verlet_drift, None);
+                self.eval(ctx, encoder, Source::Stars);
+                self.dispatch(encoder, &stages.verlet_kick, None);
+            }
+            Integrator::Rk4 => {
+                let stars = self.stars.next();
+                encoder.copy_buffer_to_buffer(stars, 0, &self.trial, 0, stars.size());
+                encoder.clear_buffer(&self.sum_x, 0, None);
+                encoder.clear_buffer(&self.sum_v, 0, None);
+
+                for rk in &self.rk_groups {
+                    self.eval(ctx, encoder, Source::Trial);
+                    self.dispatch(encoder, &stages.rk4_stage, Some(rk));
+                }
+                self.eval(ctx, encoder, Source::Trial);
+                self.dispatch(encoder, &stages.rk4_finish, None);
+            }
+            Integrator::Hermite => {
+                self.prime(ctx, encoder);
+                self.dispatch(encoder, &stages.hermite_predict, None);
+                self.eval(ctx, encoder, Source::Trial);
+                self.dispatch(encoder, &stages.hermite_correct, None);
+            }
+        }
+
+        // Forces take the nearest image, so positions only need wrapping once per step
+        if periodic {
+            self.dispatch(encoder, &stages.wrap, None);
+        }
+        self.stars.flip();
     }
 }
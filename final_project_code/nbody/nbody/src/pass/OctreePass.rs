@@ -0,0 +1,379 @@
+use std::rc::Rc;
+
+use wgpu::{
+    include_wgsl, util::DeviceExt, BindGroup, Buffer, CommandEncoder, ShaderStages,
+};
+
+use crate::{
+    app::GpuContext,
+    pipelines::{read_buffer, BindgroupBuilder, Binding, BindingResource, ComputePipeline, ComputePipelineBuilder, PingPongBuffer},
+    simulation::{
+        lbvh::{Lbvh, LbvhNode, MortonBits, NodeData, ReduceLevel, TreeParams, NONE},
+        star::Star,
+    },
+};
+
+use super::RenderPass::ComputePass;
+
+// Keys per invocation in the radix sort, must match TILE in radix_*.wgsl
+const TILE: u32 = 64;
+const RADIX_BITS: u32 = 4;
+
+// Builds a linear BVH over the star buffer entirely on the GPU: bounds -> morton codes ->
+// LSD radix sort -> Karras hierarchy -> bottom-up mass/COM/bounds. See simulation::lbvh
+// for the CPU reference.
+pub struct OctreePass {
+    n: u32,
+    bits: MortonBits,
+    num_tiles: u32,
+    bounds: ComputePipeline,
+    morton: ComputePipeline,
+    hist: ComputePipeline,
+    scan: ComputePipeline,
+    scatter: ComputePipeline,
+    build: ComputePipeline,
+    leaves: ComputePipeline,
+    reduce: ComputePipeline,
+    // Indexed by source, exec builds over whichever star buffer is current
+    bounds_groups: Vec<BindGroup>,
+    morton_groups: Vec<BindGroup>,
+    reduce_groups: Vec<BindGroup>,
+    // One per dispatch of the bottom-up reduction, see MortonBits::max_depth
+    level_groups: Vec<BindGroup>,
+    // Only when made by new
+    stars: Option<Rc<PingPongBuffer>>,
+    hist_groups: Vec<BindGroup>,
+    scatter_groups: Vec<BindGroup>,
+    // Keys/values ping-pong between the two buffers each sort pass
+    keys: [Buffer; 2],
+    values: [Buffer; 2],
+    sorted: usize,
+    pub nodes: Buffer,
+    pub leaf_parents: Buffer,
+    pub node_data: Buffer,
+    pub leaf_data: Buffer,
+    merged: Buffer,
+}
+
+impl OctreePass {
+    pub fn new(ctx: &GpuContext, stars: Rc<PingPongBuffer>, bits: MortonBits) -> Self {
+        let mut pass = Self::over(ctx, &[stars.get(0).as_ref(), stars.get(1).as_ref()], bits);
+        pass.stars = Some(stars);
+        pass
+    }
+
+    // A tree over any of `sources`, all the same size, picked on every rebuild. Without a
+    // star buffer to follow it has no use for exec
+    pub fn over(ctx: &GpuContext, sources: &[&Buffer], bits: MortonBits) -> Self {
+        let n = (sources[0].size() / std::mem::size_of::<Star>() as u64) as u32;
+        let num_tiles = (n + TILE - 1) / TILE;
+        let passes = bits.sort_passes() as usize;
+        let sorted = passes % 2;
+
+        let storage = |label: &str, count: usize, stride: usize| {
+            ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                // Bindings need at least one element, a single star has no internal nodes
+                size: (count.max(1) * stride) as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+
+        let n_internal = n.saturating_sub(1) as usize;
+        let bounds_buf = storage("Tree Bounds Buffer", 2, std::mem::size_of::<[f32; 4]>());
+        let keys = [
+            storage("Morton Keys A", n as usize, std::mem::size_of::<[u32; 2]>()),
+            storage("Morton Keys B", n as usize, std::mem::size_of::<[u32; 2]>()),
+        ];
+        let values = [
+            storage("Morton Values A", n as usize, std::mem::size_of::<u32>()),
+            storage("Morton Values B", n as usize, std::mem::size_of::<u32>()),
+        ];
+        let hist_buf = storage("Radix Histogram", (num_tiles << RADIX_BITS) as usize, std::mem::size_of::<u32>());
+        let nodes = storage("LBVH Nodes", n_internal, std::mem::size_of::<LbvhNode>());
+        let node_data = storage("LBVH Node Data", n_internal, std::mem::size_of::<NodeData>());
+        let leaf_data = storage("LBVH Leaf Data", n as usize, std::mem::size_of::<NodeData>());
+        let merged = storage("LBVH Merge Levels", n_internal, std::mem::size_of::<u32>());
+
+        // A single star never gets a parent written by tree_build.wgsl
+        let leaf_parents = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("LBVH Leaf Parents"),
+                contents: bytemuck::cast_slice(&vec![NONE; n.max(1) as usize]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            });
+
+        let params: Vec<Buffer> = (0..passes as u32)
+            .map(|p| {
+                ctx.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Tree Params Uniform"),
+                        contents: bytemuck::cast_slice(&[TreeParams {
+                            n,
+                            bits_per_axis: bits.per_axis(),
+                            num_tiles,
+                            shift: p * RADIX_BITS,
+                        }]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    })
+            })
+            .collect();
+
+        let hist_group = |p: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&keys[p % 2], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&hist_buf, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[p])})
+        };
+
+        let scatter_group = |p: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&keys[p % 2], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&values[p % 2], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&keys[1 - p % 2], false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&values[1 - p % 2], false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&hist_buf, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[p])})
+        };
+
+        let bounds_group = |s: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[s], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&bounds_buf, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[0])})
+        };
+
+        let morton_group = |s: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[s], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&bounds_buf, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&keys[0], false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&values[0], false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[0])})
+        };
+
+        let bg_scan = BindgroupBuilder::new()
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&hist_buf, false)})
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[0])});
+
+        let bg_build = BindgroupBuilder::new()
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&keys[sorted], true)})
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&nodes, false)})
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&leaf_parents, false)})
+            .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[0])});
+
+        let reduce_group = |s: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[s], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&values[sorted], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&nodes, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&leaf_parents, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&node_data, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&leaf_data, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&merged, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&params[0])})
+        };
+
+        let level_unifs: Vec<Buffer> = (1..=bits.max_depth(n))
+            .map(|level| {
+                ctx.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Reduce Level Uniform"),
+                        contents: bytemuck::cast_slice(&[ReduceLevel { level, _pad: [0; 3] }]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    })
+            })
+            .collect();
+
+        let level_group = |l: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&level_unifs[l])})
+        };
+
+        Self {
+            n,
+            bits,
+            num_tiles,
+            bounds: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/tree_bounds.wgsl"))
+                    .bind_group(&ctx.device, bounds_group(0))
+                    .name("Tree Bounds Pipeline")
+                    .build(&ctx.device)
+            },
+            morton: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/tree_morton.wgsl"))
+                    .bind_group(&ctx.device, morton_group(0))
+                    .name("Morton Code Pipeline")
+                    .build(&ctx.device)
+            },
+            hist: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/radix_hist.wgsl"))
+                    .bind_group(&ctx.device, hist_group(0))
+                    .name("Radix Histogram Pipeline")
+                    .build(&ctx.device)
+            },
+            scan: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/radix_scan.wgsl"))
+                    .bind_group(&ctx.device, bg_scan)
+                    .name("Radix Scan Pipeline")
+                    .build(&ctx.device)
+            },
+            scatter: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/radix_scatter.wgsl"))
+                    .bind_group(&ctx.device, scatter_group(0))
+                    .name("Radix Scatter Pipeline")
+                    .build(&ctx.device)
+            },
+            build: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/tree_build.wgsl"))
+                    .bind_group(&ctx.device, bg_build)
+                    .name("LBVH Build Pipeline")
+                    .build(&ctx.device)
+            },
+            leaves: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/tree_reduce.wgsl"))
+                    .entry("leaves")
+                    .bind_group(&ctx.device, reduce_group(0))
+                    .name("LBVH Leaves Pipeline")
+                    .build(&ctx.device)
+            },
+            reduce: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/tree_reduce.wgsl"))
+                    .bind_group(&ctx.device, reduce_group(0))
+                    .bind_group(&ctx.device, level_group(0))
+                    .name("LBVH Reduce Pipeline")
+                    .build(&ctx.device)
+            },
+            bounds_groups: (0..sources.len()).map(|s| bounds_group(s).build(&ctx.device).0).collect(),
+            morton_groups: (0..sources.len()).map(|s| morton_group(s).build(&ctx.device).0).collect(),
+            reduce_groups: (0..sources.len()).map(|s| reduce_group(s).build(&ctx.device).0).collect(),
+            level_groups: (0..level_unifs.len()).map(|l| level_group(l).build(&ctx.device).0).collect(),
+            stars: None,
+            hist_groups: (0..passes).map(|p| hist_group(p).build(&ctx.device).0).collect(),
+            scatter_groups: (0..passes).map(|p| scatter_group(p).build(&ctx.device).0).collect(),
+            keys,
+            values,
+            sorted,
+            nodes,
+            leaf_parents,
+            node_data,
+            leaf_data,
+            merged,
+        }
+    }
+
+    pub fn bits(&self) -> MortonBits {
+        self.bits
+    }
+
+    // Star index of each sorted leaf
+    pub fn order(&self) -> &Buffer {
+        &self.values[self.sorted]
+    }
+
+    // Rebuilds the tree over sources[src]
+    pub fn rebuild(&self, encoder: &mut CommandEncoder, src: usize) {
+        encoder.clear_buffer(&self.merged, 0, None);
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Octree Compute Pass")
+        });
+
+        let groups = |count: u32| (count + 63) / 64;
+
+        self.bounds.bind_with(&mut compute_pass, 0, &self.bounds_groups[src]);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+
+        self.morton.bind_with(&mut compute_pass, 0, &self.morton_groups[src]);
+        compute_pass.dispatch_workgroups(groups(self.n), 1, 1);
+
+        for (hist, scatter) in self.hist_groups.iter().zip(&self.scatter_groups) {
+            self.hist.bind_with(&mut compute_pass, 0, hist);
+            compute_pass.dispatch_workgroups(groups(self.num_tiles), 1, 1);
+
+            self.scan.bind(&mut compute_pass);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+
+            self.scatter.bind_with(&mut compute_pass, 0, scatter);
+            compute_pass.dispatch_workgroups(groups(self.num_tiles), 1, 1);
+        }
+
+        self.build.bind(&mut compute_pass);
+        compute_pass.dispatch_workgroups(groups(self.n.saturating_sub(1)), 1, 1);
+
+        self.leaves.bind_with(&mut compute_pass, 0, &self.reduce_groups[src]);
+        compute_pass.dispatch_workgroups(groups(self.n), 1, 1);
+
+        for level in &self.level_groups {
+            self.reduce.bind_with(&mut compute_pass, 0, &self.reduce_groups[src]);
+            compute_pass.set_bind_group(1, level, &[]);
+            compute_pass.dispatch_workgroups(groups(self.n.saturating_sub(1)), 1, 1);
+        }
+    }
+
+    // Blocking readback of everything the pass produced, in the same shape as the CPU reference
+    pub fn read_back(&self, ctx: &GpuContext) -> Lbvh {
+        let read_u32 = |b: &Buffer| read_buffer::<u32>(&ctx.device, &ctx.command_queue, b);
+        let n = self.n as usize;
+        let n_internal = n.saturating_sub(1);
+
+        let keys = read_buffer::<[u32; 2]>(&ctx.device, &ctx.command_queue, &self.keys[self.sorted])
+            .iter()
+            .take(n)
+            .map(|k| (k[0] as u64) << 32 | k[1] as u64)
+            .collect();
+
+        let mut order = read_u32(&self.values[self.sorted]);
+        order.truncate(n);
+        let mut leaf_parents = read_u32(&self.leaf_parents);
+        leaf_parents.truncate(n);
+
+        let mut nodes = read_buffer::<LbvhNode>(&ctx.device, &ctx.command_queue, &self.nodes);
+        nodes.truncate(n_internal);
+        let mut node_data = read_buffer::<NodeData>(&ctx.device, &ctx.command_queue, &self.node_data);
+        node_data.truncate(n_internal);
+        let leaf_data = read_buffer::<NodeData>(&ctx.device, &ctx.command_queue, &self.leaf_data);
+
+        Lbvh { keys, order, nodes, leaf_parents, node_data, leaf_data }
+    }
+}
+
+impl ComputePass<GpuContext> for OctreePass {
+    fn exec(
+        &self,
+        _ctx: &GpuContext,
+        encoder: &mut CommandEncoder
+    ) {
+        let stars = self.stars.as_ref().expect("a tree made by OctreePass::over has no star buffer to follow");
+        self.rebuild(encoder, stars.index());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+    use crate::{app::star_buffer, simulation::ics::{IcParams, Model}};
+
+    #[test]
+    fn matches_cpu_reference() {
+        let ctx = pollster::block_on(GpuContext::headless());
+        let mut stars = Model::Plummer.generate(&IcParams { n: 5000, mass: 1.0, scale_radius: 1.0, seed: 3 }, 1.0);
+        // Duplicate codes, which only the index tie break keeps apart
+        stars.extend_from_within(..100);
+        let bufs = star_buffer(&ctx, &stars);
+
+        for bits in [MortonBits::Bits30, MortonBits::Bits63] {
+            let pass = OctreePass::new(&ctx, bufs.clone(), bits);
+            let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            pass.exec(&ctx, &mut encoder);
+            ctx.command_queue.submit(iter::once(encoder.finish()));
+
+            let mismatch = Lbvh::build(&stars, bits).mismatch(&pass.read_back(&ctx), 1.0E-5);
+            assert!(mismatch.is_none(), "{:?} bits: {}", bits, mismatch.unwrap());
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use wgpu::{include_wgsl, util::DeviceExt, BindGroup, Buffer, CommandEncoder, ShaderStages};
+
+use crate::{
+    app::GpuContext,
+    pipelines::{BindgroupBuilder, Binding, BindingResource, ComputePipeline, ComputePipelineBuilder},
+    simulation::pm::{self, FftParams, PmParams},
+};
+
+// Particle-mesh forces, for Solver::ParticleMesh. IntegratePass owns one and calls solve
+// wherever the direct solver would run accel.wgsl, TreePmPass uses one for the long range.
+// See simulation::pm for the CPU reference.
+//
+// The mesh is refitted around the stars on every evaluation and zero padded to twice its size,
+// so the FFT convolution sees isolated boundaries rather than periodic ones. The cost is linear
+// in the stars plus size^3 log size for the FFTs, whatever the clustering. A few escapers
+// stretch the mesh and coarsen it for everyone else, so it suits compact or uniform systems best.
+pub struct ParticleMeshPass {
+    params: PmParams,
+    bounds: ComputePipeline,
+    assign: ComputePipeline,
+    load: ComputePipeline,
+    fft: ComputePipeline,
+    convolve: ComputePipeline,
+    gradient: ComputePipeline,
+    interpolate: ComputePipeline,
+    // Indexed like IntegratePass's sources, the two star buffers then the trial one
+    groups: Vec<BindGroup>,
+    // Forward along x, y and z, then the inverse ones
+    fft_groups: Vec<BindGroup>,
+    density: Buffer,
+    geometry: Buffer,
+}
+
+impl ParticleMeshPass {
+    // `split` as in simulation::pm::greens
+    pub fn new(ctx: &GpuContext, sources: &[&Buffer], accel: &Buffer, params_unif: &Buffer, mesh: u32, split: Option<f64>) -> Self {
+        let params = PmParams::new(mesh);
+        let (mesh, size) = (mesh as u64, params.size as u64);
+
+        let grid_size = size * size * size * std::mem::size_of::<[f32; 2]>() as u64;
+        let limit = ctx.device.limits().max_storage_buffer_binding_size as u64;
+        assert!(grid_size <= limit, "a PM mesh of {} needs a {} byte grid, this device binds {} at most", mesh, grid_size, limit);
+
+        let storage = |label: &str, size: u64| {
+            ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+
+        let density = storage("PM Density Buffer", mesh * mesh * mesh * 4);
+        let grid = storage("PM Grid Buffer", grid_size);
+        let field = storage("PM Field Buffer", mesh * mesh * mesh * 16);
+        let geometry = storage("PM Geometry Buffer", 32);
+
+        let greens: Vec<f32> = pm::greens(params.mesh, split).into_iter().map(|g| g as f32).collect();
+        let greens = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("PM Green's Function Buffer"),
+                contents: bytemuck::cast_slice(&greens),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let pm_unif = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("PM Params Uniform"),
+                contents: bytemuck::cast_slice(&[params]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let fft_unifs: Vec<Buffer> = [0, 1]
+            .into_iter()
+            .flat_map(|inverse| (0..3).map(move |axis| FftParams { axis, inverse, _pad: [0; 2] }))
+            .map(|p| {
+                ctx.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("FFT Params Uniform"),
+                        contents: bytemuck::cast_slice(&[p]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    })
+            })
+            .collect();
+
+        let group = |src: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(sources[src], true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(accel, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&density, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&grid, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&greens, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&field, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&geometry, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(params_unif)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&pm_unif)})
+        };
+
+        let fft_group = |i: usize| {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&fft_unifs[i])})
+        };
+
+        let stage = |entry: &str, name: &str| {
+            ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/pm.wgsl"))
+                .entry(entry)
+                .bind_group(&ctx.device, group(0))
+                .name(name)
+                .build(&ctx.device)
+        };
+
+        Self {
+            params,
+            bounds: stage("bounds", "PM Bounds Pipeline"),
+            assign: stage("assign", "PM Assign Pipeline"),
+            load: stage("load", "PM Load Pipeline"),
+            fft: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/pm.wgsl"))
+                    .entry("fft")
+                    .bind_group(&ctx.device, group(0))
+                    .bind_group(&ctx.device, fft_group(0))
+                    .name("PM FFT Pipeline")
+                    .build(&ctx.device)
+            },
+            convolve: stage("convolve", "PM Convolve Pipeline"),
+            gradient: stage("gradient", "PM Gradient Pipeline"),
+            interpolate: stage("interpolate", "PM Interpolate Pipeline"),
+            groups: (0..sources.len()).map(|s| group(s).build(&ctx.device).0).collect(),
+            fft_groups: (0..fft_unifs.len()).map(|i| fft_group(i).build(&ctx.device).0).collect(),
+            density,
+            geometry,
+        }
+    }
+
+    // Where solve last put the mesh, `struct Geometry` in pm.wgsl
+    pub fn geometry(&self) -> &Buffer {
+        &self.geometry
+    }
+
+    // Fills `accel` with the forces on the first `n_stars` of sources[src]
+    pub fn solve(&self, encoder: &mut CommandEncoder, src: usize, n_stars: u32) {
+        encoder.clear_buffer(&self.density, 0, None);
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("PM Compute Pass")
+        });
+
+        let group = &self.groups[src];
+        let PmParams { mesh, size, .. } = self.params;
+        let stars = n_stars.div_ceil(64);
+
+        self.bounds.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+
+        self.assign.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(stars, 1, 1);
+
+        // One invocation per cell, a plane of them per row of workgroups
+        self.load.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(size * size / 64, size, 1);
+
+        let (forward, inverse) = self.fft_groups.split_at(3);
+        for fft in forward {
+            self.fft.bind_with(&mut compute_pass, 0, group);
+            compute_pass.set_bind_group(1, fft, &[]);
+            compute_pass.dispatch_workgroups(size / 8, size / 8, size / 8);
+        }
+
+        self.convolve.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(size * size / 64, size, 1);
+
+        for fft in inverse {
+            self.fft.bind_with(&mut compute_pass, 0, group);
+            compute_pass.set_bind_group(1, fft, &[]);
+            compute_pass.dispatch_workgroups(size / 8, size / 8, size / 8);
+        }
+
+        self.gradient.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(mesh * mesh / 64, mesh, 1);
+
+        self.interpolate.bind_with(&mut compute_pass, 0, group);
+        compute_pass.dispatch_workgroups(stars, 1, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::GpuContext,
+        headless::Simulation,
+        simulation::{direct::relative_errors, ics::{IcParams, Model}, params::{SimParams, SofteningKernel}, pm, Integrator, Solver},
+    };
+
+    #[test]
+    fn matches_cpu_reference() {
+        let stars = Model::Plummer.generate(&IcParams { n: 4096, mass: 1.0, scale_radius: 1.0, seed: 3 }, 1.0);
+        let params = SimParams::new(4096).gravity(1.0).softening(0.05, SofteningKernel::Plummer);
+        for mesh in [32, 64] {
+            let ctx = pollster::block_on(GpuContext::headless());
+            let sim = Simulation::new(ctx, stars.clone(), Solver::ParticleMesh { mesh }, Integrator::Euler, params);
+            let errors = relative_errors(&sim.forces(), &pm::accelerations(&stars, mesh, None, &params));
+            let (median, worst) = (errors[errors.len() / 2], errors[errors.len() - 1]);
+            // Only f32 and the fixed point density apart, measured 9e-7 and 2e-5
+            assert!(median < 1.0E-5, "mesh {}: median relative error {:e}", mesh, median);
+            assert!(worst < 2.0E-4, "mesh {}: worst relative error {:e}", mesh, worst);
+        }
+    }
+}
@@ -14,6 +14,7 @@ pub trait RenderPass {
     );
 }
 
-pub trait ComputePass {
-    fn exec(&self, render_context: &RenderContext, encoder: &mut CommandEncoder);
+// Simulation passes only need a GpuContext, so they can run without a window
+pub trait ComputePass<Ctx = RenderContext> {
+    fn exec(&self, render_context: &Ctx, encoder: &mut CommandEncoder);
 }
@@ -0,0 +1,198 @@
+use std::{
+    fs,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use wgpu::{Buffer, CommandEncoder};
+
+use crate::{
+    app::GpuContext,
+    pipelines::PingPongBuffer,
+    simulation::{
+        params::SimParams,
+        snapshot::{SnapshotFormat, SnapshotHeader},
+        units::Units,
+        vtk::Pvd,
+    },
+};
+
+// How many snapshots can be in flight before new ones get dropped
+const STAGING_BUFFERS: usize = 3;
+// VTK series are listed here for ParaView, next to the files
+const COLLECTION: &str = "snapshots.pvd";
+
+struct Staging {
+    buffer: Buffer,
+    // Set once a copy has been recorded into this buffer
+    header: Option<SnapshotHeader>,
+    map_requested: bool,
+    mapped: Arc<AtomicBool>,
+}
+
+// Writes the star buffer to `dir` every `every` steps. The copy is recorded into the frame's
+// encoder and mapped asynchronously, the file is written on its own thread, so nothing waits
+// on the GPU or the disk. Native only, the web has no filesystem to write to.
+pub struct SnapshotPass {
+    stars: Rc<PingPongBuffer>,
+    every: u64,
+    dir: PathBuf,
+    format: SnapshotFormat,
+    units: Units,
+    seed: u64,
+    staging: Vec<Staging>,
+    // Encoding happens on the writer thread too
+    files: Option<Sender<(PathBuf, SnapshotHeader, Vec<u8>)>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SnapshotPass {
+    pub fn new(
+        ctx: &GpuContext,
+        stars: Rc<PingPongBuffer>,
+        every: u32,
+        dir: impl Into<PathBuf>,
+        format: SnapshotFormat,
+        units: Units,
+        seed: u64,
+    ) -> Self {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("failed to create snapshot directory {:?}: {}", dir, e));
+
+        let staging = staging(ctx, stars.size());
+
+        let (files, received) = channel::<(PathBuf, SnapshotHeader, Vec<u8>)>();
+        let collection = dir.join(COLLECTION);
+        let writer = thread::spawn(move || {
+            // Kept across runs writing to the same directory, Pvd::push replaces what gets redone
+            let mut pvd = match format {
+                SnapshotFormat::Vtk(_) => Some(Pvd::read(&collection).unwrap_or_default()),
+                _ => None,
+            };
+
+            for (path, header, stars) in received {
+                if let Err(e) = fs::write(&path, format.encode(&header, &stars)) {
+                    eprintln!("failed to write snapshot {:?}: {}", path, e);
+                    continue;
+                }
+                if let Some(pvd) = &mut pvd {
+                    pvd.push(header.time, path.file_name().unwrap().to_string_lossy());
+                    if let Err(e) = pvd.write(&collection) {
+                        eprintln!("failed to write {:?}: {}", collection, e);
+                    }
+                }
+            }
+        });
+
+        Self {
+            stars,
+            every: every.max(1) as u64,
+            dir,
+            format,
+            units,
+            seed,
+            staging,
+            files: Some(files),
+            writer: Some(writer),
+        }
+    }
+
+    // Moves over to a new star buffer, after CollisionPass shrinks it
+    pub fn rebind(&mut self, ctx: &GpuContext, stars: Rc<PingPongBuffer>) {
+        self.flush(ctx);
+        self.staging = staging(ctx, stars.size());
+        self.stars = stars;
+    }
+
+    // Records a copy of the current star state if `step` is due for a snapshot.
+    // Has to go in before the integrate pass so the file matches `step` and `time`.
+    pub fn capture(&mut self, encoder: &mut CommandEncoder, step: u64, time: f64, params: SimParams) {
+        if step % self.every != 0 {
+            return;
+        }
+
+        let Some(slot) = self.staging.iter_mut().find(|s| s.header.is_none()) else {
+            eprintln!("dropping the snapshot at step {}, the last {} haven't been written yet", step, STAGING_BUFFERS);
+            return;
+        };
+
+        let stars = self.stars.current();
+        encoder.copy_buffer_to_buffer(stars, 0, &slot.buffer, 0, stars.size());
+        slot.header = Some(SnapshotHeader::new(step, time, &self.units, params, self.seed));
+    }
+
+    // Call after submitting the encoder passed to capture. Hands finished copies to the writer
+    pub fn poll(&mut self, ctx: &GpuContext) {
+        for slot in self.staging.iter_mut().filter(|s| s.header.is_some() && !s.map_requested) {
+            let mapped = slot.mapped.clone();
+            slot.buffer.slice(..).map_async(wgpu::MapMode::Read, move |res| {
+                res.expect("failed to map snapshot staging buffer");
+                mapped.store(true, Ordering::Release);
+            });
+            slot.map_requested = true;
+        }
+
+        ctx.device.poll(wgpu::Maintain::Poll);
+
+        for slot in self.staging.iter_mut().filter(|s| s.mapped.load(Ordering::Acquire)) {
+            let header = slot.header.take().unwrap();
+            let data = slot.buffer.slice(..).get_mapped_range().to_vec();
+            slot.buffer.unmap();
+            slot.map_requested = false;
+            slot.mapped.store(false, Ordering::Release);
+
+            let path = self.dir.join(self.format.file_name(header.step));
+            if let Some(files) = &self.files {
+                files.send((path, header, data)).expect("snapshot writer thread died");
+            }
+        }
+    }
+
+    // Blocks until there's room for another capture, for batch runs that mustn't drop any
+    pub fn reserve(&mut self, ctx: &GpuContext) {
+        while self.staging.iter().all(|s| s.header.is_some()) {
+            self.poll(ctx);
+            ctx.device.poll(wgpu::Maintain::Wait);
+        }
+    }
+
+    // Blocks until every captured snapshot is with the writer
+    pub fn flush(&mut self, ctx: &GpuContext) {
+        while self.staging.iter().any(|s| s.header.is_some()) {
+            self.poll(ctx);
+            ctx.device.poll(wgpu::Maintain::Wait);
+        }
+    }
+}
+
+fn staging(ctx: &GpuContext, size: u64) -> Vec<Staging> {
+    (0..STAGING_BUFFERS)
+        .map(|_| Staging {
+            buffer: ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Snapshot Staging Buffer"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            header: None,
+            map_requested: false,
+            mapped: Arc::new(AtomicBool::new(false)),
+        })
+        .collect()
+}
+
+impl Drop for SnapshotPass {
+    // Lets the writer finish the files it already has
+    fn drop(&mut self) {
+        self.files.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
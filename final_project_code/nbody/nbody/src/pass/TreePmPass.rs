@@ -0,0 +1,100 @@
+use wgpu::{include_wgsl, util::DeviceExt, BindGroup, Buffer, CommandEncoder, ShaderStages};
+
+use crate::{
+    app::GpuContext,
+    pipelines::{BindgroupBuilder, Binding, BindingResource, ComputePipeline, ComputePipelineBuilder},
+    simulation::treepm::{ShortRangeParams, SPLIT, TREE_BITS},
+};
+
+use super::{OctreePass::OctreePass, ParticleMeshPass::ParticleMeshPass};
+
+// TreePM forces, for Solver::TreePm. The mesh takes the long range with a Gaussian filtered
+// Green's function, then a walk of the GPU tree adds the erfc filtered short range out to a few
+// cells. See simulation::treepm for the CPU reference.
+//
+// The split follows the mesh, which is refitted on every evaluation, so the walk reads the cell
+// size from the PM geometry buffer rather than anything fixed up front.
+pub struct TreePmPass {
+    mesh: ParticleMeshPass,
+    tree: OctreePass,
+    walk: ComputePipeline,
+    group: BindGroup,
+}
+
+impl TreePmPass {
+    pub fn new(ctx: &GpuContext, sources: &[&Buffer], accel: &Buffer, params_unif: &Buffer, mesh: u32, theta: f32) -> Self {
+        let mesh = ParticleMeshPass::new(ctx, sources, accel, params_unif, mesh, Some(SPLIT));
+        let tree = OctreePass::over(ctx, sources, TREE_BITS);
+
+        let short_unif = ctx
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Short Range Params Uniform"),
+                contents: bytemuck::cast_slice(&[ShortRangeParams::new(theta)]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        // The tree's buffers are the same whichever source it was built over
+        let group = || {
+            BindgroupBuilder::new()
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(accel, false)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(tree.order(), true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&tree.nodes, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&tree.leaf_parents, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&tree.node_data, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(&tree.leaf_data, true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Buffer(mesh.geometry(), true)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(params_unif)})
+                .resource( Binding { vis: ShaderStages::COMPUTE, res: BindingResource::Uniform(&short_unif)})
+        };
+
+        Self {
+            walk: {
+                ComputePipelineBuilder::new(&ctx.device, include_wgsl!("../../shaders/treepm.wgsl"))
+                    .bind_group(&ctx.device, group())
+                    .name("Short Range Walk Pipeline")
+                    .build(&ctx.device)
+            },
+            group: group().build(&ctx.device).0,
+            mesh,
+            tree,
+        }
+    }
+
+    // Fills `accel` with the forces on the first `n_stars` of sources[src]
+    pub fn solve(&self, encoder: &mut CommandEncoder, src: usize, n_stars: u32) {
+        self.tree.rebuild(encoder, src);
+        self.mesh.solve(encoder, src, n_stars);
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Short Range Compute Pass")
+        });
+
+        self.walk.bind_with(&mut compute_pass, 0, &self.group);
+        compute_pass.dispatch_workgroups(n_stars.div_ceil(64), 1, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::GpuContext,
+        headless::Simulation,
+        simulation::{direct::relative_errors, ics::{IcParams, Model}, params::{SimParams, SofteningKernel}, treepm, Integrator, Solver},
+    };
+
+    #[test]
+    fn matches_cpu_reference() {
+        let stars = Model::Plummer.generate(&IcParams { n: 4096, mass: 1.0, scale_radius: 1.0, seed: 4 }, 1.0);
+        let params = SimParams::new(4096).gravity(1.0).softening(0.05, SofteningKernel::Plummer);
+        for theta in [0.0, 0.5] {
+            let ctx = pollster::block_on(GpuContext::headless());
+            let sim = Simulation::new(ctx, stars.clone(), Solver::TreePm { mesh: 32, theta }, Integrator::Euler, params);
+            let errors = relative_errors(&sim.forces(), &treepm::accelerations(&stars, 32, theta, &params));
+            let (median, worst) = (errors[errors.len() / 2], errors[errors.len() - 1]);
+            // Same mesh, same tree and the same walk, measured 7e-7 and 4e-6
+            assert!(median < 1.0E-5, "theta {}: median relative error {:e}", theta, median);
+            assert!(worst < 1.0E-4, "theta {}: worst relative error {:e}", theta, worst);
+        }
+    }
+}
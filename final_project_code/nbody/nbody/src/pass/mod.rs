@@ -1,6 +1,12 @@
+pub mod CollisionPass;
 pub mod ColorPass;
+pub mod DiagnosticsPass;
 pub mod PPFXPass;
 pub mod RenderPass;
 pub mod BlitPass;
 pub mod IntegratePass;
+pub mod OctreePass;
+pub mod ParticleMeshPass;
+pub mod SnapshotPass;
+pub mod TreePmPass;
 // pub mod UIPass;
@@ -1,7 +1,8 @@
-use std::rc::Rc;
+use std::{cell::Cell, rc::Rc};
 
+use bytemuck::Pod;
 use wgpu::{
-    include_wgsl, BindGroup, BindGroupLayout, Buffer, ComputePass, Device, PrimitiveTopology, RenderPass, Sampler, ShaderModule,
+    util::DeviceExt, include_wgsl, BindGroup, BindGroupLayout, Buffer, ComputePass, Device, PrimitiveTopology, Queue, RenderPass, Sampler, ShaderModule,
     ShaderModuleDescriptor, TextureFormat, VertexBufferLayout, StorageTextureAccess, ShaderModuleDescriptorSpirV,
 };
 
@@ -24,6 +25,61 @@ impl RenderPipeline {
             rp.set_vertex_buffer(i as u32, vb.slice(..))
         }
     }
+
+    // Same as bind, but with `vb` in vertex buffer slot `slot`
+    pub fn bind_vertex_with<'a>(&'a self, rp: &mut RenderPass<'a>, slot: u32, vb: &'a Buffer) {
+        self.bind(rp);
+        rp.set_vertex_buffer(slot, vb.slice(..));
+    }
+}
+
+// Two buffers holding consecutive states of the same data. A pass reads `current`,
+// writes `next`, then flips, so nothing reads a buffer the same dispatch is writing.
+pub struct PingPongBuffer {
+    bufs: [Rc<Buffer>; 2],
+    current: Cell<usize>,
+}
+
+impl PingPongBuffer {
+    // Both halves start out with `contents`
+    pub fn new(device: &Device, label: &str, contents: &[u8], usage: wgpu::BufferUsages) -> Self {
+        let make = || {
+            Rc::new(device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage,
+            }))
+        };
+
+        Self {
+            bufs: [make(), make()],
+            current: Cell::new(0),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.current.get()
+    }
+
+    pub fn get(&self, index: usize) -> &Rc<Buffer> {
+        &self.bufs[index]
+    }
+
+    pub fn current(&self) -> &Rc<Buffer> {
+        &self.bufs[self.index()]
+    }
+
+    pub fn next(&self) -> &Rc<Buffer> {
+        &self.bufs[1 - self.index()]
+    }
+
+    pub fn flip(&self) {
+        self.current.set(1 - self.index());
+    }
+
+    pub fn size(&self) -> u64 {
+        self.bufs[0].size()
+    }
 }
 
 pub enum BindingResource<'a> {
@@ -311,6 +367,7 @@ pub struct ComputePipelineBuilder<'a> {
     bg_layouts: Vec<BindGroupLayout>,
     bgs: Vec<BindGroup>,
     shader: ShaderModule,
+    entry: &'a str,
     vertex_buffers: Vec<Rc<Buffer>>,
     vertex_buffer_layouts: Vec<VertexBufferLayout<'a>>,
     topo: wgpu::PrimitiveTopology,
@@ -331,6 +388,37 @@ impl ComputePipeline {
             rp.set_bind_group(i as u32, bg, &[]);
         }
     }
+
+    // Same as bind, but with `bg` swapped in at `index`. Used by passes that ping-pong buffers
+    // between dispatches, `bg` has to come from a BindgroupBuilder with the same layout.
+    pub fn bind_with<'a>(&'a self, rp: &mut ComputePass<'a>, index: u32, bg: &'a BindGroup) {
+        self.bind(rp);
+        rp.set_bind_group(index, bg, &[]);
+    }
+}
+
+// Blocking copy of a GPU buffer back to the CPU, the buffer needs COPY_SRC
+pub fn read_buffer<T: Pod>(device: &Device, queue: &Queue, buffer: &Buffer) -> Vec<T> {
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Staging Buffer"),
+        size: buffer.size(),
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = staging.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |res| res.expect("failed to map readback buffer"));
+    device.poll(wgpu::Maintain::Wait);
+
+    let out = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    staging.unmap();
+    out
 }
 
 impl<'a> ComputePipelineBuilder<'a> {
@@ -339,6 +427,7 @@ impl<'a> ComputePipelineBuilder<'a> {
             bg_layouts: vec![],
             bgs: vec![],
             shader: device.create_shader_module(module),
+            entry: "cs_main",
             vertex_buffers: vec![],
             vertex_buffer_layouts: vec![],
             topo: wgpu::PrimitiveTopology::TriangleList,
@@ -351,6 +440,7 @@ impl<'a> ComputePipelineBuilder<'a> {
             bg_layouts: vec![],
             bgs: vec![],
             shader: unsafe { device.create_shader_module_spirv(&module) },
+            entry: "cs_main",
             vertex_buffers: vec![],
             vertex_buffer_layouts: vec![],
             topo: wgpu::PrimitiveTopology::TriangleList,
@@ -377,6 +467,12 @@ impl<'a> ComputePipelineBuilder<'a> {
         self
     }
 
+    // For shaders holding several kernels, defaults to cs_main
+    pub fn entry(mut self, entry: &'a str) -> Self {
+        self.entry = entry;
+        self
+    }
+
     pub fn vertex_buffer(mut self, layout: VertexBufferLayout<'a>, buffer: Rc<Buffer>) -> Self {
         self.vertex_buffer_layouts.push(layout);
         self.vertex_buffers.push(buffer);
@@ -399,7 +495,7 @@ impl<'a> ComputePipelineBuilder<'a> {
                 })
             }),
             module: &self.shader,
-            entry_point: "cs_main",
+            entry_point: self.entry,
         });
 
         ComputePipeline {
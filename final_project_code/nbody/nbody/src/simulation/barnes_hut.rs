@@ -0,0 +1,252 @@
+use super::{params::{SimParams, SofteningKernel}, star::Star};
+
+// Leaves hold up to this many stars before they get split.
+const LEAF_SIZE: usize = 8;
+// Stops coincident stars from splitting the tree forever.
+const MAX_DEPTH: u32 = 32;
+// A missing child in Node::children
+pub const EMPTY: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub center: [f64; 3],
+    pub half_width: f64,
+    pub com: [f64; 3],
+    pub mass: f64,
+    pub children: [u32; 8],
+    // Range of `Octree::order` covered by this node
+    pub first: u32,
+    pub count: u32,
+}
+
+impl Node {
+    pub fn is_leaf(&self) -> bool {
+        self.children.iter().all(|c| *c == EMPTY)
+    }
+}
+
+pub struct Octree {
+    pub nodes: Vec<Node>,
+    pub order: Vec<u32>,
+    // Positions/masses are kept in f64, m * x overflows f32 with our masses
+    pos: Vec<[f64; 3]>,
+    mass: Vec<f64>,
+}
+
+impl Octree {
+    pub fn build(stars: &[Star]) -> Self {
+        let pos: Vec<[f64; 3]> = stars
+            .iter()
+            .map(|s| [s.x as f64, s.y as f64, s.z as f64])
+            .collect();
+        let mass = stars.iter().map(|s| s.mass as f64).collect();
+
+        let mut lo = [f64::MAX; 3];
+        let mut hi = [f64::MIN; 3];
+        for p in &pos {
+            for k in 0..3 {
+                lo[k] = lo[k].min(p[k]);
+                hi[k] = hi[k].max(p[k]);
+            }
+        }
+        if pos.is_empty() {
+            lo = [0.0; 3];
+            hi = [0.0; 3];
+        }
+
+        let center = [
+            (lo[0] + hi[0]) / 2.0,
+            (lo[1] + hi[1]) / 2.0,
+            (lo[2] + hi[2]) / 2.0,
+        ];
+        let half_width = (0..3)
+            .map(|k| (hi[k] - lo[k]) / 2.0)
+            .fold(0.0, f64::max)
+            .max(f64::MIN_POSITIVE);
+
+        let mut tree = Self {
+            nodes: vec![],
+            order: (0..stars.len() as u32).collect(),
+            pos,
+            mass,
+        };
+        tree.build_node(0, stars.len(), center, half_width, 0);
+        tree
+    }
+
+    fn build_node(&mut self, first: usize, count: usize, center: [f64; 3], half_width: f64, depth: u32) -> u32 {
+        let id = self.nodes.len() as u32;
+
+        let mut mass = 0.0;
+        let mut com = [0.0; 3];
+        for &i in &self.order[first..first + count] {
+            let i = i as usize;
+            mass += self.mass[i];
+            for k in 0..3 {
+                com[k] += self.mass[i] * self.pos[i][k];
+            }
+        }
+        let com = if mass > 0.0 {
+            [com[0] / mass, com[1] / mass, com[2] / mass]
+        } else {
+            center
+        };
+
+        self.nodes.push(Node {
+            center,
+            half_width,
+            com,
+            mass,
+            children: [EMPTY; 8],
+            first: first as u32,
+            count: count as u32,
+        });
+
+        if count <= LEAF_SIZE || depth >= MAX_DEPTH {
+            return id;
+        }
+
+        // Counting sort the node's stars by octant so each child gets a contiguous range
+        let octant = |p: &[f64; 3]| {
+            (p[0] >= center[0]) as usize
+                | ((p[1] >= center[1]) as usize) << 1
+                | ((p[2] >= center[2]) as usize) << 2
+        };
+
+        let mut counts = [0usize; 8];
+        for &i in &self.order[first..first + count] {
+            counts[octant(&self.pos[i as usize])] += 1;
+        }
+
+        let mut offsets = [0usize; 8];
+        for o in 1..8 {
+            offsets[o] = offsets[o - 1] + counts[o - 1];
+        }
+
+        let mut cursor = offsets;
+        let mut sorted = vec![0u32; count];
+        for &i in &self.order[first..first + count] {
+            let o = octant(&self.pos[i as usize]);
+            sorted[cursor[o]] = i;
+            cursor[o] += 1;
+        }
+        self.order[first..first + count].copy_from_slice(&sorted);
+
+        let h = half_width / 2.0;
+        for o in 0..8 {
+            if counts[o] == 0 {
+                continue;
+            }
+
+            let child_center = [
+                center[0] + if o & 1 != 0 { h } else { -h },
+                center[1] + if o & 2 != 0 { h } else { -h },
+                center[2] + if o & 4 != 0 { h } else { -h },
+            ];
+            let child = self.build_node(first + offsets[o], counts[o], child_center, h, depth + 1);
+            self.nodes[id as usize].children[o] = child;
+        }
+
+        id
+    }
+
+    // Force on star `target`, opening any node whose width is larger than theta times the
+    // distance to its nearest possible star, as seen from the center of mass. Nodes around the
+    // target are always opened, so no star is pulled by its own mass
+    pub fn accel(&self, target: usize, theta: f64, g: f64, softening: f64, kernel: SofteningKernel) -> [f64; 3] {
+        let p = self.pos[target];
+
+        let mut a = [0.0; 3];
+        let mut add = |q: &[f64; 3], m: f64| {
+            let v = [q[0] - p[0], q[1] - p[1], q[2] - p[2]];
+            let r2 = v[0] * v[0] + v[1] * v[1] + v[2] * v[2];
+            let k = g * m * kernel.inv_r3(r2, softening);
+            a[0] += k * v[0];
+            a[1] += k * v[1];
+            a[2] += k * v[2];
+        };
+
+        let mut stack = Vec::with_capacity(8 * MAX_DEPTH as usize);
+        stack.push(0u32);
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n as usize];
+
+            if node.is_leaf() {
+                let range = node.first as usize..(node.first + node.count) as usize;
+                for &j in &self.order[range] {
+                    if j as usize != target {
+                        add(&self.pos[j as usize], self.mass[j as usize]);
+                    }
+                }
+                continue;
+            }
+
+            let d = [node.com[0] - p[0], node.com[1] - p[1], node.com[2] - p[2]];
+            let r = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+            let o = [node.com[0] - node.center[0], node.com[1] - node.center[1], node.com[2] - node.center[2]];
+            let offset = (o[0] * o[0] + o[1] * o[1] + o[2] * o[2]).sqrt();
+            let contains = (0..3).all(|k| (p[k] - node.center[k]).abs() <= node.half_width);
+
+            if !contains && r * theta > 2.0 * node.half_width + offset * theta {
+                add(&node.com, node.mass);
+            } else {
+                stack.extend(node.children.iter().filter(|c| **c != EMPTY));
+            }
+        }
+
+        a
+    }
+}
+
+// Builds a tree over `stars` and returns the acceleration of every star, padded to vec4 for upload
+pub fn accelerations(stars: &[Star], theta: f32, params: &SimParams) -> Vec<[f32; 4]> {
+    let tree = Octree::build(stars);
+    let (theta, g, softening, kernel) = (theta as f64, params.g as f64, params.softening as f64, params.kernel());
+
+    let mut out = vec![[0.0f32; 4]; stars.len()];
+    let eval = |first: usize, chunk: &mut [[f32; 4]]| {
+        for (k, a) in chunk.iter_mut().enumerate() {
+            let f = tree.accel(first + k, theta, g, softening, kernel);
+            *a = [f[0] as f32, f[1] as f32, f[2] as f32, 0.0];
+        }
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_len = ((out.len() + workers - 1) / workers).max(1);
+
+        std::thread::scope(|s| {
+            for (c, chunk) in out.chunks_mut(chunk_len).enumerate() {
+                let eval = &eval;
+                s.spawn(move || eval(c * chunk_len, chunk));
+            }
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    eval(0, &mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{direct::{direct_accelerations, relative_errors}, ics::{IcParams, Model}};
+
+    #[test]
+    fn matches_direct_sum() {
+        let stars = Model::Plummer.generate(&IcParams { n: 2000, mass: 1.0, scale_radius: 1.0, seed: 7 }, 1.0);
+        let params = SimParams::new(stars.len() as u32).gravity(1.0).softening(0.01, SofteningKernel::Plummer);
+
+        let exact = direct_accelerations(&stars, &params);
+        let errors = relative_errors(&accelerations(&stars, 0.5, &params), &exact);
+        let median = errors[errors.len() / 2];
+        assert!(median < 1.0E-2, "median relative error {} at theta 0.5", median);
+
+        // Opening angles this wide used to let a star feel a monopole with its own mass in it
+        let errors = relative_errors(&accelerations(&stars, 1.0, &params), &exact);
+        assert!(errors[errors.len() / 2] < 5.0E-2, "median relative error {} at theta 1", errors[errors.len() / 2]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs, io,
+    mem::size_of,
+    path::Path,
+};
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    params::SimParams,
+    snapshot::{self, invalid, SnapshotHeader},
+    star::Star,
+    units::Units,
+    Integrator, Solver,
+};
+
+pub const MAGIC: [u8; 8] = *b"NBODYRES";
+pub const VERSION: u32 = 1;
+
+// Force history the integrators carry from one step to the next, one vec4 per star.
+// Empty when the integrator doesn't use it.
+#[derive(Clone, Debug, Default)]
+pub struct IntegratorState {
+    // Whether accel already holds the forces at the current state
+    pub primed: bool,
+    pub accel: Vec<[f32; 4]>,
+    pub jerk: Vec<[f32; 4]>,
+}
+
+impl IntegratorState {
+    // Keeps the first `n` stars, after the star buffer shrinks
+    pub fn truncate(&mut self, n: usize) {
+        self.accel.truncate(n);
+        self.jerk.truncate(n);
+    }
+}
+
+// Follows the stars of a checkpoint, then come `arrays` per-star vec4 arrays (accel, then jerk)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct ResumeHeader {
+    magic: [u8; 8],
+    version: u32,
+    header_bytes: u32,
+    solver: u32,
+    theta: f32,
+    integrator: u32,
+    primed: u32,
+    frame_cnt: f32,
+    zoom: f32,
+    arrays: u32,
+    // PM cells per side or the FMM expansion order, see Solver::to_raw
+    mesh: u32,
+}
+
+// Everything needed to continue a run exactly where it stopped. Written as a snapshot with the
+// rest appended, so anything that reads snapshots reads checkpoints too.
+pub struct Checkpoint {
+    pub header: SnapshotHeader,
+    pub stars: Vec<Star>,
+    pub solver: Solver,
+    pub integrator: Integrator,
+    pub state: IntegratorState,
+    // Camera, see RenderContext
+    pub frame_cnt: f32,
+    pub zoom: f32,
+}
+
+impl Checkpoint {
+    // A run that hasn't taken a step yet, with the camera at frame 0. `time` is 0 unless the run
+    // is comoving, see Config::start_time
+    pub fn initial(stars: Vec<Star>, units: &Units, time: f64, seed: u64, solver: Solver, integrator: Integrator, params: SimParams) -> Self {
+        Self {
+            header: SnapshotHeader::new(0, time, units, SimParams { n_stars: stars.len() as u32, ..params }, seed),
+            stars,
+            solver,
+            integrator,
+            state: IntegratorState::default(),
+            frame_cnt: 0.0,
+            zoom: 5.0,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (solver, theta, mesh) = self.solver.to_raw();
+        let arrays: Vec<&Vec<[f32; 4]>> =
+            [&self.state.accel, &self.state.jerk].into_iter().take_while(|a| !a.is_empty()).collect();
+
+        let resume = ResumeHeader {
+            magic: MAGIC,
+            version: VERSION,
+            header_bytes: size_of::<ResumeHeader>() as u32,
+            solver,
+            theta,
+            integrator: self.integrator as u32,
+            primed: self.state.primed as u32,
+            frame_cnt: self.frame_cnt,
+            zoom: self.zoom,
+            arrays: arrays.len() as u32,
+            mesh,
+        };
+
+        let mut out = self.header.encode(bytemuck::cast_slice(&self.stars));
+        out.extend_from_slice(bytemuck::bytes_of(&resume));
+        for a in arrays {
+            out.extend_from_slice(bytemuck::cast_slice(a));
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let (header, stars, rest) = snapshot::decode(data)?;
+        if rest.len() < size_of::<ResumeHeader>() || rest[..8] != MAGIC {
+            return Err(invalid("a snapshot, but not a checkpoint".to_string()));
+        }
+
+        let resume: ResumeHeader = bytemuck::pod_read_unaligned(&rest[..size_of::<ResumeHeader>()]);
+        if resume.version > VERSION {
+            return Err(invalid(format!("checkpoint version {} is newer than this build supports ({})", resume.version, VERSION)));
+        }
+
+        let solver = Solver::from_raw(resume.solver, resume.theta, resume.mesh)
+            .ok_or_else(|| invalid(format!("unknown solver {} with mesh or order {}", resume.solver, resume.mesh)))?;
+        let integrator = Integrator::from_u32(resume.integrator)
+            .ok_or_else(|| invalid(format!("unknown integrator {}", resume.integrator)))?;
+
+        if stars.is_empty() {
+            return Err(invalid("a checkpoint with no stars".to_string()));
+        }
+
+        let array_bytes = stars.len() * size_of::<[f32; 4]>();
+        let mut arrays = rest
+            .get(resume.header_bytes as usize..)
+            .ok_or_else(|| invalid("truncated checkpoint header".to_string()))?
+            .chunks(array_bytes);
+        let mut next_array = || -> io::Result<Vec<[f32; 4]>> {
+            match arrays.next() {
+                Some(a) if a.len() == array_bytes => Ok(a.chunks_exact(16).map(bytemuck::pod_read_unaligned).collect()),
+                _ => Err(invalid("truncated integrator state".to_string())),
+            }
+        };
+
+        let accel = if resume.arrays > 0 { next_array()? } else { vec![] };
+        let jerk = if resume.arrays > 1 { next_array()? } else { vec![] };
+
+        Ok(Self {
+            header,
+            stars,
+            solver,
+            integrator,
+            state: IntegratorState { primed: resume.primed != 0, accel, jerk },
+            frame_cnt: resume.frame_cnt,
+            zoom: resume.zoom,
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    // Goes through a temporary file, so a crash mid-write leaves the last checkpoint intact
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::ics::{IcParams, Model};
+
+    fn checkpoint(solver: Solver) -> Checkpoint {
+        let stars = Model::Plummer.generate(&IcParams { n: 16, mass: 1.0, scale_radius: 1.0, seed: 0 }, 1.0);
+        let mut checkpoint = Checkpoint::initial(stars, &Units::SI, 0.0, 0, solver, Integrator::Hermite, SimParams::new(16));
+        checkpoint.state = IntegratorState { primed: true, accel: vec![[1.0; 4]; 16], jerk: vec![[2.0; 4]; 16] };
+        checkpoint
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let data = checkpoint(Solver::Direct).encode();
+        assert!(Checkpoint::decode(&data).is_ok());
+        for len in 0..data.len() {
+            assert!(Checkpoint::decode(&data[..len]).is_err(), "decoded the first {} of {} bytes", len, data.len());
+        }
+
+        // header_bytes pointing past the end of the file
+        let mut data = data;
+        let at = data.len() - 2 * 16 * size_of::<[f32; 4]>() - size_of::<ResumeHeader>() + 12;
+        data[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Checkpoint::decode(&data).is_err());
+
+        // Star counts whose size overflows
+        for n in [u64::MAX, u64::MAX / size_of::<Star>() as u64] {
+            let mut data = checkpoint(Solver::Direct).encode();
+            let at = std::mem::offset_of!(SnapshotHeader, n_stars);
+            data[at..at + 8].copy_from_slice(&n.to_le_bytes());
+            assert!(Checkpoint::decode(&data).is_err(), "decoded {} stars", n);
+        }
+    }
+
+    #[test]
+    fn bad_meshes_and_orders_are_errors() {
+        let bad = [Solver::ParticleMesh { mesh: 0 }, Solver::TreePm { mesh: 100, theta: 0.5 }, Solver::Fmm { order: 20, theta: 0.5 }];
+        for solver in bad {
+            assert!(Checkpoint::decode(&checkpoint(solver).encode()).is_err(), "{:?}", solver);
+        }
+        assert!(Checkpoint::decode(&checkpoint(Solver::Fmm { order: 3, theta: 0.5 }).encode()).is_ok());
+    }
+}
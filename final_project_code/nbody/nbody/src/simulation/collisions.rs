@@ -0,0 +1,82 @@
+use bytemuck::{Pod, Zeroable};
+
+use super::{ewald, star::Star};
+
+// Mirrors `struct CollisionParams` in collisions.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct CollisionParams {
+    pub n: u32,
+    // Stars are counted and compacted in tiles of 64
+    pub num_tiles: u32,
+    pub merge_radius: f32,
+    // Side of the periodic box as in SimParams, 0 for open boundaries
+    pub box_size: f32,
+}
+
+// CPU version of collisions.wgsl. Stars that are each other's nearest neighbour and closer than
+// `merge_radius` merge into the lower index, conserving mass and momentum, and the survivors
+// keep their order. Massless stars count as dead. In a periodic box (`box_size` > 0) distances
+// are to the nearest image and merged stars are wrapped back in. The reference CollisionPass gets
+// checked against
+pub fn collide(stars: &[Star], merge_radius: f32, box_size: f32) -> Vec<Star> {
+    let image = |v: [f32; 3]| {
+        if box_size > 0.0 {
+            ewald::minimum_image(v.map(|x| x as f64), box_size as f64).map(|x| x as f32)
+        } else {
+            v
+        }
+    };
+
+    let nearest: Vec<Option<usize>> = stars
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            if a.mass <= 0.0 {
+                return None;
+            }
+
+            let mut best = (None, merge_radius * merge_radius);
+            for (j, b) in stars.iter().enumerate() {
+                let [x, y, z] = image([b.x - a.x, b.y - a.y, b.z - a.z]);
+                let r2 = x * x + y * y + z * z;
+                if j != i && b.mass > 0.0 && r2 < best.1 {
+                    best = (Some(j), r2);
+                }
+            }
+            best.0
+        })
+        .collect();
+
+    let mut out = vec![];
+    for (i, a) in stars.iter().enumerate() {
+        let partner = nearest[i].filter(|&j| nearest[j] == Some(i));
+        match partner {
+            Some(j) if j < i => continue,
+            Some(j) => {
+                let b = &stars[j];
+                let m = a.mass + b.mass;
+                let mix = |p: f32, q: f32| (a.mass * p + b.mass * q) / m;
+                // Around a, to b's nearest image, and back into the box, as in collisions.wgsl merge
+                let [dx, dy, dz] = image([b.x - a.x, b.y - a.y, b.z - a.z]);
+                let place = |p: f32, d: f32| {
+                    let p = p + b.mass * d / m;
+                    if box_size > 0.0 { p - box_size * (p / box_size + 0.5).floor() } else { p }
+                };
+                out.push(Star {
+                    x: place(a.x, dx),
+                    y: place(a.y, dy),
+                    z: place(a.z, dz),
+                    mass: m,
+                    x_vel: mix(a.x_vel, b.x_vel),
+                    y_vel: mix(a.y_vel, b.y_vel),
+                    z_vel: mix(a.z_vel, b.z_vel),
+                    bright: a.bright.max(b.bright),
+                });
+            }
+            None if a.mass > 0.0 => out.push(*a),
+            None => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,122 @@
+use libm::{exp, log, pow};
+
+use super::params::SimParams;
+
+// Expanding backgrounds for comoving runs. The universe holds matter and a cosmological constant,
+// curvature makes up the rest, and H(a)^2 = H0^2 (Om / a^3 + Ok / a^2 + OL).
+//
+// Positions in a comoving run are comoving, x = r / a, and velocities are the momentum per mass
+// p = a^2 dx/dt, as in GADGET. Then dx/dt = p / a^2 and dp/dt = g(x) / a, g being the Newtonian
+// field of the comoving positions with the mean density taken out, which the Ewald sum of the
+// periodic box does. So the leapfrog drifts by p times the integral of dt / a^2 over the step and
+// kicks by g times the integral of dt / a, and those only depend on the background.
+//
+// Time is ln a, so a constant dt steps evenly in ln a like GADGET does. The integrals go over
+// ln a as well, with dt = d(ln a) / H
+
+// Simpson panels for the drift and kick integrals, which only span a step
+const PANELS: usize = 16;
+// For the growth factor, whose integral starts at a = 0
+const GROWTH_PANELS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cosmology {
+    pub omega_m: f64,
+    pub omega_lambda: f64,
+    // H0, in the units of the run
+    pub hubble: f64,
+}
+
+impl Cosmology {
+    // The background SimParams::comoving set, None for a static one
+    pub fn from_params(params: &SimParams) -> Option<Self> {
+        params.is_comoving().then_some(Self {
+            omega_m: params.omega_m as f64,
+            omega_lambda: params.omega_lambda as f64,
+            hubble: params.hubble as f64,
+        })
+    }
+
+    pub fn omega_k(&self) -> f64 {
+        1.0 - self.omega_m - self.omega_lambda
+    }
+
+    // H(a) / H0
+    fn expansion(&self, a: f64) -> f64 {
+        (self.omega_m / (a * a * a) + self.omega_k() / (a * a) + self.omega_lambda).sqrt()
+    }
+
+    pub fn hubble_at(&self, a: f64) -> f64 {
+        self.hubble * self.expansion(a)
+    }
+
+    // The integral of dt / a^2 between two times
+    pub fn drift(&self, from: f64, to: f64) -> f64 {
+        simpson(
+            |t| {
+                let a = scale_factor(t);
+                1.0 / (a * a * self.hubble_at(a))
+            },
+            from,
+            to,
+            PANELS,
+        )
+    }
+
+    // The integral of dt / a between two times
+    pub fn kick(&self, from: f64, to: f64) -> f64 {
+        simpson(|t| 1.0 / (scale_factor(t) * self.hubble_at(scale_factor(t))), from, to, PANELS)
+    }
+
+    // The integral of da / (a H / H0)^3 from 0, which the growing mode is H times. Near 0 the
+    // integrand goes as a^1.5
+    fn growth_integral(&self, a: f64) -> f64 {
+        let integrand = |b: f64| {
+            if b <= 0.0 {
+                return 0.0;
+            }
+            pow(self.omega_m / b + self.omega_k() + self.omega_lambda * b * b, -1.5)
+        };
+        simpson(integrand, 0.0, a, GROWTH_PANELS)
+    }
+
+    // Linear growth factor D(a), 1 today. Heath 1977, exact for matter, curvature and a
+    // cosmological constant
+    pub fn growth(&self, a: f64) -> f64 {
+        let d = |a: f64| self.expansion(a) * self.growth_integral(a);
+        d(a) / d(1.0)
+    }
+
+    // f = d ln D / d ln a, which sets the Zel'dovich velocities. About omega_m(a)^0.55
+    pub fn growth_rate(&self, a: f64) -> f64 {
+        let e = self.expansion(a);
+        let dln_e = -(3.0 * self.omega_m / (a * a * a) + 2.0 * self.omega_k() / (a * a)) / (2.0 * e * e);
+        dln_e + 1.0 / (a * a * e * e * e * self.growth_integral(a))
+    }
+
+    // 3 H0^2 / 8 pi G, the mean density of matter is omega_m times this
+    pub fn critical_density(&self, g: f64) -> f64 {
+        3.0 * self.hubble * self.hubble / (8.0 * std::f64::consts::PI * g)
+    }
+}
+
+// Between the time of a comoving run, the scale factor and redshift
+pub fn scale_factor(time: f64) -> f64 {
+    exp(time)
+}
+
+pub fn redshift(time: f64) -> f64 {
+    1.0 / scale_factor(time) - 1.0
+}
+
+pub fn time_at(redshift: f64) -> f64 {
+    -log(1.0 + redshift)
+}
+
+// Composite Simpson's rule, `panels` is rounded up to even
+pub(super) fn simpson(f: impl Fn(f64) -> f64, from: f64, to: f64, panels: usize) -> f64 {
+    let panels = panels.div_ceil(2) * 2;
+    let h = (to - from) / panels as f64;
+    let inner: f64 = (1..panels).map(|i| f(from + i as f64 * h) * if i % 2 == 1 { 4.0 } else { 2.0 }).sum();
+    (f(from) + inner + f(to)) * h / 3.0
+}
@@ -0,0 +1,167 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{ewald, params::SimParams, star::Star};
+
+// What diagnostics.wgsl writes for each workgroup of 64 stars
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct Partial {
+    // Kinetic energy, potential energy, mass, unused
+    pub energy: [f32; 4],
+    pub momentum: [f32; 4],
+    pub angular_momentum: [f32; 4],
+    // Mass weighted position
+    pub moment: [f32; 4],
+}
+
+// The conserved quantities of the whole system at one step, in the units of the params.
+// Measured on the GPU by DiagnosticsPass, or on the CPU by `measure`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    pub step: u64,
+    pub time: f64,
+    pub kinetic: f64,
+    pub potential: f64,
+    pub momentum: [f64; 3],
+    // About the origin
+    pub angular_momentum: [f64; 3],
+    pub center_of_mass: [f64; 3],
+    pub mass: f64,
+}
+
+impl Diagnostics {
+    // Adds the workgroups up in f64, in f32 the drift of a long run would drown in rounding
+    pub fn from_partials(step: u64, time: f64, partials: &[Partial]) -> Self {
+        let mut sum = [[0.0f64; 4]; 4];
+        for p in partials {
+            for (s, v) in sum.iter_mut().zip([p.energy, p.momentum, p.angular_momentum, p.moment]) {
+                s.iter_mut().zip(v).for_each(|(s, v)| *s += v as f64);
+            }
+        }
+
+        let [energy, momentum, angular_momentum, moment] = sum;
+        Self::new(step, time, energy, momentum, angular_momentum, moment)
+    }
+
+    // CPU version of diagnostics.wgsl, O(N^2) like direct_accelerations. The reference the GPU
+    // numbers get checked against
+    pub fn measure(step: u64, time: f64, stars: &[Star], params: &SimParams) -> Self {
+        let (g, eps, kernel) = (params.g as f64, params.softening as f64, params.kernel());
+        let box_size = params.box_size as f64;
+        let table = if params.is_periodic() { ewald::table() } else { vec![] };
+        let mut sum = [[0.0f64; 4]; 4];
+
+        for (i, a) in stars.iter().enumerate() {
+            let m = a.mass as f64;
+            let x = [a.x as f64, a.y as f64, a.z as f64];
+            let v = [a.x_vel as f64, a.y_vel as f64, a.z_vel as f64];
+
+            // Periodic boxes add the star's own images, see diagnostics.wgsl
+            let mut phi = if params.is_periodic() { -g * m * ewald::lookup(&table, [0.0; 3], box_size)[3] } else { 0.0 };
+            for (j, b) in stars.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let mut d = [b.x as f64 - x[0], b.y as f64 - x[1], b.z as f64 - x[2]];
+                let mut c = 0.0;
+                if params.is_periodic() {
+                    d = ewald::minimum_image(d, box_size);
+                    c = ewald::lookup(&table, d, box_size)[3];
+                }
+                phi -= g * b.mass as f64 * (kernel.inv_r(d[0] * d[0] + d[1] * d[1] + d[2] * d[2], eps) + c);
+            }
+
+            let add = |s: &mut [f64; 4], v: [f64; 3]| (0..3).for_each(|k| s[k] += v[k]);
+            sum[0][0] += 0.5 * m * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+            sum[0][1] += 0.5 * m * phi;
+            sum[0][2] += m;
+            add(&mut sum[1], v.map(|v| m * v));
+            add(&mut sum[2], cross(x, v).map(|l| m * l));
+            add(&mut sum[3], x.map(|x| m * x));
+        }
+
+        let [energy, momentum, angular_momentum, moment] = sum;
+        Self::new(step, time, energy, momentum, angular_momentum, moment)
+    }
+
+    fn new(step: u64, time: f64, energy: [f64; 4], momentum: [f64; 4], angular_momentum: [f64; 4], moment: [f64; 4]) -> Self {
+        let mass = energy[2];
+        Self {
+            step,
+            time,
+            kinetic: energy[0],
+            potential: energy[1],
+            momentum: [momentum[0], momentum[1], momentum[2]],
+            angular_momentum: [angular_momentum[0], angular_momentum[1], angular_momentum[2]],
+            center_of_mass: [moment[0] / mass, moment[1] / mass, moment[2] / mass],
+            mass,
+        }
+    }
+
+    pub fn energy(&self) -> f64 {
+        self.kinetic + self.potential
+    }
+
+    // 2K / |W|, 1 for a system in virial equilibrium
+    pub fn virial_ratio(&self) -> f64 {
+        2.0 * self.kinetic / self.potential.abs()
+    }
+
+    // |E - E0| / |E0|, what tests of the integrators bound
+    pub fn energy_drift(&self, initial: &Diagnostics) -> f64 {
+        ((self.energy() - initial.energy()) / initial.energy()).abs()
+    }
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+const HEADER: &str = "step,time,kinetic,potential,energy,virial_ratio,px,py,pz,lx,ly,lz,com_x,com_y,com_z,mass";
+
+// Diagnostics as a CSV time series, one row per measurement
+pub struct DiagnosticsLog {
+    file: File,
+}
+
+impl DiagnosticsLog {
+    // Continues the series at `path` from `step`. Rows at or after it are dropped first, like
+    // Pvd::push, so a resumed run replaces the steps it redoes. A new run starts from 0
+    pub fn open(path: impl AsRef<Path>, step: u64) -> io::Result<Self> {
+        let path = path.as_ref();
+        let kept: Vec<String> = match fs::read_to_string(path) {
+            Ok(text) => text
+                .lines()
+                .skip(1)
+                .filter(|l| l.split(',').next().and_then(|s| s.parse::<u64>().ok()).is_some_and(|s| s < step))
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        writeln!(file, "{}", HEADER)?;
+        for line in kept {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(Self { file })
+    }
+
+    pub fn push(&mut self, d: &Diagnostics) -> io::Result<()> {
+        let [px, py, pz] = d.momentum;
+        let [lx, ly, lz] = d.angular_momentum;
+        let [cx, cy, cz] = d.center_of_mass;
+        writeln!(
+            self.file,
+            "{},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e}",
+            d.step, d.time, d.kinetic, d.potential, d.energy(), d.virial_ratio(), px, py, pz, lx, ly, lz, cx, cy, cz, d.mass
+        )
+    }
+}
@@ -0,0 +1,59 @@
+use super::{ewald, params::SimParams, star::Star};
+
+// CPU version of the pairwise loop in integrate.wgsl. Far too slow to run every
+// frame, but it is the reference the approximate solvers get checked against.
+pub fn direct_accelerations(stars: &[Star], params: &SimParams) -> Vec<[f32; 4]> {
+    let (g, eps, kernel) = (params.g as f64, params.softening as f64, params.kernel());
+    let box_size = params.box_size as f64;
+    let table = if params.is_periodic() { ewald::table() } else { vec![] };
+
+    stars
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            let mut f = [0.0f64; 3];
+            for (j, b) in stars.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+
+                let mut v = [
+                    b.x as f64 - a.x as f64,
+                    b.y as f64 - a.y as f64,
+                    b.z as f64 - a.z as f64,
+                ];
+                let mut c = [0.0; 4];
+                if params.is_periodic() {
+                    v = ewald::minimum_image(v, box_size);
+                    c = ewald::lookup(&table, v, box_size);
+                }
+
+                let r2 = v[0] * v[0] + v[1] * v[1] + v[2] * v[2];
+                let gm = g * b.mass as f64;
+                let k = gm * kernel.inv_r3(r2, eps);
+                f[0] += k * v[0] + gm * c[0];
+                f[1] += k * v[1] + gm * c[1];
+                f[2] += k * v[2] + gm * c[2];
+            }
+
+            [f[0] as f32, f[1] as f32, f[2] as f32, 0.0]
+        })
+        .collect()
+}
+
+// |a - a_exact| / |a_exact| per star, sorted, leaving out stars with no force to compare. How
+// `nbody accuracy` and the solver tests measure an approximation against the direct sum
+pub fn relative_errors(approx: &[[f32; 4]], exact: &[[f32; 4]]) -> Vec<f64> {
+    let norm = |a: [f64; 3]| (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    let mut errors: Vec<f64> = approx
+        .iter()
+        .zip(exact)
+        .filter_map(|(a, e)| {
+            let diff = norm([0, 1, 2].map(|k| a[k] as f64 - e[k] as f64));
+            let size = norm([0, 1, 2].map(|k| e[k] as f64));
+            (size > 0.0).then_some(diff / size)
+        })
+        .collect();
+    errors.sort_by(f64::total_cmp);
+    errors
+}
@@ -0,0 +1,220 @@
+use std::f64::consts::PI;
+
+// Ewald summation for periodic boxes. Forces take the nearest image of every star, then add the
+// correction for all the other images and for the uniform background that keeps the box
+// neutral. The correction comes from a table over the first octant of a unit box, the other
+// seven mirror it, since it's odd in each component. A box of side L scales the force
+// correction by 1/L^2 and the potential one by 1/L
+
+// Table intervals per side, over [0, 1/2]
+pub const TABLE: usize = 32;
+// Splits the sum between real and reciprocal space, in units of 1/L. With it both are down to
+// ~1e-10 two boxes out
+const ALPHA: f64 = 2.0;
+const IMAGES: i32 = 2;
+
+// The periodic 1/r minus the nearest image's, in a unit box. xyz is the correction to the
+// acceleration towards a unit mass at v, which plain gravity has as v/|v|^3, and w the
+// correction to 1/|v|. At v = 0, w is the star's interaction with its own images
+pub fn correction(v: [f64; 3]) -> [f64; 4] {
+    let mut out = [0.0; 4];
+    let r = dot(v, v).sqrt();
+
+    for n in images() {
+        let d = [v[0] - n[0], v[1] - n[1], v[2] - n[2]];
+        let rn = dot(d, d).sqrt();
+        if rn == 0.0 {
+            continue;
+        }
+
+        let k = (libm::erfc(ALPHA * rn) + 2.0 * ALPHA * rn / PI.sqrt() * (-ALPHA * ALPHA * rn * rn).exp()) / (rn * rn * rn);
+        for a in 0..3 {
+            out[a] += k * d[a];
+        }
+        out[3] += libm::erfc(ALPHA * rn) / rn;
+    }
+
+    for h in images() {
+        let h2 = dot(h, h);
+        if h2 == 0.0 {
+            continue;
+        }
+
+        let (phase, damp) = (2.0 * PI * dot(h, v), (-PI * PI * h2 / (ALPHA * ALPHA)).exp());
+        for a in 0..3 {
+            out[a] += 2.0 / h2 * damp * phase.sin() * h[a];
+        }
+        out[3] += damp / (PI * h2) * phase.cos();
+    }
+
+    // The background, then the nearest image taken back out
+    out[3] -= PI / (ALPHA * ALPHA);
+    if r > 0.0 {
+        for a in 0..3 {
+            out[a] -= v[a] / (r * r * r);
+        }
+        out[3] -= 1.0 / r;
+    } else {
+        out[3] -= 2.0 * ALPHA / PI.sqrt();
+    }
+    out
+}
+
+// `correction` on (TABLE + 1)^3 points over [0, 1/2]^3, x fastest. What gets uploaded
+pub fn table() -> Vec<[f32; 4]> {
+    let side = TABLE + 1;
+    let step = 0.5 / TABLE as f64;
+    (0..side * side * side)
+        .map(|i| {
+            let v = [i % side, i / side % side, i / (side * side)].map(|k| k as f64 * step);
+            correction(v).map(|c| c as f32)
+        })
+        .collect()
+}
+
+// The correction at v, a nearest image displacement in a box of side `box_size`, scaled to it.
+// Trilinear in the table. Mirrors ewald in accel.wgsl
+pub fn lookup(table: &[[f32; 4]], v: [f64; 3], box_size: f64) -> [f64; 4] {
+    let side = TABLE + 1;
+    let u = v.map(|x| (x.abs() / box_size * 2.0 * TABLE as f64).min(TABLE as f64));
+    let i = u.map(|x| (x as usize).min(TABLE - 1));
+    let f = [u[0] - i[0] as f64, u[1] - i[1] as f64, u[2] - i[2] as f64];
+
+    let mut c = [0.0; 4];
+    for corner in 0..8 {
+        let o = [corner & 1, corner >> 1 & 1, corner >> 2];
+        let w: f64 = (0..3).map(|a| if o[a] == 1 { f[a] } else { 1.0 - f[a] }).product();
+        let t = table[i[0] + o[0] + side * (i[1] + o[1] + side * (i[2] + o[2]))];
+        for (c, t) in c.iter_mut().zip(t) {
+            *c += w * t as f64;
+        }
+    }
+
+    let scale = 1.0 / (box_size * box_size);
+    [c[0] * v[0].signum() * scale, c[1] * v[1].signum() * scale, c[2] * v[2].signum() * scale, c[3] / box_size]
+}
+
+// The displacement to the nearest image of whatever is v away
+pub fn minimum_image(v: [f64; 3], box_size: f64) -> [f64; 3] {
+    v.map(|x| x - box_size * (x / box_size).round())
+}
+
+fn images() -> impl Iterator<Item = [f64; 3]> {
+    let range = -IMAGES..=IMAGES;
+    range.clone().flat_map(move |z| {
+        let range = range.clone();
+        range.clone().flat_map(move |y| range.clone().map(move |x| [x as f64, y as f64, z as f64]))
+    })
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{
+        direct::direct_accelerations,
+        params::{SimParams, SofteningKernel},
+        star::Star,
+    };
+
+    // The acceleration towards unit masses at v + n for n within `k` boxes, less a uniform unit
+    // density over the cells around them. Every cell is neutral with no dipole, so it converges
+    // without Ewald's splitting. The cells make a cube centered on v, whose pull at the origin is
+    // (4 pi / 3) v to leading order
+    fn image_sum(v: [f64; 3], k: i32) -> [f64; 3] {
+        let mut f = [0.0; 3];
+        for z in -k..=k {
+            for y in -k..=k {
+                for x in -k..=k {
+                    let d = [v[0] + x as f64, v[1] + y as f64, v[2] + z as f64];
+                    let r = norm(d);
+                    for a in 0..3 {
+                        f[a] += d[a] / (r * r * r);
+                    }
+                }
+            }
+        }
+        [0, 1, 2].map(|a| f[a] - 4.0 * PI / 3.0 * v[a])
+    }
+
+    // What's left out falls as 1/k^2, so two sums extrapolate to an infinite one
+    fn brute_force(v: [f64; 3]) -> [f64; 3] {
+        let (near, far) = (image_sum(v, 12), image_sum(v, 24));
+        [0, 1, 2].map(|a| (4.0 * far[a] - near[a]) / 3.0)
+    }
+
+    fn periodic(v: [f64; 3]) -> [f64; 3] {
+        let (c, r) = (correction(v), norm(v));
+        [0, 1, 2].map(|a| c[a] + v[a] / (r * r * r))
+    }
+
+    fn star(at: [f32; 3], mass: f32) -> Star {
+        Star { x: at[0], y: at[1], z: at[2], mass, x_vel: 0.0, y_vel: 0.0, z_vel: 0.0, bright: 1.0 }
+    }
+
+    fn norm(v: [f64; 3]) -> f64 {
+        dot(v, v).sqrt()
+    }
+
+    #[test]
+    fn lattice_points_feel_no_force() {
+        // A star's own images pull it every way equally
+        let own = correction([0.0; 3]);
+        assert!(norm([own[0], own[1], own[2]]) < 1.0E-12, "{:?}", own);
+
+        // So does a star half a box away along any of the axes, the nearest image included
+        let table = table();
+        for v in [[0.5, 0.0, 0.0], [0.0, -0.5, 0.5], [0.5, 0.5, 0.5]] {
+            assert!(norm(periodic(v)) < 1.0E-9, "{:?} {:?}", v, periodic(v));
+
+            let (box_size, r) = (3.0, norm(v));
+            let c = lookup(&table, v.map(|x| x * box_size), box_size);
+            let f = [0, 1, 2].map(|a| c[a] + v[a] / (r * r * r * box_size * box_size));
+            assert!(norm(f) < 1.0E-6, "{:?} {:?}", v, f);
+        }
+
+        let params = SimParams::new(2).gravity(1.0).softening(1.0E-3, SofteningKernel::Spline).periodic(3.0);
+        let f = direct_accelerations(&[star([-1.0, 0.2, 0.7], 1.0), star([0.5, 1.7, -0.8], 1.0)], &params);
+        assert!(f.iter().all(|f| norm([f[0], f[1], f[2]].map(|x| x as f64)) < 1.0E-6), "{:?}", f);
+    }
+
+    #[test]
+    fn correction_is_antisymmetric() {
+        let table = table();
+        for v in [[0.1, 0.2, -0.3], [0.45, -0.05, 0.2], [0.01, 0.0, 0.0], [-0.3, 0.3, 0.3]] {
+            let (c, m) = (correction(v), correction(v.map(|x| -x)));
+            assert!((0..3).all(|a| (c[a] + m[a]).abs() <= 1.0E-12 * c[a].abs().max(1.0)), "{:?} {:?}", c, m);
+            assert!((c[3] - m[3]).abs() <= 1.0E-12 * c[3].abs(), "{:?} {:?}", c, m);
+
+            let (c, m) = (lookup(&table, v, 2.0), lookup(&table, v.map(|x| -x), 2.0));
+            assert_eq!([c[0], c[1], c[2], c[3]], [-m[0], -m[1], -m[2], m[3]]);
+        }
+    }
+
+    #[test]
+    fn matches_a_sum_over_images() {
+        // The exact correction, to what the brute force sum has left out
+        for v in [[0.1, 0.2, -0.3], [0.45, -0.05, 0.2], [0.01, 0.0, 0.0], [-0.3, 0.3, 0.3]] {
+            let (exact, periodic) = (brute_force(v), periodic(v));
+            let error = norm([0, 1, 2].map(|a| periodic[a] - exact[a]));
+            assert!(error < 1.0E-5 * norm(exact), "{:?}: {:?} vs {:?}", v, periodic, exact);
+        }
+
+        // A pair through the table, which is good to a few 1e-4
+        let box_size = 4.0;
+        let params = SimParams::new(2).gravity(1.0).softening(1.0E-3, SofteningKernel::Spline).periodic(box_size);
+        for (a, b) in [([0.3, -1.2, 0.5], [1.1, 0.4, -0.9]), ([1.9, 1.9, 1.9], [-1.7, -0.2, 0.1])] {
+            let f = direct_accelerations(&[star(a, 2.0), star(b, 0.5)], &params);
+            let v = minimum_image([0, 1, 2].map(|k| (b[k] - a[k]) as f64), box_size as f64);
+            let exact = brute_force(v.map(|x| x / box_size as f64)).map(|x| x / (box_size * box_size) as f64);
+
+            for (f, exact) in [(f[0], exact.map(|x| 0.5 * x)), (f[1], exact.map(|x| -2.0 * x))] {
+                let error = norm([0, 1, 2].map(|k| f[k] as f64 - exact[k]));
+                assert!(error < 1.0E-3 * norm(exact), "{:?} {:?}: {:?} vs {:?}", a, b, f, exact);
+            }
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use std::{
+    fs, io,
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    cosmology::{self, Cosmology},
+    ics::{BRIGHT_BULGE, BRIGHT_DISK, BRIGHT_HALO, BRIGHT_SPHERE},
+    params::SimParams,
+    snapshot::invalid,
+    star::Star,
+    units::{self, Units, HUBBLE_UNIT, KILOPARSEC, SOLAR_MASS},
+};
+
+// GADGET's six particle types. Stars don't store one, so the type travels as the brightness
+// the initial conditions already give each component.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParticleType {
+    Gas = 0,
+    Halo = 1,
+    Disk = 2,
+    Bulge = 3,
+    Star = 4,
+    Boundary = 5,
+}
+
+impl ParticleType {
+    pub const ALL: [ParticleType; 6] = [
+        ParticleType::Gas,
+        ParticleType::Halo,
+        ParticleType::Disk,
+        ParticleType::Bulge,
+        ParticleType::Star,
+        ParticleType::Boundary,
+    ];
+
+    pub fn bright(self) -> f32 {
+        match self {
+            ParticleType::Gas => 0.4,
+            ParticleType::Halo => BRIGHT_HALO,
+            ParticleType::Disk => BRIGHT_DISK,
+            ParticleType::Bulge => BRIGHT_BULGE,
+            ParticleType::Star => BRIGHT_SPHERE,
+            ParticleType::Boundary => 0.1,
+        }
+    }
+
+    // The type with the nearest brightness
+    pub fn from_bright(bright: f32) -> Self {
+        let dist = |t: &ParticleType| (t.bright() - bright).abs();
+        *Self::ALL.iter().min_by(|a, b| dist(a).total_cmp(&dist(b))).unwrap()
+    }
+}
+
+// SnapFormat 1 is plain Fortran records, 2 puts a record with a four letter label before each block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GadgetFormat {
+    One,
+    Two,
+}
+
+// The 256 byte io_header of GADGET-2
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GadgetHeader {
+    // In this file
+    pub npart: [u32; 6],
+    // Mass of every particle of a type, or 0 if they're listed in the MASS block
+    pub mass: [f64; 6],
+    // The scale factor in cosmological runs
+    pub time: f64,
+    pub redshift: f64,
+    pub flag_sfr: i32,
+    pub flag_feedback: i32,
+    // Over all files
+    pub npart_total: [u32; 6],
+    pub flag_cooling: i32,
+    pub num_files: i32,
+    pub box_size: f64,
+    pub omega0: f64,
+    pub omega_lambda: f64,
+    pub hubble_param: f64,
+    pub flag_stellarage: i32,
+    pub flag_metals: i32,
+    pub npart_total_high_word: [u32; 6],
+    pub flag_entropy_instead_u: i32,
+    pub _fill: [u32; 15],
+}
+
+const _: () = assert!(size_of::<GadgetHeader>() == 256);
+
+impl GadgetHeader {
+    // GADGET's default units: kpc/h, 1e10 solar masses/h and km/s
+    pub fn units(&self) -> Units {
+        let h = if self.hubble_param > 0.0 { self.hubble_param } else { 1.0 };
+        let length = KILOPARSEC / h;
+        Units { length, mass: 1.0E10 * SOLAR_MASS / h, time: length / 1.0E3 }
+    }
+}
+
+// A snapshot or set of initial conditions, particles in file order (grouped by type) and in
+// the header's units. Velocities are as stored, which for cosmological runs is v / sqrt(a).
+pub struct Gadget {
+    pub header: GadgetHeader,
+    pub stars: Vec<Star>,
+    pub ids: Vec<u64>,
+    pub types: Vec<ParticleType>,
+}
+
+impl Gadget {
+    // Stars are converted from `units` to GADGET's and numbered from 1 in their original order,
+    // which stars_by_id restores. h is 1 unless the params are comoving, then the header gets
+    // their cosmology and the scale factor as the time
+    pub fn from_stars(stars: &[Star], units: &Units, time: f64, params: &SimParams) -> Self {
+        let mut header = GadgetHeader::zeroed();
+        header.hubble_param = 1.0;
+        header.num_files = 1;
+
+        let cosmology = Cosmology::from_params(params);
+        match cosmology {
+            Some(c) => {
+                header.hubble_param = c.hubble / units.time / HUBBLE_UNIT;
+                header.omega0 = c.omega_m;
+                header.omega_lambda = c.omega_lambda;
+                header.time = cosmology::scale_factor(time);
+                header.redshift = cosmology::redshift(time);
+            }
+            None => header.time = time * units.time_to(&header.units()),
+        }
+        header.box_size = params.box_size as f64 * units.length_to(&header.units());
+
+        let mut order: Vec<usize> = (0..stars.len()).collect();
+        order.sort_by_key(|&i| ParticleType::from_bright(stars[i].bright) as u32);
+
+        let mut converted: Vec<Star> = order.iter().map(|&i| stars[i]).collect();
+        units.convert_stars(&mut converted, &header.units());
+        // a^2 dx/dt to GADGET's sqrt(a) dx/dt
+        if cosmology.is_some() {
+            let scale = header.time.powf(-1.5) as f32;
+            for s in &mut converted {
+                s.x_vel *= scale;
+                s.y_vel *= scale;
+                s.z_vel *= scale;
+            }
+        }
+        let types: Vec<ParticleType> = converted.iter().map(|s| ParticleType::from_bright(s.bright)).collect();
+
+        for t in ParticleType::ALL {
+            let masses: Vec<f32> = converted.iter().zip(&types).filter(|(_, &ty)| ty == t).map(|(s, _)| s.mass).collect();
+            header.npart[t as usize] = masses.len() as u32;
+            if !masses.is_empty() && masses.iter().all(|&m| m == masses[0]) {
+                header.mass[t as usize] = masses[0] as f64;
+            }
+        }
+        header.npart_total = header.npart;
+
+        Self {
+            header,
+            stars: converted,
+            ids: order.iter().map(|&i| i as u64 + 1).collect(),
+            types,
+        }
+    }
+
+    // Written by a cosmological run, which from_stars marks with Omega_0 and the periodic box
+    pub fn is_comoving(&self) -> bool {
+        self.header.omega0 > 0.0 && self.header.box_size > 0.0
+    }
+
+    // Henon units for the whole snapshot, see units::fit_nbody
+    pub fn nbody_units(&self) -> Units {
+        units::fit_nbody(&self.stars, &self.header.units())
+    }
+
+    // Stars sorted by particle ID, in `units`. Comoving velocities go back from sqrt(a) dx/dt to
+    // the a^2 dx/dt the integrators use, undoing from_stars
+    pub fn stars_by_id(&self, units: &Units) -> Vec<Star> {
+        let mut order: Vec<usize> = (0..self.stars.len()).collect();
+        order.sort_by_key(|&i| self.ids[i]);
+
+        let mut stars: Vec<Star> = order.iter().map(|&i| self.stars[i]).collect();
+        self.header.units().convert_stars(&mut stars, units);
+        if self.is_comoving() {
+            let scale = self.header.time.powf(1.5) as f32;
+            for s in &mut stars {
+                s.x_vel *= scale;
+                s.y_vel *= scale;
+                s.z_vel *= scale;
+            }
+        }
+        stars
+    }
+
+    // A single file, positions and velocities in single precision
+    pub fn encode(&self, format: GadgetFormat) -> Vec<u8> {
+        let mut header = self.header;
+        header.num_files = 1;
+        header.npart_total = header.npart;
+        header.npart_total_high_word = [0; 6];
+
+        let vec3 = |f: &dyn Fn(&Star) -> [f32; 3]| -> Vec<u8> {
+            self.stars.iter().flat_map(|s| f(s)).flat_map(f32::to_le_bytes).collect()
+        };
+        let masses: Vec<u8> = self
+            .stars
+            .iter()
+            .zip(&self.types)
+            .filter(|(_, &t)| header.mass[t as usize] == 0.0)
+            .flat_map(|(s, _)| s.mass.to_le_bytes())
+            .collect();
+        let ids: Vec<u8> = if self.ids.iter().all(|&id| id <= u32::MAX as u64) {
+            self.ids.iter().flat_map(|&id| (id as u32).to_le_bytes()).collect()
+        } else {
+            self.ids.iter().flat_map(|&id| id.to_le_bytes()).collect()
+        };
+
+        let mut out = vec![];
+        write_block(&mut out, format, b"HEAD", bytemuck::bytes_of(&header));
+        write_block(&mut out, format, b"POS ", &vec3(&|s| [s.x, s.y, s.z]));
+        write_block(&mut out, format, b"VEL ", &vec3(&|s| [s.x_vel, s.y_vel, s.z_vel]));
+        write_block(&mut out, format, b"ID  ", &ids);
+        if !masses.is_empty() {
+            write_block(&mut out, format, b"MASS", &masses);
+        }
+        // GADGET won't start from gas without an internal energy, cold it is
+        let n_gas = header.npart[ParticleType::Gas as usize] as usize;
+        if n_gas > 0 {
+            write_block(&mut out, format, b"U   ", &vec![0u8; n_gas * size_of::<f32>()]);
+        }
+        out
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>, format: GadgetFormat) -> io::Result<()> {
+        fs::write(path, self.encode(format))
+    }
+
+    // Either format, single or double precision. For snapshots split over several files give the
+    // base name or the first file (`snap.0`), the rest are found from the header
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let numbered = |base: &Path, i: i32| PathBuf::from(format!("{}.{}", base.display(), i));
+
+        let (first, base) = if path.exists() {
+            (path.to_path_buf(), path.to_str().and_then(|p| p.strip_suffix(".0")).map(PathBuf::from))
+        } else {
+            (numbered(path, 0), Some(path.to_path_buf()))
+        };
+
+        let mut gadget = Self::decode(&fs::read(&first)?)?;
+        let files = gadget.header.num_files;
+        if files > 1 {
+            let base = base.ok_or_else(|| invalid(format!("{:?} is one of {} files but isn't named *.0", path, files)))?;
+            for i in 1..files {
+                let part = Self::decode(&fs::read(numbered(&base, i))?)?;
+                gadget.stars.extend(part.stars);
+                gadget.ids.extend(part.ids);
+                gadget.types.extend(part.types);
+                for t in 0..6 {
+                    gadget.header.npart[t] += part.header.npart[t];
+                }
+            }
+            gadget.header.num_files = 1;
+        }
+        Ok(gadget)
+    }
+
+    // One file of a snapshot
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(data)?;
+
+        let header_block = reader.block(b"HEAD")?;
+        if header_block.len() != size_of::<GadgetHeader>() {
+            return Err(invalid(format!("header block is {} bytes, not 256", header_block.len())));
+        }
+        let header: GadgetHeader = bytemuck::pod_read_unaligned(header_block);
+
+        // The counts are checked against the POS block before anything is sized by them
+        let n = header.npart.iter().map(|&c| c as usize).sum::<usize>();
+        let pos = reals(reader.block(b"POS ")?, 3 * n, "POS")?;
+        let types: Vec<ParticleType> = ParticleType::ALL
+            .iter()
+            .flat_map(|&t| std::iter::repeat(t).take(header.npart[t as usize] as usize))
+            .collect();
+        let vel = reals(reader.block(b"VEL ")?, 3 * n, "VEL")?;
+        let ids = integers(reader.block(b"ID  ")?, n)?;
+
+        let listed = types.iter().filter(|&&t| header.mass[t as usize] == 0.0).count();
+        let mut listed_masses = if listed > 0 { reals(reader.block(b"MASS")?, listed, "MASS")? } else { vec![] }.into_iter();
+
+        let stars = types
+            .iter()
+            .enumerate()
+            .map(|(i, &t)| {
+                let mass = match header.mass[t as usize] {
+                    m if m == 0.0 => listed_masses.next().unwrap(),
+                    m => m as f32,
+                };
+                Star {
+                    x: pos[3 * i],
+                    y: pos[3 * i + 1],
+                    z: pos[3 * i + 2],
+                    mass,
+                    x_vel: vel[3 * i],
+                    y_vel: vel[3 * i + 1],
+                    z_vel: vel[3 * i + 2],
+                    bright: t.bright(),
+                }
+            })
+            .collect();
+
+        Ok(Self { header, stars, ids, types })
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, data: &[u8]) {
+    let marker = (data.len() as u32).to_le_bytes();
+    out.extend_from_slice(&marker);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&marker);
+}
+
+fn write_block(out: &mut Vec<u8>, format: GadgetFormat, label: &[u8; 4], data: &[u8]) {
+    if format == GadgetFormat::Two {
+        let mut head = label.to_vec();
+        head.extend_from_slice(&(data.len() as u32 + 8).to_le_bytes());
+        write_record(out, &head);
+    }
+    write_record(out, data);
+}
+
+// f32 or f64 values, told apart by the block size
+fn reals(block: &[u8], count: usize, name: &str) -> io::Result<Vec<f32>> {
+    match block.len() {
+        len if len == count * 4 => Ok(block.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect()),
+        len if len == count * 8 => Ok(block.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32).collect()),
+        len => Err(invalid(format!("{} block is {} bytes for {} values", name, len, count))),
+    }
+}
+
+// u32 or u64 IDs
+fn integers(block: &[u8], count: usize) -> io::Result<Vec<u64>> {
+    match block.len() {
+        len if len == count * 4 => Ok(block.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64).collect()),
+        len if len == count * 8 => Ok(block.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect()),
+        len => Err(invalid(format!("ID block is {} bytes for {} particles", len, count))),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: GadgetFormat,
+}
+
+impl<'a> Reader<'a> {
+    // The first record marker is 256 for a format 1 header and 8 for a format 2 label
+    fn new(data: &'a [u8]) -> io::Result<Self> {
+        let first = data.get(..4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        let format = match first {
+            Some(256) => GadgetFormat::One,
+            Some(8) => GadgetFormat::Two,
+            Some(m) if m.swap_bytes() == 256 || m.swap_bytes() == 8 => {
+                return Err(invalid("big endian GADGET files aren't supported".to_string()))
+            }
+            _ => return Err(invalid("not a GADGET snapshot".to_string())),
+        };
+        Ok(Self { data, pos: 0, format })
+    }
+
+    fn record(&mut self) -> io::Result<&'a [u8]> {
+        let marker = |at: usize| -> io::Result<usize> {
+            self.data
+                .get(at..at + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or_else(|| invalid("truncated GADGET file".to_string()))
+        };
+
+        let len = marker(self.pos)?;
+        let start = self.pos + 4;
+        if marker(start + len)? != len {
+            return Err(invalid(format!("record markers don't match at byte {}", self.pos)));
+        }
+        self.pos = start + len + 4;
+        Ok(&self.data[start..start + len])
+    }
+
+    // Format 1 blocks have to come in the standard order, format 2 ones are found by label
+    fn block(&mut self, label: &[u8; 4]) -> io::Result<&'a [u8]> {
+        match self.format {
+            GadgetFormat::One => self.record(),
+            GadgetFormat::Two => loop {
+                let head = self.record()?;
+                let data = self.record()?;
+                if head.get(..4) == Some(label.as_slice()) {
+                    return Ok(data);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    // Every type but the boundary, interleaved. Gas and halo particles share a mass each, which
+    // goes in the header, disk and star particles don't and get the MASS block
+    fn mixed() -> Vec<Star> {
+        let types = [ParticleType::Disk, ParticleType::Halo, ParticleType::Gas, ParticleType::Star, ParticleType::Halo, ParticleType::Bulge];
+        (0..30)
+            .map(|i| {
+                let t = types[i % types.len()];
+                let mass = match t {
+                    ParticleType::Gas => 0.5,
+                    ParticleType::Halo => 2.0,
+                    _ => 1.0 + 0.1 * i as f32,
+                };
+                let f = i as f32;
+                Star { x: f, y: -0.5 * f, z: 0.25, mass, x_vel: 0.1 * f, y_vel: 1.0, z_vel: -f, bright: t.bright() }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let units = Units::nbody(1.0E5 * SOLAR_MASS, 1.0E3 * KILOPARSEC);
+        let stars = mixed();
+        let gadget = Gadget::from_stars(&stars, &units, 2.0, &SimParams::new(stars.len() as u32));
+        assert_ne!(gadget.header.mass[ParticleType::Halo as usize], 0.0);
+        assert_ne!(gadget.header.mass[ParticleType::Gas as usize], 0.0);
+        assert_eq!(gadget.header.mass[ParticleType::Disk as usize], 0.0);
+
+        for format in [GadgetFormat::One, GadgetFormat::Two] {
+            let read = Gadget::decode(&gadget.encode(format)).unwrap();
+            assert!(!read.is_comoving());
+            assert_eq!(read.header.npart, [5, 10, 5, 5, 5, 0], "{:?}", format);
+            assert_eq!(read.types, gadget.types, "{:?}", format);
+            assert!((read.header.time - gadget.header.time).abs() <= 1.0E-12 * gadget.header.time);
+
+            let back = read.stars_by_id(&units);
+            assert_eq!(back.len(), stars.len());
+            for (a, b) in back.iter().zip(&stars) {
+                let (a, b) = ([a.x, a.y, a.z, a.mass, a.x_vel, a.y_vel, a.z_vel, a.bright], [b.x, b.y, b.z, b.mass, b.x_vel, b.y_vel, b.z_vel, b.bright]);
+                assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() <= 1.0E-5 * b.abs().max(1.0)), "{:?}: {:?} vs {:?}", format, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        // Without gas, whose U block decode never reads
+        let stars: Vec<Star> = mixed().into_iter().filter(|s| ParticleType::from_bright(s.bright) != ParticleType::Gas).collect();
+        let gadget = Gadget::from_stars(&stars, &Units::SI, 0.0, &SimParams::new(stars.len() as u32));
+        for format in [GadgetFormat::One, GadgetFormat::Two] {
+            let data = gadget.encode(format);
+            assert!(Gadget::decode(&data).is_ok());
+            for len in 0..data.len() {
+                assert!(Gadget::decode(&data[..len]).is_err(), "{:?}: decoded the first {} of {} bytes", format, len, data.len());
+            }
+
+            // A particle count far past what the blocks hold
+            let mut data = data;
+            let at = if format == GadgetFormat::Two { 20 } else { 4 };
+            data[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(Gadget::decode(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn comoving_round_trip() {
+        let scenario = "[ics]\nn = 512\n[params]\nbox_size = 50.0\nintegrator = \"leapfrog\"\n[cosmology]\nz_start = 20.0\nh = 0.68";
+        let config = Config::from_toml(scenario).unwrap();
+        let run = config.initial_run();
+        let units = run.header.units();
+
+        let gadget = Gadget::from_stars(&run.stars, &units, run.header.time, &run.header.params);
+        let read = Gadget::decode(&gadget.encode(GadgetFormat::Two)).unwrap();
+        assert!(read.is_comoving());
+
+        // Started from a scenario with no cosmology or box, those come from the file
+        let plain = Config::from_toml("[params]\nintegrator = \"leapfrog\"").unwrap();
+        let start = plain.start_from_gadget(&read).unwrap();
+        let close = |a: f64, b: f64| (a - b).abs() <= 1.0E-5 * b.abs().max(1.0);
+        assert!(close(start.header.time, run.header.time), "ln a {} vs {}", start.header.time, run.header.time);
+        assert!(close(start.header.params.box_size as f64, 50.0), "box {}", start.header.params.box_size);
+        let (p, q) = (start.header.params, run.header.params);
+        assert!(close(p.hubble as f64, q.hubble as f64) && close(p.omega_m as f64, 0.3) && close(p.omega_lambda as f64, 0.7));
+        assert!(close(start.header.units[0], run.header.units[0]), "length unit {} vs {}", start.header.units[0], run.header.units[0]);
+
+        assert_eq!(start.stars.len(), run.stars.len());
+        let speed = run.stars.iter().map(|s| s.x_vel.abs().max(s.y_vel.abs()).max(s.z_vel.abs())).fold(0.0f32, f32::max) as f64;
+        for (a, b) in start.stars.iter().zip(&run.stars) {
+            for (x, y) in [(a.x, b.x), (a.y, b.y), (a.z, b.z), (a.mass, b.mass)] {
+                assert!(close(x as f64, y as f64), "{:?} vs {:?}", a, b);
+            }
+            for (v, w) in [(a.x_vel, b.x_vel), (a.y_vel, b.y_vel), (a.z_vel, b.z_vel)] {
+                assert!((v - w).abs() as f64 <= 1.0E-5 * speed, "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use bytemuck::{Pod, Zeroable};
+
+use super::star::Star;
+
+// CPU reference for OctreePass. Every step mirrors the matching tree_*.wgsl or
+// radix_*.wgsl shader so the GPU output can be compared node for node.
+
+// Set on a child index when it refers to a leaf (sorted star) instead of an internal node
+pub const LEAF_BIT: u32 = 0x8000_0000;
+pub const NONE: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MortonBits {
+    // 10 bits per axis, fits in a u32
+    Bits30,
+    // 21 bits per axis, stored as a (hi, lo) u32 pair on the GPU
+    Bits63,
+}
+
+impl MortonBits {
+    // 30 or 63
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            30 => Some(MortonBits::Bits30),
+            63 => Some(MortonBits::Bits63),
+            _ => None,
+        }
+    }
+
+    pub fn per_axis(self) -> u32 {
+        match self {
+            MortonBits::Bits30 => 10,
+            MortonBits::Bits63 => 21,
+        }
+    }
+
+    // The radix sort consumes 4 bits per pass
+    pub fn sort_passes(self) -> u32 {
+        (3 * self.per_axis() + 3) / 4
+    }
+
+    // Most internal nodes on a path from the root of a tree over `n` keys. Each one down shares
+    // a longer prefix, of the key or of the index that breaks ties, so this is a bound on the
+    // levels tree_reduce.wgsl takes
+    pub fn max_depth(self, n: u32) -> u32 {
+        3 * self.per_axis() + (u32::BITS - n.leading_zeros())
+    }
+}
+
+// Uniform shared by every tree_*.wgsl and radix_*.wgsl shader
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct TreeParams {
+    pub n: u32,
+    pub bits_per_axis: u32,
+    pub num_tiles: u32,
+    // Key bit the current radix sort pass starts at
+    pub shift: u32,
+}
+
+// Mirrors `struct ReduceLevel` in tree_reduce.wgsl, one per dispatch, counting from 1
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct ReduceLevel {
+    pub level: u32,
+    pub _pad: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct LbvhNode {
+    pub left: u32,
+    pub right: u32,
+    pub parent: u32,
+    // Range of sorted leaves under this node
+    pub first: u32,
+    pub last: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct NodeData {
+    pub min: [f32; 3],
+    pub mass: f32,
+    pub max: [f32; 3],
+    pub _pad0: f32,
+    pub com: [f32; 3],
+    pub _pad1: f32,
+}
+
+impl NodeData {
+    pub fn leaf(s: &Star) -> Self {
+        let p = [s.x, s.y, s.z];
+        Self { min: p, mass: s.mass, max: p, _pad0: 0.0, com: p, _pad1: 0.0 }
+    }
+
+    // Written as a lerp between the two COMs, m * x overflows f32 with our masses
+    pub fn merge(a: &Self, b: &Self) -> Self {
+        let mass = a.mass + b.mass;
+        let t = if mass > 0.0 { b.mass / mass } else { 0.0 };
+
+        let mut out = Self::zeroed();
+        for k in 0..3 {
+            out.min[k] = a.min[k].min(b.min[k]);
+            out.max[k] = a.max[k].max(b.max[k]);
+            out.com[k] = a.com[k] + (b.com[k] - a.com[k]) * t;
+        }
+        out.mass = mass;
+        out
+    }
+}
+
+pub struct Lbvh {
+    pub keys: Vec<u64>,
+    // Star index for each sorted key
+    pub order: Vec<u32>,
+    // n - 1 internal nodes, node 0 is the root
+    pub nodes: Vec<LbvhNode>,
+    pub leaf_parents: Vec<u32>,
+    pub node_data: Vec<NodeData>,
+    pub leaf_data: Vec<NodeData>,
+}
+
+pub fn bounds(stars: &[Star]) -> ([f32; 3], [f32; 3]) {
+    let mut lo = [3.4E38f32; 3];
+    let mut hi = [-3.4E38f32; 3];
+    for s in stars {
+        for (k, v) in [s.x, s.y, s.z].into_iter().enumerate() {
+            lo[k] = lo[k].min(v);
+            hi[k] = hi[k].max(v);
+        }
+    }
+    (lo, hi)
+}
+
+pub fn morton_code(p: [f32; 3], lo: [f32; 3], hi: [f32; 3], bits: MortonBits) -> u64 {
+    let per_axis = bits.per_axis();
+    let scale = ((1u32 << per_axis) - 1) as f32;
+
+    let mut q = [0u32; 3];
+    for k in 0..3 {
+        let extent = (hi[k] - lo[k]).max(1.0E-30);
+        q[k] = (((p[k] - lo[k]) / extent).clamp(0.0, 1.0) * scale) as u32;
+    }
+
+    // x takes the highest bit of each triple
+    let mut code = 0u64;
+    for i in 0..per_axis {
+        for (a, v) in q.iter().enumerate() {
+            code |= (((v >> i) & 1) as u64) << (3 * i + 2 - a as u32);
+        }
+    }
+    code
+}
+
+pub fn morton_codes(stars: &[Star], bits: MortonBits) -> Vec<u64> {
+    let (lo, hi) = bounds(stars);
+    stars
+        .iter()
+        .map(|s| morton_code([s.x, s.y, s.z], lo, hi, bits))
+        .collect()
+}
+
+// Length of the common key prefix, ties broken by index so duplicate codes still form a tree
+fn delta(keys: &[u64], i: i64, j: i64) -> i64 {
+    if j < 0 || j >= keys.len() as i64 {
+        return -1;
+    }
+
+    let x = keys[i as usize] ^ keys[j as usize];
+    if x != 0 {
+        x.leading_zeros() as i64
+    } else {
+        64 + (i as u32 ^ j as u32).leading_zeros() as i64
+    }
+}
+
+impl Lbvh {
+    pub fn build(stars: &[Star], bits: MortonBits) -> Self {
+        let codes = morton_codes(stars, bits);
+
+        // Stable, like the LSD radix sort on the GPU
+        let mut order: Vec<u32> = (0..stars.len() as u32).collect();
+        order.sort_by_key(|i| codes[*i as usize]);
+        let keys = order.iter().map(|i| codes[*i as usize]).collect();
+
+        Self::from_sorted(stars, keys, order)
+    }
+
+    pub fn from_sorted(stars: &[Star], keys: Vec<u64>, order: Vec<u32>) -> Self {
+        let n = keys.len();
+        let mut nodes = vec![LbvhNode { left: 0, right: 0, parent: NONE, first: 0, last: 0 }; n.saturating_sub(1)];
+        let mut leaf_parents = vec![NONE; n];
+
+        // Karras 2012, "Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d Trees"
+        for i in 0..n.saturating_sub(1) as i64 {
+            let d = (delta(&keys, i, i + 1) - delta(&keys, i, i - 1)).signum();
+            let d_min = delta(&keys, i, i - d);
+
+            let mut l_max = 2;
+            while delta(&keys, i, i + l_max * d) > d_min {
+                l_max *= 2;
+            }
+
+            let mut l = 0;
+            let mut t = l_max / 2;
+            while t >= 1 {
+                if delta(&keys, i, i + (l + t) * d) > d_min {
+                    l += t;
+                }
+                t /= 2;
+            }
+            let j = i + l * d;
+
+            let d_node = delta(&keys, i, j);
+            let mut s = 0;
+            let mut t = l;
+            loop {
+                t = (t + 1) / 2;
+                if delta(&keys, i, i + (s + t) * d) > d_node {
+                    s += t;
+                }
+                if t <= 1 {
+                    break;
+                }
+            }
+            let gamma = i + s * d + d.min(0);
+
+            let (first, last) = (i.min(j), i.max(j));
+            let left = if first == gamma {
+                leaf_parents[gamma as usize] = i as u32;
+                gamma as u32 | LEAF_BIT
+            } else {
+                nodes[gamma as usize].parent = i as u32;
+                gamma as u32
+            };
+            let right = if last == gamma + 1 {
+                leaf_parents[gamma as usize + 1] = i as u32;
+                (gamma + 1) as u32 | LEAF_BIT
+            } else {
+                nodes[gamma as usize + 1].parent = i as u32;
+                (gamma + 1) as u32
+            };
+
+            let node = &mut nodes[i as usize];
+            node.left = left;
+            node.right = right;
+            node.first = first as u32;
+            node.last = last as u32;
+        }
+
+        let leaf_data: Vec<NodeData> = order
+            .iter()
+            .map(|i| NodeData::leaf(&stars[*i as usize]))
+            .collect();
+
+        // Post-order walk so both children are merged before their parent (the GPU uses atomics instead)
+        let mut node_data = vec![NodeData::zeroed(); nodes.len()];
+        if !nodes.is_empty() {
+            let mut stack = vec![(0u32, false)];
+            while let Some((i, children_done)) = stack.pop() {
+                let node = nodes[i as usize];
+                if children_done {
+                    let get = |c: u32| {
+                        if c & LEAF_BIT != 0 {
+                            leaf_data[(c & !LEAF_BIT) as usize]
+                        } else {
+                            node_data[c as usize]
+                        }
+                    };
+                    node_data[i as usize] = NodeData::merge(&get(node.left), &get(node.right));
+                    continue;
+                }
+
+                stack.push((i, true));
+                for c in [node.left, node.right] {
+                    if c & LEAF_BIT == 0 {
+                        stack.push((c, false));
+                    }
+                }
+            }
+        }
+
+        Self { keys, order, nodes, leaf_parents, node_data, leaf_data }
+    }
+
+    // Compares against another build of the same stars. Node data is allowed a relative
+    // tolerance since the GPU is free to fuse the multiply-adds in the COM lerp.
+    pub fn mismatch(&self, other: &Lbvh, tolerance: f32) -> Option<String> {
+        if self.keys != other.keys {
+            return Some("morton keys differ".to_owned());
+        }
+        if self.order != other.order {
+            return Some("sort order differs".to_owned());
+        }
+        if self.nodes != other.nodes {
+            return Some("node topology differs".to_owned());
+        }
+        if self.leaf_parents != other.leaf_parents {
+            return Some("leaf parents differ".to_owned());
+        }
+
+        let close = |a: f32, b: f32| (a - b).abs() <= tolerance * a.abs().max(b.abs()).max(f32::MIN_POSITIVE);
+        for (i, (a, b)) in self.node_data.iter().zip(&other.node_data).enumerate() {
+            let fields = a.min.iter().chain(&a.max).chain(&a.com).chain([&a.mass])
+                .zip(b.min.iter().chain(&b.max).chain(&b.com).chain([&b.mass]));
+            for (x, y) in fields {
+                if !close(*x, *y) {
+                    return Some(format!("node {} data differs: {:?} vs {:?}", i, a, b));
+                }
+            }
+        }
+
+        None
+    }
+}
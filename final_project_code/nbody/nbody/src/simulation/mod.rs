@@ -1 +1,118 @@
+pub mod barnes_hut;
+pub mod checkpoint;
+pub mod collisions;
+pub mod cosmology;
+pub mod diagnostics;
+pub mod direct;
+pub mod ewald;
+pub mod fmm;
+pub mod gadget;
+pub mod ics;
+pub mod lbvh;
+pub mod params;
+pub mod ply;
+pub mod pm;
+pub mod reference;
+pub mod scenario;
+pub mod snapshot;
 pub mod star;
+pub mod text;
+pub mod treepm;
+pub mod units;
+pub mod vtk;
+pub mod zeldovich;
+
+// Defaults for SimParams, in SI units
+pub const G: f32 = units::G_SI as f32;
+pub const SOFTENING: f32 = 1.0E6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Solver {
+    // O(N^2) tiled pairwise sum, entirely on the GPU (integrate.wgsl)
+    Direct,
+    // Octree on the CPU; theta is the opening angle (0 is exact, ~0.5 is typical)
+    BarnesHut { theta: f32 },
+    // Particle-mesh on the GPU (pm.wgsl), with `mesh` cells per side, a power of two
+    ParticleMesh { mesh: u32 },
+    // PM for the long range plus a GPU tree walk for the short range (treepm.wgsl), mesh and
+    // theta as above
+    TreePm { mesh: u32, theta: f32 },
+    // Fast multipole method on the CPU (simulation::fmm), Cartesian expansions to `order` (2 is
+    // the quadrupole, 3 the octupole) and theta the opening angle between pairs of nodes
+    Fmm { order: u32, theta: f32 },
+}
+
+impl Solver {
+    // (kind, theta, mesh or order), for storing in files. Zero where the solver has no such thing
+    pub fn to_raw(self) -> (u32, f32, u32) {
+        match self {
+            Solver::Direct => (0, 0.0, 0),
+            Solver::BarnesHut { theta } => (1, theta, 0),
+            Solver::ParticleMesh { mesh } => (2, 0.0, mesh),
+            Solver::TreePm { mesh, theta } => (3, theta, mesh),
+            Solver::Fmm { order, theta } => (4, theta, order),
+        }
+    }
+
+    pub const NAMES: [&'static str; 5] = ["direct", "barnes-hut", "pm", "treepm", "fmm"];
+
+    // The typical opening angle, a 64^3 mesh and octupole expansions
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "direct" => Some(Solver::Direct),
+            "barnes-hut" => Some(Solver::BarnesHut { theta: 0.5 }),
+            "pm" => Some(Solver::ParticleMesh { mesh: 64 }),
+            "treepm" => Some(Solver::TreePm { mesh: 64, theta: 0.5 }),
+            "fmm" => Some(Solver::Fmm { order: 3, theta: 0.5 }),
+            _ => None,
+        }
+    }
+
+    // None for unknown kinds, and meshes or orders the solvers would refuse
+    pub fn from_raw(kind: u32, theta: f32, mesh: u32) -> Option<Self> {
+        let valid_mesh = mesh.is_power_of_two() && (pm::MIN_MESH..=pm::MAX_MESH).contains(&mesh);
+        let valid_order = (fmm::MIN_ORDER..=fmm::MAX_ORDER).contains(&mesh);
+        match kind {
+            0 => Some(Solver::Direct),
+            1 => Some(Solver::BarnesHut { theta }),
+            2 if valid_mesh => Some(Solver::ParticleMesh { mesh }),
+            3 if valid_mesh => Some(Solver::TreePm { mesh, theta }),
+            4 if valid_order => Some(Solver::Fmm { order: mesh, theta }),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    // Semi-implicit Euler, first order. Fused into integrate.wgsl for the direct solver
+    Euler,
+    // Kick-drift-kick, symplectic and second order
+    Leapfrog,
+    // Second order, keeps the previous step's accelerations around
+    VelocityVerlet,
+    // Classic fourth order Runge-Kutta, four force evaluations per step and not symplectic
+    Rk4,
+    // Fourth order predictor-corrector using the jerk, needs Solver::Direct
+    Hermite,
+}
+
+impl Integrator {
+    pub const NAMES: [&'static str; 5] = ["euler", "leapfrog", "verlet", "rk4", "hermite"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().position(|&n| n == name).and_then(|i| Self::from_u32(i as u32))
+    }
+
+    pub fn from_u32(v: u32) -> Option<Self> {
+        match v {
+            0 => Some(Integrator::Euler),
+            1 => Some(Integrator::Leapfrog),
+            2 => Some(Integrator::VelocityVerlet),
+            3 => Some(Integrator::Rk4),
+            4 => Some(Integrator::Hermite),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::mem::{align_of, offset_of, size_of};
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{cosmology::Cosmology, G, SOFTENING};
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SofteningKernel {
+    // 1 / (r^2 + eps^2)^1.5 everywhere, never exactly Newtonian
+    Plummer = 0,
+    // Monaghan cubic spline (as in GADGET), Newtonian beyond 2.8 * eps
+    Spline = 1,
+}
+
+impl SofteningKernel {
+    pub const NAMES: [&'static str; 2] = ["plummer", "spline"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().position(|&n| n == name).and_then(|i| Self::from_u32(i as u32))
+    }
+
+    pub fn from_u32(v: u32) -> Option<Self> {
+        match v {
+            0 => Some(SofteningKernel::Plummer),
+            1 => Some(SofteningKernel::Spline),
+            _ => None,
+        }
+    }
+
+    // The softened 1 / r^3, so that a = G * m * r * inv_r3. Mirrors softened_inv_r3 in the shaders
+    pub fn inv_r3(self, r2: f64, eps: f64) -> f64 {
+        match self {
+            SofteningKernel::Plummer => (r2 + eps * eps).powf(-1.5),
+            SofteningKernel::Spline => {
+                let h = 2.8 * eps;
+                if r2 >= h * h {
+                    return r2.powf(-1.5);
+                }
+
+                let u = r2.sqrt() / h;
+                let g = if u < 0.5 {
+                    10.666666666667 + u * u * (32.0 * u - 38.4)
+                } else {
+                    21.333333333333 - 48.0 * u + 38.4 * u * u
+                        - 10.666666666667 * u * u * u
+                        - 0.066666666667 / (u * u * u)
+                };
+                g / (h * h * h)
+            }
+        }
+    }
+
+    // The softened 1 / r, so that phi = -G * m * inv_r. The potential inv_r3 is the force of,
+    // mirrors softened_inv_r in diagnostics.wgsl
+    pub fn inv_r(self, r2: f64, eps: f64) -> f64 {
+        match self {
+            SofteningKernel::Plummer => (r2 + eps * eps).powf(-0.5),
+            SofteningKernel::Spline => {
+                let h = 2.8 * eps;
+                if r2 >= h * h {
+                    return r2.powf(-0.5);
+                }
+
+                let u = r2.sqrt() / h;
+                let w = if u < 0.5 {
+                    2.8 - u * u * (5.333333333333 + u * u * (6.4 * u - 9.6))
+                } else {
+                    3.2 - 0.066666666667 / u
+                        - u * u * (10.666666666667 + u * (-16.0 + u * (9.6 - 2.133333333333 * u)))
+                };
+                w / h
+            }
+        }
+    }
+}
+
+// Mirrors `struct SimParams` in integrate.wgsl, accel*.wgsl and integrators.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct SimParams {
+    pub dt: f32,
+    pub g: f32,
+    pub softening: f32,
+    // A SofteningKernel, kept as a u32 so the struct stays Pod
+    pub kernel: u32,
+    pub n_stars: u32,
+    // Side of the periodic box, centered on the origin. 0 for open boundaries
+    pub box_size: f32,
+    // The background of a comoving run, see simulation::cosmology. H0 in these units, 0 for a
+    // static one
+    pub hubble: f32,
+    pub omega_m: f32,
+    pub omega_lambda: f32,
+    // What the leapfrog's drift and two half kicks multiply by, IntegratePass sets them from
+    // dt every step, see at()
+    pub drift: f32,
+    pub kick: [f32; 2],
+}
+
+// Uniform buffers want 16 byte multiples, and the WGSL side packs the fields in this order
+const _: () = {
+    assert!(size_of::<SimParams>() % 16 == 0);
+    assert!(align_of::<SimParams>() == 4);
+    assert!(offset_of!(SimParams, dt) == 0);
+    assert!(offset_of!(SimParams, g) == 4);
+    assert!(offset_of!(SimParams, softening) == 8);
+    assert!(offset_of!(SimParams, kernel) == 12);
+    assert!(offset_of!(SimParams, n_stars) == 16);
+    assert!(offset_of!(SimParams, box_size) == 20);
+    assert!(offset_of!(SimParams, hubble) == 24);
+    assert!(offset_of!(SimParams, drift) == 36);
+    assert!(offset_of!(SimParams, kick) == 40);
+};
+
+impl SimParams {
+    pub fn new(n_stars: u32) -> Self {
+        Self {
+            dt: 1.0,
+            g: G,
+            softening: SOFTENING,
+            kernel: SofteningKernel::Plummer as u32,
+            n_stars,
+            box_size: 0.0,
+            hubble: 0.0,
+            omega_m: 0.0,
+            omega_lambda: 0.0,
+            drift: 0.0,
+            kick: [0.0; 2],
+        }
+    }
+
+    pub fn dt(mut self, dt: f32) -> Self {
+        self.dt = dt;
+        self
+    }
+
+    pub fn gravity(mut self, g: f32) -> Self {
+        self.g = g;
+        self
+    }
+
+    pub fn softening(mut self, softening: f32, kernel: SofteningKernel) -> Self {
+        self.softening = softening;
+        self.kernel = kernel as u32;
+        self
+    }
+
+    // Wrap positions into a box of side `box_size` and take forces with Ewald summation, see
+    // simulation::ewald. 0 is open boundaries
+    pub fn periodic(mut self, box_size: f32) -> Self {
+        self.box_size = box_size;
+        self
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        self.box_size > 0.0
+    }
+
+    // Positions become comoving and velocities a^2 dx/dt, with time the log of the scale factor.
+    // The box has to be periodic
+    pub fn comoving(mut self, cosmology: &Cosmology) -> Self {
+        self.hubble = cosmology.hubble as f32;
+        self.omega_m = cosmology.omega_m as f32;
+        self.omega_lambda = cosmology.omega_lambda as f32;
+        self
+    }
+
+    pub fn is_comoving(&self) -> bool {
+        self.hubble > 0.0
+    }
+
+    // With the drift and kick factors for a step from `time`: dt and dt / 2 each, or for a
+    // comoving run the integrals over the step from Cosmology
+    pub fn at(self, time: f64) -> Self {
+        let Some(cosmology) = Cosmology::from_params(&self) else {
+            return Self { drift: self.dt, kick: [self.dt / 2.0; 2], ..self };
+        };
+
+        let (start, dt) = (time, self.dt as f64);
+        Self {
+            drift: cosmology.drift(start, start + dt) as f32,
+            kick: [cosmology.kick(start, start + dt / 2.0) as f32, cosmology.kick(start + dt / 2.0, start + dt) as f32],
+            ..self
+        }
+    }
+
+    pub fn kernel(&self) -> SofteningKernel {
+        SofteningKernel::from_u32(self.kernel).expect("invalid softening kernel")
+    }
+}
@@ -0,0 +1,78 @@
+use std::{fs, io, path::Path};
+
+use super::{star::Star, units::Units};
+
+const PROPERTIES: [&str; 8] = ["x", "y", "z", "vx", "vy", "vz", "mass", "bright"];
+
+// A binary PLY point cloud, one vertex per star with the Star fields as float properties and
+// the brightness again as a grey vertex colour, which is what Blender's importer shows.
+// `stars` are in `units`, noted in the header comments along with the time.
+pub fn encode(stars: &[Star], units: &Units, time: f64) -> Vec<u8> {
+    let mut header = String::new();
+    header += "ply\n";
+    header += "format binary_little_endian 1.0\n";
+    header += &format!("comment time {:e}\n", time);
+    header += &format!("comment units {:e} m {:e} kg {:e} s\n", units.length, units.mass, units.time);
+    header += &format!("element vertex {}\n", stars.len());
+    for p in PROPERTIES {
+        header += &format!("property float {}\n", p);
+    }
+    header += "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+    header += "end_header\n";
+
+    let mut out = header.into_bytes();
+    for s in stars {
+        for v in [s.x, s.y, s.z, s.x_vel, s.y_vel, s.z_vel, s.mass, s.bright] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        let grey = (s.bright.clamp(0.0, 1.0) * 255.0).round() as u8;
+        out.extend_from_slice(&[grey; 3]);
+    }
+    out
+}
+
+pub fn write(path: impl AsRef<Path>, stars: &[Star], units: &Units, time: f64) -> io::Result<()> {
+    fs::write(path, encode(stars, units, time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_matches_the_data() {
+        let stars: Vec<Star> = (0..10)
+            .map(|i| {
+                let f = i as f32;
+                Star { x: f, y: -f, z: 0.5 * f, mass: 1.0 + f, x_vel: 0.1, y_vel: 0.2 * f, z_vel: -f, bright: 0.2 * f - 0.5 }
+            })
+            .collect();
+        let data = encode(&stars, &Units::SI, 2.5);
+
+        let end = b"end_header\n";
+        let body = data.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+        let header = std::str::from_utf8(&data[..body]).unwrap();
+        let lines: Vec<&str> = header.lines().collect();
+        assert_eq!(lines[..2], ["ply", "format binary_little_endian 1.0"]);
+        assert!(lines.contains(&"comment time 2.5e0"), "{}", header);
+        assert!(lines.contains(&"element vertex 10"), "{}", header);
+
+        // The properties in order, each float 4 bytes and uchar 1, have to add up to the rest of the file
+        let properties: Vec<(&str, &str)> = lines
+            .iter()
+            .filter_map(|l| l.strip_prefix("property "))
+            .map(|p| p.split_once(' ').unwrap())
+            .collect();
+        let names: Vec<&str> = properties.iter().map(|p| p.1).collect();
+        assert_eq!(names, [PROPERTIES.as_slice(), &["red", "green", "blue"]].concat());
+        let size: usize = properties.iter().map(|p| if p.0 == "float" { 4 } else { 1 }).sum();
+        assert_eq!(data.len() - body, 10 * size);
+
+        for (s, vertex) in stars.iter().zip(data[body..].chunks_exact(size)) {
+            let floats: Vec<f32> = vertex[..32].chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+            assert_eq!(floats, [s.x, s.y, s.z, s.x_vel, s.y_vel, s.z_vel, s.mass, s.bright]);
+            let grey = (s.bright.clamp(0.0, 1.0) * 255.0).round() as u8;
+            assert_eq!(vertex[32..], [grey; 3]);
+        }
+    }
+}
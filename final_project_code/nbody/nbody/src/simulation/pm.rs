@@ -0,0 +1,224 @@
+use std::f64::consts::PI;
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{params::SimParams, star::Star};
+
+// Cells left free around the stars, so the CIC weights and the gradient never reach the edge
+pub const MARGIN: u32 = 3;
+// Limits of the line FFT in pm.wgsl, which holds a whole padded line in workgroup memory
+pub const MIN_MESH: u32 = 8;
+pub const MAX_MESH: u32 = 256;
+
+// Mirrors `struct PmParams` in pm.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct PmParams {
+    // Cells per side around the stars
+    pub mesh: u32,
+    // Cells per side of the zero padded grid the FFTs run on, and its log2
+    pub size: u32,
+    pub log2_size: u32,
+    pub _pad: u32,
+}
+
+impl PmParams {
+    pub fn new(mesh: u32) -> Self {
+        assert!(
+            mesh.is_power_of_two() && (MIN_MESH..=MAX_MESH).contains(&mesh),
+            "the PM mesh takes a power of two from {} to {} cells per side, not {}",
+            MIN_MESH,
+            MAX_MESH,
+            mesh
+        );
+        Self { mesh, size: 2 * mesh, log2_size: (2 * mesh).trailing_zeros(), _pad: 0 }
+    }
+}
+
+// Mirrors `struct FftParams` in pm.wgsl, one per axis and direction
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct FftParams {
+    pub axis: u32,
+    pub inverse: u32,
+    pub _pad: [u32; 2],
+}
+
+// Corner of the mesh and the cell size, a cube fitted to the stars with MARGIN cells to spare.
+// Mirrors `bounds` in pm.wgsl
+pub fn geometry(stars: &[Star], mesh: u32) -> ([f64; 3], f64) {
+    let mut lo = [f64::MAX; 3];
+    let mut hi = [f64::MIN; 3];
+    for s in stars {
+        for (k, x) in [s.x, s.y, s.z].into_iter().enumerate() {
+            lo[k] = lo[k].min(x as f64);
+            hi[k] = hi[k].max(x as f64);
+        }
+    }
+
+    let extent = (0..3).map(|k| hi[k] - lo[k]).fold(1e-30, f64::max);
+    let h = extent / (mesh - 2 * MARGIN) as f64;
+    let origin = [0, 1, 2].map(|k| (lo[k] + hi[k]) / 2.0 - 0.5 * mesh as f64 * h);
+    (origin, h)
+}
+
+// The transformed Green's function of the padded grid, 1/r in cells. Real, since 1/r is even.
+// The value at r = 0 only has to be finite, forces at the scale of a cell are off anyway.
+//
+// With a `split` scale r_s (in cells) it is the long-range part for TreePM instead, 1/r filtered
+// by exp(-k^2 r_s^2). In real space that's erf(r / 2r_s) / r, smooth enough to sample on the
+// grid as it is. It also undoes the CIC window, once for the assignment and once for the
+// interpolation, which the split is too close to the cell size to shrug off. Plain PM goes
+// without, there's no filter to keep that from blowing up the noise at high k
+pub fn greens(mesh: u32, split: Option<f64>) -> Vec<f64> {
+    let size = 2 * mesh as usize;
+    let wrap = |i: usize| i.min(size - i) as f64;
+
+    let mut grid = vec![[0.0; 2]; size * size * size];
+    for (i, g) in grid.iter_mut().enumerate() {
+        let (x, y, z) = (wrap(i % size), wrap(i / size % size), wrap(i / (size * size)));
+        let r = (x * x + y * y + z * z).sqrt();
+        g[0] = match split {
+            Some(rs) if r > 0.0 => libm::erf(r / (2.0 * rs)) / r,
+            Some(rs) => 1.0 / (PI.sqrt() * rs),
+            None if r > 0.0 => 1.0 / r,
+            None => 1.0,
+        };
+    }
+
+    fft3(&mut grid, size, false);
+    let sinc = |n: usize| {
+        let x = PI * wrap(n) / size as f64;
+        if x > 0.0 { x.sin() / x } else { 1.0 }
+    };
+    grid.iter()
+        .enumerate()
+        .map(|(i, g)| match split {
+            Some(_) => g[0] / (sinc(i % size) * sinc(i / size % size) * sinc(i / (size * size))).powi(4),
+            None => g[0],
+        })
+        .collect()
+}
+
+// In place radix-2 FFT of a power of two length line, unnormalized both ways
+pub fn fft(line: &mut [[f64; 2]], inverse: bool) {
+    let n = line.len();
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            line.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut half = 1;
+    while half < n {
+        for start in (0..n).step_by(2 * half) {
+            for j in 0..half {
+                let angle = sign * PI * j as f64 / half as f64;
+                let (s, c) = angle.sin_cos();
+                let (u, v) = (line[start + j], line[start + j + half]);
+                let v = [c * v[0] - s * v[1], c * v[1] + s * v[0]];
+                line[start + j] = [u[0] + v[0], u[1] + v[1]];
+                line[start + j + half] = [u[0] - v[0], u[1] - v[1]];
+            }
+        }
+        half *= 2;
+    }
+}
+
+// Along x, then y, then z, like the three dispatches in ParticleMeshPass
+pub fn fft3(grid: &mut [[f64; 2]], size: usize, inverse: bool) {
+    let mut line = vec![[0.0; 2]; size];
+    for stride in [1, size, size * size] {
+        for a in 0..size {
+            for b in 0..size {
+                // The first cell of line (a, b), whose cells are `stride` apart
+                let start = match stride {
+                    1 => size * (a + size * b),
+                    s if s == size => a + size * size * b,
+                    _ => a + size * b,
+                };
+                for (k, c) in line.iter_mut().enumerate() {
+                    *c = grid[start + k * stride];
+                }
+                fft(&mut line, inverse);
+                for (k, c) in line.iter().enumerate() {
+                    grid[start + k * stride] = *c;
+                }
+            }
+        }
+    }
+}
+
+// CPU version of ParticleMeshPass, in f64. Cloud-in-cell assignment onto a `mesh`^3 grid,
+// the potential from a zero padded FFT convolution so the boundaries are isolated, four point
+// differences for the field, and the same CIC weights to interpolate it back to the stars.
+// Ignores the softening, the mesh softens on the scale of a cell by itself. `split` is passed
+// on to greens
+pub fn accelerations(stars: &[Star], mesh: u32, split: Option<f64>, params: &SimParams) -> Vec<[f32; 4]> {
+    let (m, size) = (mesh as usize, 2 * mesh as usize);
+    let (origin, h) = geometry(stars, mesh);
+
+    // Lower corner of the 8 cells a star spreads over, and how far it is towards the upper one
+    let cic = |s: &Star| {
+        let mut cell = [0; 3];
+        let mut f = [0.0; 3];
+        for (k, x) in [s.x, s.y, s.z].into_iter().enumerate() {
+            let u = (x as f64 - origin[k]) / h - 0.5;
+            let i = u.floor().clamp(0.0, (m - 2) as f64);
+            cell[k] = i as usize;
+            f[k] = u - i;
+        }
+        (cell, f)
+    };
+    let corners = (0..8).map(|c| [c & 1, (c >> 1) & 1, c >> 2]);
+    let weight = |f: [f64; 3], d: [usize; 3]| (0..3).map(|k| if d[k] == 1 { f[k] } else { 1.0 - f[k] }).product::<f64>();
+    let at = |c: [usize; 3]| c[0] + size * (c[1] + size * c[2]);
+
+    let mut grid = vec![[0.0; 2]; size * size * size];
+    for s in stars {
+        let (cell, f) = cic(s);
+        for d in corners.clone() {
+            grid[at([cell[0] + d[0], cell[1] + d[1], cell[2] + d[2]])][0] += s.mass as f64 * weight(f, d);
+        }
+    }
+
+    fft3(&mut grid, size, false);
+    let scale = -params.g as f64 / (h * (size * size * size) as f64);
+    for (c, g) in grid.iter_mut().zip(greens(mesh, split)) {
+        c[0] *= g * scale;
+        c[1] *= g * scale;
+    }
+    fft3(&mut grid, size, true);
+
+    // Clamped at the edge of the mesh, where no star reaches
+    let field = |c: [usize; 3]| {
+        [0, 1, 2].map(|k| {
+            let step = |d: usize| {
+                let (mut up, mut down) = (c, c);
+                up[k] = (c[k] + d).min(m - 1);
+                down[k] = c[k].saturating_sub(d);
+                grid[at(up)][0] - grid[at(down)][0]
+            };
+            -(8.0 * step(1) - step(2)) / (12.0 * h)
+        })
+    };
+
+    stars
+        .iter()
+        .map(|s| {
+            let (cell, f) = cic(s);
+            let mut a = [0.0; 3];
+            for d in corners.clone() {
+                let e = field([cell[0] + d[0], cell[1] + d[1], cell[2] + d[2]]);
+                let w = weight(f, d);
+                for k in 0..3 {
+                    a[k] += w * e[k];
+                }
+            }
+            [a[0] as f32, a[1] as f32, a[2] as f32, 0.0]
+        })
+        .collect()
+}
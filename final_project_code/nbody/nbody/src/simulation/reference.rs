@@ -0,0 +1,168 @@
+use std::f64::consts::PI;
+
+use libm::{cos, sin};
+
+use super::{
+    ics::{IcParams, Model},
+    params::{SimParams, SofteningKernel},
+    star::Star,
+};
+
+// Problems with known answers, which `nbody verify` checks the integrators against.
+// In N-body units (G = 1), unsoftened except for the Plummer sphere.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reference {
+    // Two equal masses a unit apart, for one orbit
+    CircularKepler,
+    // Masses 0.8 and 0.2 on an e = 0.5 orbit from pericenter, for two orbits
+    EccentricKepler,
+    // Three equal masses chasing each other around a figure eight (Chenciner & Montgomery 2000),
+    // for one period
+    FigureEight,
+    // A sampled Plummer sphere, which should stay in virial equilibrium
+    Plummer,
+}
+
+// Positions and velocities of bodies 1 and 3 from Simo's solution, body 2 is body 1 mirrored
+const FIGURE_EIGHT_X: [f64; 2] = [0.97000436, -0.24308753];
+const FIGURE_EIGHT_V: [f64; 2] = [-0.93240737, -0.86473146];
+pub const FIGURE_EIGHT_PERIOD: f64 = 6.32591398;
+
+const ECCENTRICITY: f64 = 0.5;
+const ECCENTRIC_MASSES: [f64; 2] = [0.8, 0.2];
+
+pub const PLUMMER_N: u32 = 512;
+
+impl Reference {
+    pub const ALL: [Self; 4] = [Self::CircularKepler, Self::EccentricKepler, Self::FigureEight, Self::Plummer];
+    pub const NAMES: [&'static str; 4] = ["circular", "eccentric", "figure-eight", "plummer"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().position(|&n| n == name).map(|i| Self::ALL[i])
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES[Self::ALL.iter().position(|&r| r == self).unwrap()]
+    }
+
+    fn masses(self) -> [f64; 2] {
+        match self {
+            Self::EccentricKepler => ECCENTRIC_MASSES,
+            _ => [0.5, 0.5],
+        }
+    }
+
+    fn eccentricity(self) -> f64 {
+        match self {
+            Self::EccentricKepler => ECCENTRICITY,
+            _ => 0.0,
+        }
+    }
+
+    // The state at step 0
+    pub fn stars(self) -> Vec<Star> {
+        match self {
+            Self::CircularKepler | Self::EccentricKepler => self.kepler(0.0).1,
+            Self::FigureEight => {
+                let ([x, y], [vx, vy]) = (FIGURE_EIGHT_X, FIGURE_EIGHT_V);
+                vec![
+                    star([x, y, 0.0], [-vx / 2.0, -vy / 2.0, 0.0], 1.0),
+                    star([-x, -y, 0.0], [-vx / 2.0, -vy / 2.0, 0.0], 1.0),
+                    star([0.0; 3], [vx, vy, 0.0], 1.0),
+                ]
+            }
+            Self::Plummer => Model::Plummer.generate(&IcParams { n: PLUMMER_N, mass: 1.0, scale_radius: 1.0, seed: 0 }, 1.0),
+        }
+    }
+
+    // The run is this many steps of duration / steps, which is a whole number of orbits
+    pub fn steps(self) -> u32 {
+        match self {
+            Self::CircularKepler => 1000,
+            Self::EccentricKepler => 4000,
+            Self::FigureEight => 2000,
+            Self::Plummer => 200,
+        }
+    }
+
+    // In N-body time units
+    pub fn duration(self) -> f64 {
+        match self {
+            Self::CircularKepler => 2.0 * PI,
+            Self::EccentricKepler => 4.0 * PI,
+            Self::FigureEight => FIGURE_EIGHT_PERIOD,
+            Self::Plummer => 2.0,
+        }
+    }
+
+    pub fn params(self) -> SimParams {
+        let softening = if self == Self::Plummer { 0.05 } else { 0.0 };
+        SimParams::new(self.stars().len() as u32)
+            .dt((self.duration() / self.steps() as f64) as f32)
+            .gravity(1.0)
+            .softening(softening, SofteningKernel::Plummer)
+    }
+
+    // Where the two bodies of the Kepler problems are at `t`, None for the others
+    pub fn positions(self, t: f64) -> Option<Vec<[f64; 3]>> {
+        match self {
+            Self::CircularKepler | Self::EccentricKepler => Some(self.kepler(t).0),
+            _ => None,
+        }
+    }
+
+    // The exact total energy, where the initial conditions pin it down
+    pub fn energy(self) -> Option<f64> {
+        match self {
+            Self::CircularKepler | Self::EccentricKepler => {
+                let [m1, m2] = self.masses();
+                Some(-m1 * m2 / 2.0)
+            }
+            _ => None,
+        }
+    }
+
+    // Semi-major axis 1 about the center of mass, pericenter on +x at t = 0
+    fn kepler(self, t: f64) -> (Vec<[f64; 3]>, Vec<Star>) {
+        let ([m1, m2], e) = (self.masses(), self.eccentricity());
+        let (x, v) = kepler_orbit(m1 + m2, 1.0, e, t);
+
+        let (f1, f2) = (m2 / (m1 + m2), m1 / (m1 + m2));
+        let positions = vec![x.map(|x| -f1 * x), x.map(|x| f2 * x)];
+        let stars = vec![star(positions[0], v.map(|v| -f1 * v), m1), star(positions[1], v.map(|v| f2 * v), m2)];
+        (positions, stars)
+    }
+}
+
+fn star(x: [f64; 3], v: [f64; 3], mass: f64) -> Star {
+    Star {
+        x: x[0] as f32,
+        y: x[1] as f32,
+        z: x[2] as f32,
+        mass: mass as f32,
+        x_vel: v[0] as f32,
+        y_vel: v[1] as f32,
+        z_vel: v[2] as f32,
+        bright: 1.0,
+    }
+}
+
+// Relative position and velocity on a bound orbit `t` after pericenter, by solving Kepler's
+// equation M = E - e sin E with Newton's method
+pub fn kepler_orbit(mu: f64, a: f64, e: f64, t: f64) -> ([f64; 3], [f64; 3]) {
+    let n = (mu / (a * a * a)).sqrt();
+    let m = n * t;
+    let mut ecc = if e > 0.8 { PI } else { m };
+    for _ in 0..50 {
+        let step = (ecc - e * sin(ecc) - m) / (1.0 - e * cos(ecc));
+        ecc -= step;
+        if step.abs() < 1e-15 {
+            break;
+        }
+    }
+
+    let (s, c) = (sin(ecc), cos(ecc));
+    let b = a * (1.0 - e * e).sqrt();
+    let e_dot = n / (1.0 - e * c);
+    ([a * (c - e), b * s, 0.0], [-a * s * e_dot, b * c * e_dot, 0.0])
+}
@@ -0,0 +1,213 @@
+use libm::{acos, cos, sin};
+
+use super::{
+    ics::{DiskParams, IcParams, Model},
+    star::Star,
+};
+
+// A pre-generated galaxy, centered on the origin at rest with any disk spinning about +z
+pub struct Galaxy {
+    pub stars: Vec<Star>,
+    // Degrees, as in Toomre & Toomre 1972: the tilt of the disk against the orbital plane,
+    // and the angle in the disk plane from the line of nodes to the pericenter
+    pub inclination: f64,
+    pub argument: f64,
+}
+
+impl Galaxy {
+    pub fn new(model: Model, params: &IcParams, g: f64) -> Self {
+        Self::from_stars(model.generate(params, g))
+    }
+
+    pub fn from_stars(stars: Vec<Star>) -> Self {
+        Self { stars, inclination: 0.0, argument: 0.0 }
+    }
+
+    pub fn orientation(mut self, inclination: f64, argument: f64) -> Self {
+        self.inclination = inclination;
+        self.argument = argument;
+        self
+    }
+
+    pub fn mass(&self) -> f64 {
+        self.stars.iter().map(|s| s.mass as f64).sum()
+    }
+
+    // Disk frame to orbit frame, Rz(-argument) * Rx(-inclination)
+    fn rotation(&self) -> [[f64; 3]; 3] {
+        let (i, w) = (-self.inclination.to_radians(), -self.argument.to_radians());
+        let (si, ci, sw, cw) = (sin(i), cos(i), sin(w), cos(w));
+        [
+            [cw, -sw * ci, sw * si],
+            [sw, cw * ci, -cw * si],
+            [0.0, si, ci],
+        ]
+    }
+}
+
+// Relative two-body orbit of the galaxies' centers in the xy plane, pericenter on +x.
+// They start `separation` apart and approaching, which has to be at least the pericenter, and
+// equal to it for a circular orbit.
+#[derive(Clone, Copy, Debug)]
+pub struct Orbit {
+    pub pericenter: f64,
+    // 1 is parabolic, the usual choice for mergers
+    pub eccentricity: f64,
+    pub separation: f64,
+}
+
+impl Orbit {
+    // Position and velocity of the second body relative to the first
+    fn initial_state(&self, mu: f64) -> ([f64; 3], [f64; 3]) {
+        let (q, e, r) = (self.pericenter, self.eccentricity, self.separation);
+        assert!(r >= q, "orbit starts inside its pericenter ({} < {})", r, q);
+
+        let p = q * (1.0 + e);
+        // True anomaly, negative so the bodies are still falling in
+        let f = if e > 0.0 {
+            let cos_f = (p / r - 1.0) / e;
+            assert!(cos_f >= -1.0, "separation {} is past the apocenter of the orbit", r);
+            -acos(cos_f.min(1.0))
+        } else {
+            assert!((r - q).abs() <= 1.0E-6 * q, "a circular orbit starts at its pericenter ({} != {})", r, q);
+            0.0
+        };
+
+        let (sf, cf) = (sin(f), cos(f));
+        let h = (mu / p).sqrt();
+        let (v_r, v_t) = (h * e * sf, h * (1.0 + e * cf));
+
+        ([r * cf, r * sf, 0.0], [v_r * cf - v_t * sf, v_r * sf + v_t * cf, 0.0])
+    }
+}
+
+struct Placed {
+    galaxy: Galaxy,
+    position: [f64; 3],
+    velocity: [f64; 3],
+}
+
+// Composes galaxies into one set of initial conditions, either placed by hand or put on
+// Keplerian orbits around everything added before them
+pub struct Scenario {
+    g: f64,
+    bodies: Vec<Placed>,
+}
+
+impl Scenario {
+    pub const NAMES: [&'static str; 5] = ["plummer", "hernquist", "king", "disk", "merger"];
+
+    pub fn new(g: f64) -> Self {
+        Self { g, bodies: vec![] }
+    }
+
+    pub fn galaxy(mut self, galaxy: Galaxy, position: [f64; 3], velocity: [f64; 3]) -> Self {
+        self.bodies.push(Placed { galaxy, position, velocity });
+        self
+    }
+
+    // Puts `galaxy` on `orbit` around the center of mass of the galaxies added so far,
+    // then moves everything into the frame of the new center of mass
+    pub fn orbit(mut self, galaxy: Galaxy, orbit: Orbit) -> Self {
+        if self.bodies.is_empty() {
+            return self.galaxy(galaxy, [0.0; 3], [0.0; 3]);
+        }
+
+        let (m1, m2) = (self.mass(), galaxy.mass());
+        let (x1, v1) = self.center_of_mass();
+        let (dx, dv) = orbit.initial_state(self.g * (m1 + m2));
+
+        let (f1, f2) = (m2 / (m1 + m2), m1 / (m1 + m2));
+        for body in &mut self.bodies {
+            for k in 0..3 {
+                body.position[k] += -x1[k] - f1 * dx[k];
+                body.velocity[k] += -v1[k] - f1 * dv[k];
+            }
+        }
+
+        let position = [f2 * dx[0], f2 * dx[1], f2 * dx[2]];
+        let velocity = [f2 * dv[0], f2 * dv[1], f2 * dv[2]];
+        self.galaxy(galaxy, position, velocity)
+    }
+
+    // The classic setup, two galaxies meeting on an orbit
+    pub fn merger(g: f64, a: Galaxy, b: Galaxy, orbit: Orbit) -> Self {
+        Self::new(g).galaxy(a, [0.0; 3], [0.0; 3]).orbit(b, orbit)
+    }
+
+    // The single models from ics, plus "merger": two equal disk galaxies on a parabolic orbit,
+    // one prograde and one inclined. In N-body units of one galaxy.
+    pub fn from_name(name: &str, n: u32, seed: u64, g: f64) -> Option<Self> {
+        if name == "merger" {
+            let disk = |n: u32, seed: u64| {
+                Galaxy::new(
+                    Model::Disk(DiskParams::default()),
+                    &IcParams { n, mass: 1.0, scale_radius: 1.0, seed },
+                    g,
+                )
+            };
+
+            return Some(Self::merger(
+                g,
+                disk(n / 2, seed),
+                disk(n - n / 2, seed.wrapping_add(1)).orientation(60.0, -30.0),
+                Orbit { pericenter: 4.0, eccentricity: 1.0, separation: 30.0 },
+            ));
+        }
+
+        let model = Model::from_name(name)?;
+        let params = IcParams { n, mass: 1.0, scale_radius: 1.0, seed };
+        Some(Self::new(g).galaxy(Galaxy::new(model, &params, g), [0.0; 3], [0.0; 3]))
+    }
+
+    fn mass(&self) -> f64 {
+        self.bodies.iter().map(|b| b.galaxy.mass()).sum()
+    }
+
+    fn center_of_mass(&self) -> ([f64; 3], [f64; 3]) {
+        let total = self.mass();
+        let mut x = [0.0; 3];
+        let mut v = [0.0; 3];
+        if total <= 0.0 {
+            return (x, v);
+        }
+
+        for b in &self.bodies {
+            let m = b.galaxy.mass() / total;
+            for k in 0..3 {
+                x[k] += m * b.position[k];
+                v[k] += m * b.velocity[k];
+            }
+        }
+        (x, v)
+    }
+
+    pub fn build(self) -> Vec<Star> {
+        let mut stars = vec![];
+        for body in self.bodies {
+            let rot = body.galaxy.rotation();
+            let apply = |v: [f64; 3], offset: [f64; 3]| -> [f32; 3] {
+                let mut out = [0.0f32; 3];
+                for k in 0..3 {
+                    out[k] = (rot[k][0] * v[0] + rot[k][1] * v[1] + rot[k][2] * v[2] + offset[k]) as f32;
+                }
+                out
+            };
+
+            for s in &body.galaxy.stars {
+                let p = apply([s.x as f64, s.y as f64, s.z as f64], body.position);
+                let v = apply([s.x_vel as f64, s.y_vel as f64, s.z_vel as f64], body.velocity);
+                stars.push(Star {
+                    x: p[0],
+                    y: p[1],
+                    z: p[2],
+                    x_vel: v[0],
+                    y_vel: v[1],
+                    z_vel: v[2],
+                    ..*s
+                });
+            }
+        }
+        stars
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs, io,
+    mem::{offset_of, size_of},
+    path::Path,
+};
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    cosmology,
+    gadget::{Gadget, GadgetFormat},
+    params::SimParams,
+    ply,
+    star::Star,
+    text::TextFormat,
+    units::Units,
+    vtk::VtkFormat,
+};
+
+pub const MAGIC: [u8; 8] = *b"NBODYSNP";
+pub const VERSION: u32 = 2;
+
+// Names of the f32 fields of each Star record, in file order
+pub const STAR_FIELDS: &str = "x y z mass x_vel y_vel z_vel bright";
+
+// Starts every snapshot file, followed by header.n_stars Star records.
+// Everything is little endian, and the header and record sizes are stored so readers
+// can skip fields added by later versions.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct SnapshotHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub header_bytes: u32,
+    pub step: u64,
+    // In `units`, or ln a for comoving runs
+    pub time: f64,
+    pub n_stars: u64,
+    pub star_bytes: u32,
+    pub _pad: u32,
+    // SI value of one length, mass and time unit
+    pub units: [f64; 3],
+    pub seed: u64,
+    pub params: SimParams,
+    // STAR_FIELDS, zero padded
+    pub fields: [u8; 64],
+    // Of comoving runs, whose time is ln a. 0 otherwise
+    pub redshift: f64,
+}
+
+// No implicit padding, so the struct can go straight to disk
+const _: () = {
+    assert!(size_of::<SnapshotHeader>() == 200);
+    assert!(offset_of!(SnapshotHeader, step) == 16);
+    assert!(offset_of!(SnapshotHeader, units) == 48);
+    assert!(offset_of!(SnapshotHeader, params) == 80);
+    assert!(offset_of!(SnapshotHeader, fields) == 128);
+    assert!(offset_of!(SnapshotHeader, redshift) == 192);
+};
+
+// Version 1 had 32 bytes of params, which ended here, and no redshift
+const V1_PARAMS_END: usize = 112;
+const V1_HEADER_BYTES: usize = 176;
+
+impl SnapshotHeader {
+    pub fn new(step: u64, time: f64, units: &Units, params: SimParams, seed: u64) -> Self {
+        let mut fields = [0u8; 64];
+        fields[..STAR_FIELDS.len()].copy_from_slice(STAR_FIELDS.as_bytes());
+
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            header_bytes: size_of::<SnapshotHeader>() as u32,
+            step,
+            time,
+            n_stars: params.n_stars as u64,
+            star_bytes: size_of::<Star>() as u32,
+            _pad: 0,
+            units: [units.length, units.mass, units.time],
+            seed,
+            params,
+            fields,
+            redshift: if params.is_comoving() { cosmology::redshift(time) } else { 0.0 },
+        }
+    }
+
+    pub fn units(&self) -> Units {
+        Units { length: self.units[0], mass: self.units[1], time: self.units[2] }
+    }
+
+    // The whole file, `stars` being the raw contents of a star buffer
+    pub fn encode(&self, stars: &[u8]) -> Vec<u8> {
+        assert_eq!(stars.len() as u64, self.n_stars * self.star_bytes as u64, "star data doesn't match the header");
+
+        let mut out = Vec::with_capacity(size_of::<SnapshotHeader>() + stars.len());
+        out.extend_from_slice(bytemuck::bytes_of(self));
+        out.extend_from_slice(stars);
+        out
+    }
+}
+
+pub(super) fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Parses a snapshot from the start of `data`, also returning whatever follows the stars
+pub fn decode(data: &[u8]) -> io::Result<(SnapshotHeader, Vec<Star>, &[u8])> {
+    if data.len() < V1_HEADER_BYTES || data[..8] != MAGIC {
+        return Err(invalid("not a snapshot file".to_string()));
+    }
+
+    let header: SnapshotHeader = match u32::from_le_bytes(data[8..12].try_into().unwrap()) {
+        // The params it lacks are those of an open box with a static background, all zero
+        1 => {
+            let mut bytes = [0u8; size_of::<SnapshotHeader>()];
+            bytes[..V1_PARAMS_END].copy_from_slice(&data[..V1_PARAMS_END]);
+            bytes[offset_of!(SnapshotHeader, fields)..][..64].copy_from_slice(&data[V1_PARAMS_END..V1_HEADER_BYTES]);
+            bytemuck::pod_read_unaligned(&bytes)
+        }
+        _ if data.len() < size_of::<SnapshotHeader>() => return Err(invalid("truncated header".to_string())),
+        _ => bytemuck::pod_read_unaligned(&data[..size_of::<SnapshotHeader>()]),
+    };
+    if header.version > VERSION {
+        return Err(invalid(format!("snapshot version {} is newer than this build supports ({})", header.version, VERSION)));
+    }
+    if header.star_bytes as usize != size_of::<Star>() {
+        return Err(invalid(format!("expected {} byte stars, the file has {}", size_of::<Star>(), header.star_bytes)));
+    }
+
+    // Both come from the file, a corrupt count mustn't overflow
+    let start = header.header_bytes as usize;
+    let end = usize::try_from(header.n_stars)
+        .ok()
+        .and_then(|n| n.checked_mul(size_of::<Star>()))
+        .and_then(|len| len.checked_add(start))
+        .ok_or_else(|| invalid(format!("{} stars is more than a file can hold", header.n_stars)))?;
+    if data.len() < end {
+        return Err(invalid(format!("truncated, {} of {} bytes", data.len(), end)));
+    }
+
+    let stars = data[start..end]
+        .chunks_exact(size_of::<Star>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect();
+    Ok((header, stars, &data[end..]))
+}
+
+// What SnapshotPass writes, and what the current state can be exported as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotFormat {
+    // The SnapshotHeader and Star records above
+    Native,
+    // For outside analysis tools, this loses the params and seed
+    Gadget(GadgetFormat),
+    // For ParaView. A series also gets a snapshots.pvd collection, see SnapshotPass
+    Vtk(VtkFormat),
+    // For Blender and other mesh tools
+    Ply,
+    // CSV, see TextFormat::csv
+    Text,
+}
+
+impl SnapshotFormat {
+    pub const NAMES: [&'static str; 7] = ["native", "gadget1", "gadget2", "vtu", "vtp", "ply", "csv"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "native" => Some(SnapshotFormat::Native),
+            "gadget1" => Some(SnapshotFormat::Gadget(GadgetFormat::One)),
+            "gadget2" => Some(SnapshotFormat::Gadget(GadgetFormat::Two)),
+            "ply" => Some(SnapshotFormat::Ply),
+            "csv" => Some(SnapshotFormat::Text),
+            name => VtkFormat::from_extension(name).map(SnapshotFormat::Vtk),
+        }
+    }
+
+    // Going by the extension, GADGET files have none so they can't be picked this way
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "bin" => Some(SnapshotFormat::Native),
+            "ply" => Some(SnapshotFormat::Ply),
+            "csv" | "txt" => Some(SnapshotFormat::Text),
+            ext => VtkFormat::from_extension(ext).map(SnapshotFormat::Vtk),
+        }
+    }
+
+    // The whole file, `stars` being the raw contents of a star buffer
+    pub fn encode(self, header: &SnapshotHeader, stars: &[u8]) -> Vec<u8> {
+        let decoded = || -> Vec<Star> { stars.chunks_exact(size_of::<Star>()).map(bytemuck::pod_read_unaligned).collect() };
+        let units = header.units();
+        match self {
+            SnapshotFormat::Native => header.encode(stars),
+            SnapshotFormat::Gadget(format) => Gadget::from_stars(&decoded(), &units, header.time, &header.params).encode(format),
+            SnapshotFormat::Vtk(format) => format.encode(&decoded(), &units, header.time),
+            SnapshotFormat::Ply => ply::encode(&decoded(), &units, header.time),
+            SnapshotFormat::Text => TextFormat::csv().write(&decoded(), &units).into_bytes(),
+        }
+    }
+
+    // Numbered by step so they sort in order, GADGET ones named the way GADGET does
+    pub fn file_name(self, step: u64) -> String {
+        let ext = match self {
+            SnapshotFormat::Native => "bin",
+            SnapshotFormat::Gadget(_) => return format!("snapshot_{:010}", step),
+            SnapshotFormat::Vtk(format) => format.extension(),
+            SnapshotFormat::Ply => "ply",
+            SnapshotFormat::Text => "csv",
+        };
+        format!("snapshot_{:010}.{}", step, ext)
+    }
+}
+
+// Writes `stars` to `path` in the format its extension names
+pub fn export(path: impl AsRef<Path>, header: &SnapshotHeader, stars: &[Star]) -> io::Result<()> {
+    let path = path.as_ref();
+    let format = SnapshotFormat::from_path(path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} isn't .csv, .txt, .vtu, .vtp, .ply or .bin", path))
+    })?;
+    fs::write(path, format.encode(header, bytemuck::cast_slice(stars)))
+}
@@ -0,0 +1,289 @@
+use std::{fs, io, path::Path};
+
+use super::{ics::BRIGHT_SPHERE, snapshot::invalid, star::Star, units::Units};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    X,
+    Y,
+    Z,
+    VX,
+    VY,
+    VZ,
+    Mass,
+    Bright,
+    // Read past, written as 0
+    Skip,
+}
+
+impl Column {
+    pub const DEFAULT: [Column; 8] =
+        [Column::X, Column::Y, Column::Z, Column::VX, Column::VY, Column::VZ, Column::Mass, Column::Bright];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::X => "x",
+            Column::Y => "y",
+            Column::Z => "z",
+            Column::VX => "vx",
+            Column::VY => "vy",
+            Column::VZ => "vz",
+            Column::Mass => "mass",
+            Column::Bright => "bright",
+            Column::Skip => "-",
+        }
+    }
+
+    // Also takes the Star field names and a few common spellings, case insensitive
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "x" => Some(Column::X),
+            "y" => Some(Column::Y),
+            "z" => Some(Column::Z),
+            "vx" | "v_x" | "x_vel" => Some(Column::VX),
+            "vy" | "v_y" | "y_vel" => Some(Column::VY),
+            "vz" | "v_z" | "z_vel" => Some(Column::VZ),
+            "m" | "mass" => Some(Column::Mass),
+            "bright" | "brightness" => Some(Column::Bright),
+            "-" | "_" | "skip" => Some(Column::Skip),
+            _ => None,
+        }
+    }
+
+    // A comma separated list like "x,y,z,mass"
+    pub fn parse_list(list: &str) -> Option<Vec<Self>> {
+        list.split(',').map(Self::from_name).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Header {
+    // A header is there if the first line has something that isn't a number
+    Auto,
+    Present,
+    Absent,
+}
+
+// One star per line, `#` starts a comment. Reading without columns set takes them from the header,
+// or Column::DEFAULT if there is none. Missing velocities are 0, missing masses split a total of 1
+// evenly and missing brightnesses are those of a single sphere.
+#[derive(Clone, Debug)]
+pub struct TextFormat {
+    pub columns: Option<Vec<Column>>,
+    // Whitespace when None. Reading guesses commas if the first line has any
+    pub delimiter: Option<char>,
+    pub header: Header,
+    // What the file's numbers are in, None when they're in the same units as the stars
+    pub units: Option<Units>,
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        Self { columns: None, delimiter: None, header: Header::Auto, units: None }
+    }
+}
+
+impl TextFormat {
+    // Comma separated with a header, the usual export
+    pub fn csv() -> Self {
+        Self { delimiter: Some(','), header: Header::Present, ..Default::default() }
+    }
+
+    pub fn columns(mut self, columns: &[Column]) -> Self {
+        self.columns = Some(columns.to_vec());
+        self
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    pub fn header(mut self, header: Header) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn units(mut self, units: Units) -> Self {
+        self.units = Some(units);
+        self
+    }
+
+    // Stars in `units`
+    pub fn read(&self, text: &str, units: &Units) -> io::Result<Vec<Star>> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l.split('#').next().unwrap().trim()))
+            .filter(|(_, l)| !l.is_empty())
+            .peekable();
+
+        let Some(&(_, first)) = lines.peek() else { return Ok(vec![]) };
+        let delimiter = self.delimiter.or(first.contains(',').then_some(','));
+        let split = |line: &'_ str| -> Vec<String> {
+            match delimiter {
+                Some(d) => line.split(d).map(|f| f.trim().to_string()).collect(),
+                None => line.split_whitespace().map(str::to_string).collect(),
+            }
+        };
+
+        let has_header = match self.header {
+            Header::Auto => split(first).iter().any(|f| f.parse::<f64>().is_err()),
+            h => h == Header::Present,
+        };
+        let names = if has_header { lines.next().map(|(_, l)| split(l)) } else { None };
+
+        let columns = match (&self.columns, names) {
+            (Some(columns), _) => columns.clone(),
+            (None, Some(names)) => names.iter().map(|n| Column::from_name(n).unwrap_or(Column::Skip)).collect(),
+            (None, None) => Column::DEFAULT.to_vec(),
+        };
+        if ![Column::X, Column::Y, Column::Z].iter().all(|c| columns.contains(c)) {
+            return Err(invalid(format!("no x, y and z among the columns {:?}", columns)));
+        }
+
+        let mut stars = vec![];
+        for (number, line) in lines {
+            let fields = split(line);
+            if fields.len() < columns.len() {
+                return Err(invalid(format!("line {} has {} fields, expected {}", number, fields.len(), columns.len())));
+            }
+
+            let mut star = Star { x: 0.0, y: 0.0, z: 0.0, mass: 0.0, x_vel: 0.0, y_vel: 0.0, z_vel: 0.0, bright: BRIGHT_SPHERE };
+            for (column, field) in columns.iter().zip(&fields) {
+                if *column == Column::Skip {
+                    continue;
+                }
+                let value: f32 = field
+                    .parse()
+                    .map_err(|_| invalid(format!("line {}: {:?} isn't a number", number, field)))?;
+                *match column {
+                    Column::X => &mut star.x,
+                    Column::Y => &mut star.y,
+                    Column::Z => &mut star.z,
+                    Column::VX => &mut star.x_vel,
+                    Column::VY => &mut star.y_vel,
+                    Column::VZ => &mut star.z_vel,
+                    Column::Mass => &mut star.mass,
+                    Column::Bright => &mut star.bright,
+                    Column::Skip => unreachable!(),
+                } = value;
+            }
+            stars.push(star);
+        }
+
+        if let Some(from) = &self.units {
+            from.convert_stars(&mut stars, units);
+        }
+        // After converting, the total of 1 is in the target units
+        if !columns.contains(&Column::Mass) {
+            let m = 1.0 / stars.len() as f32;
+            stars.iter_mut().for_each(|s| s.mass = m);
+        }
+        Ok(stars)
+    }
+
+    // `stars` are in `units`, the header is written unless it's Header::Absent
+    pub fn write(&self, stars: &[Star], units: &Units) -> String {
+        let columns = self.columns.clone().unwrap_or(Column::DEFAULT.to_vec());
+        let delimiter = self.delimiter.map_or(" ".to_string(), |d| d.to_string());
+
+        let mut stars = stars.to_vec();
+        if let Some(to) = &self.units {
+            units.convert_stars(&mut stars, to);
+        }
+
+        let mut out = String::new();
+        if self.header != Header::Absent {
+            out += &columns.iter().map(|c| c.name()).collect::<Vec<_>>().join(&delimiter);
+            out.push('\n');
+        }
+
+        for s in &stars {
+            let fields: Vec<String> = columns
+                .iter()
+                .map(|c| match c {
+                    Column::X => s.x.to_string(),
+                    Column::Y => s.y.to_string(),
+                    Column::Z => s.z.to_string(),
+                    Column::VX => s.x_vel.to_string(),
+                    Column::VY => s.y_vel.to_string(),
+                    Column::VZ => s.z_vel.to_string(),
+                    Column::Mass => s.mass.to_string(),
+                    Column::Bright => s.bright.to_string(),
+                    Column::Skip => "0".to_string(),
+                })
+                .collect();
+            out += &fields.join(&delimiter);
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn read_file(&self, path: impl AsRef<Path>, units: &Units) -> io::Result<Vec<Star>> {
+        self.read(&fs::read_to_string(path)?, units)
+    }
+
+    pub fn write_file(&self, path: impl AsRef<Path>, stars: &[Star], units: &Units) -> io::Result<()> {
+        fs::write(path, self.write(stars, units))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::units::{PARSEC, SOLAR_MASS};
+
+    fn stars() -> Vec<Star> {
+        (0..20)
+            .map(|i| {
+                let f = i as f32;
+                Star { x: f, y: -0.3 * f, z: 1.0E-3 * f, mass: 0.05 + f, x_vel: 0.0, y_vel: 0.0, z_vel: -2.5 * f, bright: BRIGHT_SPHERE }
+            })
+            .collect()
+    }
+
+    const COLUMNS: [Column; 6] = [Column::Mass, Column::X, Column::Skip, Column::Y, Column::Z, Column::VZ];
+
+    #[test]
+    fn round_trip() {
+        let units = Units::nbody(1.0E5 * SOLAR_MASS, PARSEC);
+        let format = TextFormat::csv().columns(&COLUMNS).units(Units::ASTRO);
+        let text = format.write(&stars(), &units);
+        assert!(text.starts_with("mass,x,-,y,z,vz\n"), "{}", text);
+
+        // With the columns given, and from the header
+        let from_header = TextFormat::default().units(Units::ASTRO);
+        for read in [format.read(&text, &units).unwrap(), from_header.read(&text, &units).unwrap()] {
+            assert_eq!(read.len(), 20);
+            for (a, b) in read.iter().zip(stars()) {
+                let (a, b) = ([a.x, a.y, a.z, a.mass, a.x_vel, a.y_vel, a.z_vel, a.bright], [b.x, b.y, b.z, b.mass, b.x_vel, b.y_vel, b.z_vel, b.bright]);
+                assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() <= 1.0E-5 * b.abs().max(1.0)), "{:?} vs {:?}", a, b);
+            }
+        }
+
+        // Whitespace, no header, default columns, same units
+        let plain = TextFormat::default().header(Header::Absent);
+        let read = plain.read(&plain.write(&stars(), &units), &units).unwrap();
+        assert!(read.iter().zip(stars()).all(|(a, b)| bytemuck::bytes_of(a) == bytemuck::bytes_of(&b)));
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let units = Units::SI;
+        let format = TextFormat::csv().columns(&COLUMNS);
+        let text = format.write(&stars(), &units);
+        let header = text.find('\n').unwrap() + 1;
+
+        for (len, c) in text.char_indices().skip(header) {
+            let read = format.read(&text[..len + 1], &units);
+            match c {
+                // A line cut short after a delimiter is missing fields
+                ',' => assert!(read.is_err(), "read {:?}", &text[..len + 1]),
+                // Whole lines are fine, and the stars up to there come back
+                '\n' => assert_eq!(read.unwrap().len(), text[header..len + 1].lines().count()),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::f64::consts::PI;
+
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    lbvh::{Lbvh, MortonBits, NodeData, LEAF_BIT, NONE},
+    params::SimParams,
+    pm,
+    star::Star,
+};
+
+// The split scale r_s in mesh cells, and how many r_s out the short-range force stops. GADGET-2's
+// ASMTH and RCUT, which keep the force error across the split around a percent
+pub const SPLIT: f64 = 1.25;
+pub const CUTOFF: f64 = 4.5;
+// 30 bit keys run out in dense cores, where the tree falls back to splitting by index and the
+// boxes stop being compact
+pub const TREE_BITS: MortonBits = MortonBits::Bits63;
+
+// Mirrors `struct ShortRangeParams` in treepm.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct ShortRangeParams {
+    pub theta: f32,
+    // SPLIT and CUTOFF
+    pub split: f32,
+    pub cutoff: f32,
+    pub _pad: u32,
+}
+
+impl ShortRangeParams {
+    pub fn new(theta: f32) -> Self {
+        Self { theta, split: SPLIT as f32, cutoff: CUTOFF as f32, _pad: 0 }
+    }
+}
+
+// The fraction of the Newtonian force at r the short range keeps, what the long-range
+// erf(r / 2r_s) / r in simulation::pm::greens leaves out
+pub fn short_range(r: f64, rs: f64) -> f64 {
+    let u = r / (2.0 * rs);
+    libm::erfc(u) + 2.0 * u / PI.sqrt() * (-u * u).exp()
+}
+
+// CPU version of TreePmPass: the split PM force plus the short range from the same tree walk
+// as treepm.wgsl, over the LBVH OctreePass would build
+pub fn accelerations(stars: &[Star], mesh: u32, theta: f32, params: &SimParams) -> Vec<[f32; 4]> {
+    let (_, h) = pm::geometry(stars, mesh);
+    let tree = Lbvh::build(stars, TREE_BITS);
+    let short = short_range_accelerations(&tree, SPLIT * h, theta as f64, params);
+
+    pm::accelerations(stars, mesh, Some(SPLIT), params)
+        .into_iter()
+        .zip(short)
+        .map(|(a, b)| [a[0] + b[0], a[1] + b[1], a[2] + b[2], 0.0])
+        .collect()
+}
+
+// Nodes entirely beyond the cutoff are skipped, ones small enough for theta act as their centre
+// of mass. Indexed by star like the tree's input, rs is the split scale in length units
+pub fn short_range_accelerations(tree: &Lbvh, rs: f64, theta: f64, params: &SimParams) -> Vec<[f32; 4]> {
+    let n = tree.leaf_data.len();
+    let mut out = vec![[0.0; 4]; n];
+    if n < 2 {
+        return out;
+    }
+
+    let (g, eps, kernel) = (params.g as f64, params.softening as f64, params.kernel());
+    let cutoff = CUTOFF * rs;
+    let data = |c: u32| -> NodeData {
+        if c & LEAF_BIT != 0 { tree.leaf_data[(c & !LEAF_BIT) as usize] } else { tree.node_data[c as usize] }
+    };
+    let parent = |c: u32| {
+        if c & LEAF_BIT != 0 { tree.leaf_parents[(c & !LEAF_BIT) as usize] } else { tree.nodes[c as usize].parent }
+    };
+    let vec = |v: [f32; 3]| v.map(|x| x as f64);
+
+    for (i, leaf) in tree.leaf_data.iter().enumerate() {
+        let p = vec(leaf.com);
+        let mut f = [0.0f64; 3];
+
+        // Stackless, arriving from the parent means the node is new, from the left child that
+        // the right one is next, and from the right one that it's done
+        let (mut node, mut prev) = (0u32, NONE);
+        while node != NONE {
+            let next = if prev == parent(node) {
+                let d = data(node);
+                let (lo, hi, com) = (vec(d.min), vec(d.max), vec(d.com));
+
+                // Distance from p to the node's box
+                let gap: f64 = (0..3).map(|k| (lo[k] - p[k]).max(p[k] - hi[k]).max(0.0).powi(2)).sum();
+                let v = [com[0] - p[0], com[1] - p[1], com[2] - p[2]];
+                let r2 = v[0] * v[0] + v[1] * v[1] + v[2] * v[2];
+                let size = (0..3).map(|k| hi[k] - lo[k]).fold(0.0, f64::max);
+                let leaf = node & LEAF_BIT != 0;
+
+                if node == i as u32 | LEAF_BIT || gap > cutoff * cutoff {
+                    parent(node)
+                } else if leaf || (gap > 0.0 && size * size < theta * theta * r2) {
+                    let r = r2.sqrt();
+                    if r < cutoff {
+                        let k = g * d.mass as f64 * kernel.inv_r3(r2, eps) * short_range(r, rs);
+                        for (f, v) in f.iter_mut().zip(v) {
+                            *f += k * v;
+                        }
+                    }
+                    parent(node)
+                } else {
+                    tree.nodes[node as usize].left
+                }
+            } else if prev == tree.nodes[node as usize].left {
+                tree.nodes[node as usize].right
+            } else {
+                parent(node)
+            };
+
+            prev = node;
+            node = next;
+        }
+
+        out[tree.order[i] as usize] = [f[0] as f32, f[1] as f32, f[2] as f32, 0.0];
+    }
+
+    out
+}
@@ -0,0 +1,140 @@
+use super::{params::SimParams, star::Star};
+
+// SI values of the constants the presets are built from
+pub const G_SI: f64 = 6.67430E-11;
+pub const SOLAR_MASS: f64 = 1.98847E30;
+pub const PARSEC: f64 = 3.0856775814913673E16;
+pub const KILOPARSEC: f64 = 1.0E3 * PARSEC;
+pub const MEGAPARSEC: f64 = 1.0E6 * PARSEC;
+// 100 km/s/Mpc, H0 is h of these
+pub const HUBBLE_UNIT: f64 = 1.0E5 / MEGAPARSEC;
+// Julian year
+pub const YEAR: f64 = 3.15576E7;
+pub const MEGAYEAR: f64 = 1.0E6 * YEAR;
+
+// A unit system, stored as the SI value of one length, mass and time unit.
+// Star positions, velocities and masses and SimParams are always in some Units,
+// converting between them keeps the dynamics identical since G is converted too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Units {
+    pub length: f64,
+    pub mass: f64,
+    pub time: f64,
+}
+
+impl Units {
+    // m, kg, s
+    pub const SI: Units = Units { length: 1.0, mass: 1.0, time: 1.0 };
+
+    // kpc, solar masses, Myr. G is about 4.5E-3 here
+    pub const ASTRO: Units = Units { length: KILOPARSEC, mass: SOLAR_MASS, time: MEGAYEAR };
+
+    // For comoving runs: Mpc/h, 1E10 solar masses/h and 1/H0, with H0 = 100h km/s/Mpc. H0 is 1
+    // here and G about 43, whatever h is
+    pub fn cosmological(h: f64) -> Self {
+        Self { length: MEGAPARSEC / h, mass: 1.0E10 * SOLAR_MASS / h, time: 1.0 / (h * HUBBLE_UNIT) }
+    }
+
+    // Henon units: G = M = R = 1 for a system of total mass `mass` (kg) and
+    // virial radius `length` (m), the time unit follows from G = 1
+    pub fn nbody(mass: f64, length: f64) -> Self {
+        Self {
+            length,
+            mass,
+            time: (length * length * length / (G_SI * mass)).sqrt(),
+        }
+    }
+
+    // G expressed in these units
+    pub fn g(&self) -> f64 {
+        G_SI * self.mass * self.time * self.time / (self.length * self.length * self.length)
+    }
+
+    pub fn velocity(&self) -> f64 {
+        self.length / self.time
+    }
+
+    // Factors that take a value in these units to the same value in `to`
+    pub fn length_to(&self, to: &Units) -> f64 {
+        self.length / to.length
+    }
+
+    pub fn mass_to(&self, to: &Units) -> f64 {
+        self.mass / to.mass
+    }
+
+    pub fn time_to(&self, to: &Units) -> f64 {
+        self.time / to.time
+    }
+
+    pub fn velocity_to(&self, to: &Units) -> f64 {
+        self.velocity() / to.velocity()
+    }
+
+    pub fn convert_stars(&self, stars: &mut [Star], to: &Units) {
+        let (l, m, v) = (
+            self.length_to(to) as f32,
+            self.mass_to(to) as f32,
+            self.velocity_to(to) as f32,
+        );
+
+        for s in stars {
+            s.x *= l;
+            s.y *= l;
+            s.z *= l;
+            s.mass *= m;
+            s.x_vel *= v;
+            s.y_vel *= v;
+            s.z_vel *= v;
+        }
+    }
+
+    // The dt of a comoving run is in ln a, which has no units
+    pub fn convert_params(&self, params: SimParams, to: &Units) -> SimParams {
+        SimParams {
+            dt: if params.is_comoving() { params.dt } else { params.dt * self.time_to(to) as f32 },
+            g: to.g() as f32,
+            softening: params.softening * self.length_to(to) as f32,
+            box_size: params.box_size * self.length_to(to) as f32,
+            hubble: params.hubble / self.time_to(to) as f32,
+            ..params
+        }
+    }
+
+    // The SI defaults from SimParams::new, converted into these units
+    pub fn params(&self, n_stars: u32) -> SimParams {
+        Units::SI.convert_params(SimParams::new(n_stars), self)
+    }
+}
+
+// Henon units for `stars` (given in `units`): their total mass and half mass radius about the
+// center of mass are 1. The app's dt and softening defaults are in such units
+pub fn fit_nbody(stars: &[Star], units: &Units) -> Units {
+    let total: f64 = stars.iter().map(|s| s.mass as f64).sum();
+    let mut center = [0.0; 3];
+    for s in stars {
+        for (c, x) in center.iter_mut().zip([s.x, s.y, s.z]) {
+            *c += s.mass as f64 * x as f64 / total;
+        }
+    }
+
+    let mut shells: Vec<(f64, f64)> = stars
+        .iter()
+        .map(|s| {
+            let d = [s.x as f64 - center[0], s.y as f64 - center[1], s.z as f64 - center[2]];
+            ((d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt(), s.mass as f64)
+        })
+        .collect();
+    shells.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut enclosed = 0.0;
+    let half = shells
+        .iter()
+        .find(|(_, m)| {
+            enclosed += m;
+            enclosed >= total / 2.0
+        })
+        .map_or(1.0, |&(r, _)| r);
+
+    Units::nbody(total * units.mass, half.max(f64::MIN_POSITIVE) * units.length)
+}
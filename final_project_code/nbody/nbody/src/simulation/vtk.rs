@@ -0,0 +1,276 @@
+use std::{fs, io, path::Path};
+
+use super::{star::Star, units::Units};
+
+// The two XML dataset types ParaView opens as point clouds. Both carry the same points and
+// attributes, PolyData is the lighter of the two, some filters only take an UnstructuredGrid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VtkFormat {
+    PolyData,
+    UnstructuredGrid,
+}
+
+impl VtkFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            VtkFormat::PolyData => "vtp",
+            VtkFormat::UnstructuredGrid => "vtu",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "vtp" => Some(VtkFormat::PolyData),
+            "vtu" => Some(VtkFormat::UnstructuredGrid),
+            _ => None,
+        }
+    }
+
+    // One vertex cell per star with mass, velocity and brightness as point data. `stars` are
+    // in `units`, which go in the field data next to the time.
+    pub fn encode(self, stars: &[Star], units: &Units, time: f64) -> Vec<u8> {
+        let n = stars.len();
+        let mut appended = Appended::default();
+
+        let mass = appended.push(stars.iter().flat_map(|s| s.mass.to_le_bytes()));
+        let velocity = appended.push(stars.iter().flat_map(|s| [s.x_vel, s.y_vel, s.z_vel]).flat_map(f32::to_le_bytes));
+        let bright = appended.push(stars.iter().flat_map(|s| s.bright.to_le_bytes()));
+        let points = appended.push(stars.iter().flat_map(|s| [s.x, s.y, s.z]).flat_map(f32::to_le_bytes));
+        let connectivity = appended.push((0..n as i64).flat_map(i64::to_le_bytes));
+        let offsets = appended.push((1..=n as i64).flat_map(i64::to_le_bytes));
+
+        let array = |ty: &str, name: &str, components: u32, offset: usize| {
+            format!(
+                "        <DataArray type=\"{}\" Name=\"{}\" NumberOfComponents=\"{}\" format=\"appended\" offset=\"{}\"/>\n",
+                ty, name, components, offset
+            )
+        };
+
+        let (tag, cells) = match self {
+            VtkFormat::PolyData => (
+                "PolyData",
+                format!(
+                    "      <Verts>\n{}{}      </Verts>\n",
+                    array("Int64", "connectivity", 1, connectivity),
+                    array("Int64", "offsets", 1, offsets)
+                ),
+            ),
+            VtkFormat::UnstructuredGrid => {
+                // VTK_VERTEX
+                let types = appended.push(std::iter::repeat(1u8).take(n));
+                (
+                    "UnstructuredGrid",
+                    format!(
+                        "      <Cells>\n{}{}{}      </Cells>\n",
+                        array("Int64", "connectivity", 1, connectivity),
+                        array("Int64", "offsets", 1, offsets),
+                        array("UInt8", "types", 1, types)
+                    ),
+                )
+            }
+        };
+        let counts = match self {
+            VtkFormat::PolyData => format!("NumberOfPoints=\"{}\" NumberOfVerts=\"{}\"", n, n),
+            VtkFormat::UnstructuredGrid => format!("NumberOfPoints=\"{}\" NumberOfCells=\"{}\"", n, n),
+        };
+
+        let mut out = String::new();
+        out += "<?xml version=\"1.0\"?>\n";
+        out += &format!(
+            "<VTKFile type=\"{}\" version=\"1.0\" byte_order=\"LittleEndian\" header_type=\"UInt64\">\n",
+            tag
+        );
+        out += &format!("  <{}>\n", tag);
+        out += "    <FieldData>\n";
+        out += &format!(
+            "      <DataArray type=\"Float64\" Name=\"TimeValue\" NumberOfTuples=\"1\" format=\"ascii\">{:e}</DataArray>\n",
+            time
+        );
+        // SI value of one length, mass and time unit
+        out += &format!(
+            "      <DataArray type=\"Float64\" Name=\"Units\" NumberOfComponents=\"3\" NumberOfTuples=\"1\" format=\"ascii\">{:e} {:e} {:e}</DataArray>\n",
+            units.length, units.mass, units.time
+        );
+        out += "    </FieldData>\n";
+        out += &format!("    <Piece {}>\n", counts);
+        out += "      <PointData Scalars=\"mass\" Vectors=\"velocity\">\n";
+        out += &array("Float32", "mass", 1, mass);
+        out += &array("Float32", "velocity", 3, velocity);
+        out += &array("Float32", "bright", 1, bright);
+        out += "      </PointData>\n";
+        out += "      <Points>\n";
+        out += &array("Float32", "position", 3, points);
+        out += "      </Points>\n";
+        out += &cells;
+        out += "    </Piece>\n";
+        out += &format!("  </{}>\n", tag);
+        out += "  <AppendedData encoding=\"raw\">\n   _";
+
+        let mut out = out.into_bytes();
+        out.extend_from_slice(&appended.data);
+        out.extend_from_slice(b"\n  </AppendedData>\n</VTKFile>\n");
+        out
+    }
+
+    pub fn write(self, path: impl AsRef<Path>, stars: &[Star], units: &Units, time: f64) -> io::Result<()> {
+        fs::write(path, self.encode(stars, units, time))
+    }
+}
+
+// The raw appended block, each array prefixed with its length in bytes
+#[derive(Default)]
+struct Appended {
+    data: Vec<u8>,
+}
+
+impl Appended {
+    // Returns the array's offset for the DataArray tag
+    fn push(&mut self, bytes: impl Iterator<Item = u8>) -> usize {
+        let offset = self.data.len();
+        self.data.extend_from_slice(&[0; 8]);
+        self.data.extend(bytes);
+        let len = (self.data.len() - offset - 8) as u64;
+        self.data[offset..offset + 8].copy_from_slice(&len.to_le_bytes());
+        offset
+    }
+}
+
+// A ParaView collection, which opens a series of files as one dataset with a time slider
+#[derive(Clone, Debug, Default)]
+pub struct Pvd {
+    // Time and file name relative to the .pvd, in time order
+    pub entries: Vec<(f64, String)>,
+}
+
+impl Pvd {
+    // Reads a collection written by `encode`, an empty one if there's no file yet
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        let attribute = |line: &str, name: &str| -> Option<String> {
+            let start = line.find(&format!("{}=\"", name))? + name.len() + 2;
+            Some(line[start..][..line[start..].find('"')?].to_string())
+        };
+        let entries = text
+            .lines()
+            .filter(|l| l.trim_start().starts_with("<DataSet"))
+            .filter_map(|l| Some((attribute(l, "timestep")?.parse().ok()?, attribute(l, "file")?)))
+            .collect();
+        Ok(Self { entries })
+    }
+
+    // Anything at or after `time` is dropped first, so a run that went back to an earlier
+    // checkpoint replaces the steps it redoes
+    pub fn push(&mut self, time: f64, file: impl Into<String>) {
+        self.entries.retain(|(t, _)| *t < time);
+        self.entries.push((time, file.into()));
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        out += "<?xml version=\"1.0\"?>\n";
+        out += "<VTKFile type=\"Collection\" version=\"0.1\">\n";
+        out += "  <Collection>\n";
+        for (time, file) in &self.entries {
+            out += &format!("    <DataSet timestep=\"{:e}\" group=\"\" part=\"0\" file=\"{}\"/>\n", time, file);
+        }
+        out += "  </Collection>\n";
+        out += "</VTKFile>\n";
+        out
+    }
+
+    // Goes through a temporary file like checkpoints, ParaView may be reading it mid-run
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("pvd.tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stars() -> Vec<Star> {
+        (0..10)
+            .map(|i| {
+                let f = i as f32;
+                Star { x: f, y: -f, z: 0.5 * f, mass: 1.0 + f, x_vel: 0.1, y_vel: 0.2 * f, z_vel: -f, bright: 0.1 * f }
+            })
+            .collect()
+    }
+
+    fn attribute<'a>(tag: &'a str, name: &str) -> &'a str {
+        let start = tag.find(&format!(" {}=\"", name)).unwrap_or_else(|| panic!("no {} in {}", name, tag)) + name.len() + 3;
+        &tag[start..][..tag[start..].find('"').unwrap()]
+    }
+
+    #[test]
+    fn headers_match_the_data() {
+        let units = Units::ASTRO;
+        for format in [VtkFormat::PolyData, VtkFormat::UnstructuredGrid] {
+            let data = format.encode(&stars(), &units, 1.5);
+            let marker = b"<AppendedData encoding=\"raw\">\n   _";
+            let start = data.windows(marker.len()).position(|w| w == marker).unwrap() + marker.len();
+            let text = std::str::from_utf8(&data[..start]).unwrap();
+            let appended = &data[start..data.len() - b"\n  </AppendedData>\n</VTKFile>\n".len()];
+            assert!(data.ends_with(b"\n  </AppendedData>\n</VTKFile>\n"));
+
+            assert!(text.contains(&format!("<VTKFile type=\"{}\"", if format == VtkFormat::PolyData { "PolyData" } else { "UnstructuredGrid" })));
+            assert_eq!(attribute(text.lines().find(|l| l.contains("<Piece")).unwrap(), "NumberOfPoints"), "10");
+            let field = |name: &str| text.lines().find(|l| l.contains(&format!("Name=\"{}\"", name))).unwrap();
+            let ascii = |line: &str| -> Vec<f64> { line[line.find('>').unwrap() + 1..line.rfind('<').unwrap()].split(' ').map(|v| v.parse().unwrap()).collect() };
+            assert_eq!(ascii(field("TimeValue")), [1.5]);
+            assert_eq!(ascii(field("Units")), [units.length, units.mass, units.time]);
+
+            // Every appended array where its tag says, the right length, and nothing in between
+            let mut covered = 0;
+            for tag in text.lines().filter(|l| l.contains("format=\"appended\"")) {
+                let offset: usize = attribute(tag, "offset").parse().unwrap();
+                let components: usize = attribute(tag, "NumberOfComponents").parse().unwrap();
+                let size = match attribute(tag, "type") {
+                    "Float32" => 4,
+                    "Int64" => 8,
+                    "UInt8" => 1,
+                    ty => panic!("unexpected type {}", ty),
+                };
+                let len = u64::from_le_bytes(appended[offset..offset + 8].try_into().unwrap()) as usize;
+                assert_eq!(len, 10 * components * size, "{}", tag);
+                covered += 8 + len;
+
+                let bytes = &appended[offset + 8..offset + 8 + len];
+                let floats: Vec<f32> = bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+                let expected: Vec<f32> = match attribute(tag, "Name") {
+                    "position" => stars().iter().flat_map(|s| [s.x, s.y, s.z]).collect(),
+                    "velocity" => stars().iter().flat_map(|s| [s.x_vel, s.y_vel, s.z_vel]).collect(),
+                    "mass" => stars().iter().map(|s| s.mass).collect(),
+                    "bright" => stars().iter().map(|s| s.bright).collect(),
+                    _ => continue,
+                };
+                assert_eq!(floats, expected, "{}", tag);
+            }
+            assert_eq!(covered, appended.len(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn pvd_round_trip() {
+        let mut pvd = Pvd::default();
+        for (t, file) in [(0.0, "a.vtu"), (1.0, "b.vtu"), (2.0, "c.vtu")] {
+            pvd.push(t, file);
+        }
+        // Going back to 1 replaces what came after
+        pvd.push(1.0, "d.vtu");
+
+        let path = std::env::temp_dir().join(format!("nbody-test-{}.pvd", std::process::id()));
+        pvd.write(&path).unwrap();
+        let read = Pvd::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.entries, vec![(0.0, "a.vtu".to_string()), (1.0, "d.vtu".to_string())]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::f64::consts::PI;
+
+use libm::{cos, exp, log, pow, sin};
+use rand::SeedableRng;
+
+use super::{
+    cosmology::{simpson, Cosmology},
+    ics::{gaussian, IcRng, BRIGHT_HALO},
+    pm,
+    star::Star,
+};
+
+// Initial conditions for a comoving run. A grid of particles fills the periodic box and the
+// Zel'dovich approximation moves them off it, along a displacement field Psi that grows with
+// the linear growth factor: x = q + D(a) Psi, so p = a^2 dx/dt = a^2 H f D Psi. Psi comes from
+// a Gaussian random density field with delta = -div Psi, drawn from the power spectrum.
+//
+// Lengths are in Mpc/h, which the spectrum needs, and everything else in Units::cosmological
+
+// Radius of the spheres sigma8 is measured in
+const SIGMA8_RADIUS: f64 = 8.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PowerSpectrum {
+    // Of the primordial spectrum, P ~ k^n before the transfer function
+    pub spectral_index: f64,
+    // The amplitude, as the rms linear density contrast today in spheres of 8 Mpc/h
+    pub sigma8: f64,
+    // Gamma = omega_m h, where the transfer function turns over
+    pub shape: f64,
+}
+
+impl PowerSpectrum {
+    // Cold dark matter without baryons, from Bardeen, Bond, Kaiser & Szalay 1986. k in h/Mpc
+    fn transfer(&self, k: f64) -> f64 {
+        let q = k / self.shape;
+        if q < 1.0E-8 {
+            return 1.0;
+        }
+        let poly = 1.0 + 3.89 * q + pow(16.1 * q, 2.0) + pow(5.46 * q, 3.0) + pow(6.71 * q, 4.0);
+        log(1.0 + 2.34 * q) / (2.34 * q) * pow(poly, -0.25)
+    }
+
+    fn unnormalized(&self, k: f64) -> f64 {
+        pow(k, self.spectral_index) * self.transfer(k).powi(2)
+    }
+
+    // The variance in top hat spheres of `radius` of the unnormalized spectrum, integrated in
+    // ln k over far more than the box can hold
+    fn variance(&self, radius: f64) -> f64 {
+        let window = |x: f64| if x < 1.0E-3 { 1.0 - x * x / 10.0 } else { 3.0 * (sin(x) - x * cos(x)) / (x * x * x) };
+        let integrand = |ln_k: f64| {
+            let k = exp(ln_k);
+            k * k * k * self.unnormalized(k) * window(k * radius).powi(2)
+        };
+        simpson(integrand, log(1.0E-5), log(1.0E3), 2048) / (2.0 * PI * PI)
+    }
+
+    // What takes the shape above to P(k) today in (Mpc/h)^3
+    fn normalization(&self) -> f64 {
+        self.sigma8 * self.sigma8 / self.variance(SIGMA8_RADIUS)
+    }
+}
+
+// `side`^3 particles, a power of two for the FFT, in a box of `box_size` centered on the origin
+// at scale factor `a`. `g` is the gravitational constant, which with H0 sets the mean density
+pub fn generate(cosmology: &Cosmology, spectrum: &PowerSpectrum, side: usize, box_size: f64, a: f64, seed: u64, g: f64) -> Vec<Star> {
+    assert!(side.is_power_of_two(), "the Zel'dovich grid needs a power of two side, not {}", side);
+    let n = side * side * side;
+    let volume = box_size * box_size * box_size;
+    let norm = spectrum.normalization();
+
+    // White noise, whose transform has the right statistics and symmetry to scale by sqrt(P).
+    // With unnormalized transforms both ways, |noise_k|^2 averages n
+    let mut rng = IcRng::seed_from_u64(seed);
+    let mut noise: Vec<[f64; 2]> = (0..n).map(|_| [gaussian(&mut rng, 1.0), 0.0]).collect();
+    pm::fft3(&mut noise, side, false);
+
+    // Psi_k = i k / k^2 delta_k, leaving out the mean and the Nyquist planes, which have no
+    // partner of opposite k to keep Psi real
+    let mut psi = [(); 3].map(|_| vec![[0.0; 2]; n]);
+    for (i, w) in noise.iter().enumerate() {
+        let cell = [i % side, i / side % side, i / (side * side)];
+        if cell.contains(&(side / 2)) || cell == [0; 3] {
+            continue;
+        }
+
+        let k = cell.map(|c| 2.0 * PI / box_size * if c < side / 2 { c as f64 } else { c as f64 - side as f64 });
+        let k2 = k[0] * k[0] + k[1] * k[1] + k[2] * k[2];
+        let amplitude = (norm * spectrum.unnormalized(k2.sqrt()) / (volume * n as f64)).sqrt();
+        for (axis, field) in psi.iter_mut().enumerate() {
+            let s = amplitude * k[axis] / k2;
+            field[i] = [-w[1] * s, w[0] * s];
+        }
+    }
+    for field in &mut psi {
+        pm::fft3(field, side, true);
+    }
+
+    let growth = cosmology.growth(a);
+    let momentum = a * a * cosmology.hubble_at(a) * cosmology.growth_rate(a) * growth;
+    let mass = cosmology.omega_m * cosmology.critical_density(g) * volume / n as f64;
+    let spacing = box_size / side as f64;
+    let wrap = |x: f64| x - box_size * (x / box_size + 0.5).floor();
+
+    (0..n)
+        .map(|i| {
+            let cell = [i % side, i / side % side, i / (side * side)];
+            let d = [psi[0][i][0], psi[1][i][0], psi[2][i][0]];
+            let q = cell.map(|c| (c as f64 + 0.5) * spacing - box_size / 2.0);
+            Star {
+                x: wrap(q[0] + growth * d[0]) as f32,
+                y: wrap(q[1] + growth * d[1]) as f32,
+                z: wrap(q[2] + growth * d[2]) as f32,
+                mass: mass as f32,
+                x_vel: (momentum * d[0]) as f32,
+                y_vel: (momentum * d[1]) as f32,
+                z_vel: (momentum * d[2]) as f32,
+                bright: BRIGHT_HALO,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,177 @@
+use crate::{
+    app::GpuContext,
+    headless::Simulation,
+    simulation::{
+        reference::{Reference, PLUMMER_N},
+        star::Star,
+        Integrator, Solver,
+    },
+};
+
+// `nbody verify`, runs the reference problems headless through IntegratePass and compares
+// them with their known answers. Meant as the regression gate for the integrate shaders
+
+// One error and the most it may be
+struct Check {
+    name: &'static str,
+    error: f64,
+    tolerance: f64,
+}
+
+// Per integrator, in Integrator::NAMES order. Around ten times what the integrators reached
+// when the gate was set up, on wgpu's software adapter, so rounding noise between GPUs passes
+// and a broken stage doesn't. The fourth order ones can't beat the second order ones by much
+// in f32, or on the figure eight's 8 digit start. Measured then:
+//
+//                              euler    leapfrog verlet   rk4      hermite
+//   circular      position     6.24e-3  4.17e-5  4.06e-5  5.00e-6  9.06e-6
+//                 energy       1.79e-7  7.15e-7  1.67e-6  1.91e-6  3.58e-7
+//   eccentric     position     1.14e-2  8.10e-4  7.06e-4  1.19e-4  2.27e-4
+//                 energy       2.96e-5  1.14e-5  9.54e-7  7.99e-6  4.63e-6
+//   figure-eight  period       6.49e-3  1.30e-5  1.83e-5  1.56e-5  1.95e-5
+//                 energy       5.56e-6  3.89e-6  1.30e-6  6.48e-7  5.56e-7
+//   plummer       energy       4.47e-4  5.84e-6  5.94e-6  6.25e-8  5.50e-7
+//                 virial       5.90e-3  4.04e-3  4.04e-3  4.05e-3  4.05e-3
+//                 half mass    3.36e-2  3.16e-2  3.16e-2  3.16e-2  3.16e-2
+//
+// The Plummer sphere's virial ratio is set by how the stars were sampled rather than by the
+// integrator, see plummer_virial_tolerance. Its half mass radius is compared with the same
+// stars' at the start, and the sampled sphere settles by about 3e-2 whatever the integrator, so
+// that bound only leaves half as much again
+fn tolerance(reference: Reference, check: &str, integrator: Integrator) -> f64 {
+    let by_integrator: [f64; 5] = match (reference, check) {
+        (Reference::CircularKepler, "position") => [5e-2, 5e-4, 5e-4, 1e-4, 1e-4],
+        (Reference::CircularKepler, "energy") => [1e-4, 2e-5, 2e-5, 2e-5, 2e-5],
+        (Reference::EccentricKepler, "position") => [1e-1, 1e-2, 1e-2, 2e-3, 2e-3],
+        (Reference::EccentricKepler, "energy") => [5e-4, 1e-4, 1e-4, 1e-4, 1e-4],
+        (Reference::FigureEight, "period") => [5e-2, 2e-4, 2e-4, 2e-4, 2e-4],
+        (Reference::FigureEight, "energy") => [1e-4, 5e-5, 5e-5, 1e-5, 1e-5],
+        (Reference::Plummer, "energy") => [5e-3, 1e-4, 1e-4, 1e-5, 1e-5],
+        (Reference::Plummer, "virial") => [plummer_virial_tolerance(PLUMMER_N, reference.params().softening as f64); 5],
+        (Reference::Plummer, "half mass radius") => [5e-2; 5],
+        _ => unreachable!("no tolerance for {:?} {}", reference, check),
+    };
+    by_integrator[integrator as usize]
+}
+
+// How far 2K/|W| of a sampled Plummer sphere of `n` stars may be from 1. Between seeds it
+// scatters by about 0.8 / sqrt(n), and Plummer softening makes |W| smaller, which reads about
+// 1.5 (eps / a)^2 high. Both measured with Diagnostics::measure over 40 seeds at n = 512 and 2048,
+// the bound is three standard deviations past the bias
+fn plummer_virial_tolerance(n: u32, softening: f64) -> f64 {
+    1.5 * softening * softening + 3.0 * 0.8 / (n as f64).sqrt()
+}
+
+// Every combination of `references` and `integrators`, returns whether all of them passed
+pub fn verify(references: &[Reference], integrators: &[Integrator]) -> bool {
+    let mut passed = true;
+    for &reference in references {
+        for &integrator in integrators {
+            let checks = run(reference, integrator);
+            let ok = checks.iter().all(|c| c.error <= c.tolerance);
+            passed &= ok;
+
+            let report: Vec<String> = checks
+                .iter()
+                .map(|c| format!("{} {:.2e} (< {:.0e})", c.name, c.error, c.tolerance))
+                .collect();
+            println!(
+                "{:<13} {:<9} {}  {}",
+                reference.name(),
+                Integrator::NAMES[integrator as usize],
+                report.join("  "),
+                if ok { "ok" } else { "FAILED" }
+            );
+        }
+    }
+    passed
+}
+
+fn run(reference: Reference, integrator: Integrator) -> Vec<Check> {
+    let ctx = pollster::block_on(GpuContext::headless());
+    let mut sim = Simulation::new(ctx, reference.stars(), Solver::Direct, integrator, reference.params());
+    let start = sim.measure();
+    let start_radius = half_mass_radius(&sim.stars(), start.center_of_mass);
+
+    let mut checks = vec![];
+    let mut check = |name, error| checks.push(Check { name, error, tolerance: tolerance(reference, name, integrator) });
+
+    match reference {
+        Reference::CircularKepler | Reference::EccentricKepler => {
+            // Compared 40 times over the run, the phase error grows with time
+            let every = reference.steps() / 40;
+            let mut error: f64 = 0.0;
+            while sim.steps() < reference.steps() as u64 {
+                sim.step(every);
+                let exact = reference.positions(sim.time()).unwrap();
+                for (s, x) in sim.stars().iter().zip(exact) {
+                    error = worst(error, distance(s, x));
+                }
+            }
+            check("position", error);
+        }
+        Reference::FigureEight => {
+            sim.step(reference.steps());
+            let error = sim
+                .stars()
+                .iter()
+                .zip(reference.stars())
+                .fold(0.0, |e, (s, s0)| worst(e, distance(s, [s0.x as f64, s0.y as f64, s0.z as f64])));
+            check("period", error);
+        }
+        Reference::Plummer => {
+            sim.step(reference.steps());
+        }
+    }
+
+    let end = sim.measure();
+    let energy = reference.energy().unwrap_or(start.energy());
+    check("energy", ((end.energy() - energy) / energy).abs());
+
+    if reference == Reference::Plummer {
+        check("virial", (end.virial_ratio() - 1.0).abs());
+        check("half mass radius", (half_mass_radius(&sim.stars(), end.center_of_mass) / start_radius - 1.0).abs());
+    }
+    checks
+}
+
+// f64::max would hide a NaN, which has to fail the check
+fn worst(error: f64, e: f64) -> f64 {
+    if e > error || e.is_nan() {
+        e
+    } else {
+        error
+    }
+}
+
+fn distance(s: &Star, x: [f64; 3]) -> f64 {
+    let d = [s.x as f64 - x[0], s.y as f64 - x[1], s.z as f64 - x[2]];
+    (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+}
+
+fn half_mass_radius(stars: &[Star], center: [f64; 3]) -> f64 {
+    let mut by_radius: Vec<(f64, f64)> = stars.iter().map(|s| (distance(s, center), s.mass as f64)).collect();
+    by_radius.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let half = by_radius.iter().map(|(_, m)| m).sum::<f64>() / 2.0;
+    let mut mass = 0.0;
+    for (r, m) in by_radius {
+        mass += m;
+        if mass >= half {
+            return r;
+        }
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The whole gate, as `nbody verify` runs it
+    #[test]
+    fn references_pass() {
+        let integrators: Vec<Integrator> = Integrator::NAMES.iter().filter_map(|n| Integrator::from_name(n)).collect();
+        assert!(verify(&Reference::ALL, &integrators));
+    }
+}
@@ -2,6 +2,8 @@ use std::num::NonZeroU32;
 
 use winit::{dpi::LogicalSize, window::Window};
 
+use crate::config::Config;
+
 // Intellisense probably doesn't work here (it's fine, trust)
 pub fn insert_canvas(window: &Window) {
     use softbuffer::{Surface, SurfaceExtWeb};
@@ -33,3 +35,27 @@ pub fn insert_canvas(window: &Window) {
 
     body.append_child(&canvas);
 }
+
+// The scenario from the page's query string, `?ics.n=20000&camera.zoom=3` sets the same keys
+// a scenario file would. Falls back to the defaults if it doesn't parse
+pub fn query_config() -> Config {
+    let search = web_sys::window().unwrap().location().search().unwrap_or_default();
+    let decode = |s: &str| -> String {
+        js_sys::decode_uri_component(&s.replace('+', " ")).map(String::from).unwrap_or_else(|_| s.to_string())
+    };
+
+    let pairs: Vec<(String, String)> = search
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, value) = p.split_once('=').unwrap_or((p, "true"));
+            (decode(key), decode(value))
+        })
+        .collect();
+
+    Config::from_query(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))).unwrap_or_else(|e| {
+        web_sys::console::error_1(&format!("ignoring the query string: {}", e).into());
+        Config::default()
+    })
+}